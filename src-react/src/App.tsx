@@ -20,6 +20,7 @@ import {
   getBookshelfState,
   initNovel,
   isTauriApp,
+  listProviderModels,
   listWorkTree,
   openFolderDialog,
   readText,
@@ -83,6 +84,7 @@ import {
   defaultBaseUrlByCustomProviderApiFormat,
   inferProviderPresetKey,
   kindFromCustomProviderApiFormat,
+  providerIsKeyless,
   providerKindLabel,
   type CustomProviderApiFormat,
 } from './config/providerPresets'
@@ -140,6 +142,7 @@ type BackendChangeSet = {
       status: 'pending' | 'accepted' | 'rejected'
     }>
     status: 'pending' | 'partial' | 'accepted' | 'rejected'
+    fileOp?: 'create' | 'delete'
   }>
 }
 
@@ -220,6 +223,7 @@ function parseBackendChangeSets(raw: unknown): ImportedChangeSet[] {
       modifications,
       stats: { additions, deletions },
       status: normalizeStatus(file.status),
+      fileOp: file.fileOp === 'create' || file.fileOp === 'delete' ? file.fileOp : undefined,
     }
 
     imported.push({ changeSet, originalContent })
@@ -542,7 +546,7 @@ function getProviderAvailability(
   if (!provider.model_name.trim()) {
     return { ready: false, statusLabel: t('chat.provider.missingModel') }
   }
-  if (tauriRuntime && apiKeyKnown && !hasApiKey) {
+  if (tauriRuntime && apiKeyKnown && !hasApiKey && !providerIsKeyless(provider.kind)) {
     return { ready: false, statusLabel: t('chat.provider.missingApiKey') }
   }
   return { ready: true, statusLabel: t('chat.provider.ready') }
@@ -640,6 +644,7 @@ function App() {
   const [editingCustomProviderApiFormat, setEditingCustomProviderApiFormat] = useState<CustomProviderApiFormat>('openai')
   const [isNewProvider, setIsNewProvider] = useState(true)
   const [providerProbeRunning, setProviderProbeRunning] = useState(false)
+  const [catalogModelNames, setCatalogModelNames] = useState<string[]>([])
   const [providerProbeResult, setProviderProbeResult] = useState<ProviderProbeViewResult | null>(null)
 
   // Editors & Refs
@@ -886,7 +891,9 @@ function App() {
           base = t('chat.phase.retrying')
           break
         default:
-          base = t('chat.phase.processing')
+          base = phase?.startsWith('retrying (')
+            ? `${t('chat.phase.retrying')} ${phase.slice('retrying '.length)}`
+            : t('chat.phase.processing')
           break
       }
       return elapsedSec > 0 ? `${base} (${formatElapsedLabel(elapsedSec)})` : base
@@ -1821,6 +1828,9 @@ function App() {
       api_key: '',
       base_url: editingProvider.base_url?.trim() ?? '',
       model_name: editingProvider.model_name?.trim() ?? '',
+      ollama: editingProvider.ollama,
+      prices: editingProvider.prices,
+      generation: editingProvider.generation,
     }
     if (!normalizedProvider.base_url || !normalizedProvider.model_name) {
       setProviderProbeResult({ kind: 'error', text: 'Please fill in Base URL and Model ID first.' })
@@ -1968,6 +1978,56 @@ function App() {
     setProviderProbeResult(null)
   }, [editingProvider.base_url, editingProvider.model_name, editingProvider.kind, editingProvider.api_key])
 
+  useEffect(() => {
+    const baseUrl = editingProvider.base_url?.trim() ?? ''
+    if (!editingProvider.kind || !baseUrl || !isTauriApp()) {
+      setCatalogModelNames([])
+      return
+    }
+    let cancelled = false
+    const provider: ModelProvider = {
+      id: editingProvider.id?.trim() || `catalog-${editingProvider.kind}`,
+      name: editingProvider.name ?? '',
+      kind: editingProvider.kind,
+      api_key: '',
+      base_url: baseUrl,
+      model_name: '',
+    }
+    const rawKey = (editingProvider.api_key ?? '').trim()
+    const timer = window.setTimeout(() => {
+      listProviderModels(provider, rawKey || null)
+        .then((catalog) => {
+          if (!cancelled) setCatalogModelNames(catalog.models.map((m) => m.id))
+        })
+        .catch(() => {
+          if (!cancelled) setCatalogModelNames([])
+        })
+    }, 500)
+    return () => {
+      cancelled = true
+      window.clearTimeout(timer)
+    }
+  }, [editingProvider.base_url, editingProvider.kind, editingProvider.id, editingProvider.name, editingProvider.api_key])
+
+  useEffect(() => {
+    if (!isTauriApp()) return
+    let unlisten: (() => void) | null = null
+    let disposed = false
+    void listen('provider_model_warning', (event) => {
+      const payload: unknown = event.payload
+      if (payload && typeof payload === 'object' && typeof (payload as { message?: unknown }).message === 'string') {
+        setError((payload as { message: string }).message)
+      }
+    }).then((u) => {
+      if (disposed) u()
+      else unlisten = u
+    })
+    return () => {
+      disposed = true
+      if (unlisten) unlisten()
+    }
+  }, [])
+
   const saveAndCloseSettings = useCallback(async () => {
     if (!appSettings) return
     try {
@@ -3579,6 +3639,12 @@ function App() {
       const writeLinesRaw = typeof p.writeLines === 'number' ? p.writeLines : Number(p.writeLines)
       const writeCharsRaw = typeof p.writeChars === 'number' ? p.writeChars : Number(p.writeChars)
       const writePreview = typeof p.writePreview === 'string' ? p.writePreview : undefined
+      const parentActionId =
+        typeof p.parentActionId === 'string' && p.parentActionId.trim() ? p.parentActionId.trim() : undefined
+      const delegateAgent = typeof p.delegateAgent === 'string' ? p.delegateAgent : undefined
+      const delegateAnswerPreview =
+        typeof p.delegateAnswerPreview === 'string' ? p.delegateAnswerPreview : undefined
+      const provider = typeof p.provider === 'string' && p.provider ? p.provider : undefined
       const timestamp = typeof p.timestamp === 'number' ? p.timestamp : Date.now()
       const durationMsRaw = typeof p.durationMs === 'number' ? p.durationMs : Number(p.durationMs)
       const activity: AgentToolActivity = {
@@ -3600,6 +3666,10 @@ function App() {
         writeLines: Number.isFinite(writeLinesRaw) ? Math.max(0, Math.floor(writeLinesRaw)) : undefined,
         writeChars: Number.isFinite(writeCharsRaw) ? Math.max(0, Math.floor(writeCharsRaw)) : undefined,
         writePreview,
+        parentActionId,
+        delegateAgent,
+        delegateAnswerPreview,
+        provider,
         timestamp,
         startedAt: phase === 'start' ? timestamp : undefined,
         finishedAt: phase === 'finish' ? timestamp : undefined,
@@ -3710,6 +3780,22 @@ function App() {
       setStreamPhaseById((prev) => ({ ...prev, [streamId]: phase }))
     })
 
+    subscribe('ai_tool_approval_request', (rawPayload) => {
+      const p = parsePayload(rawPayload)
+      if (!p) return
+      const approvalId = typeof p.approvalId === 'string' ? p.approvalId : ''
+      if (!approvalId) return
+      const tool = typeof p.tool === 'string' ? p.tool : ''
+      const input = typeof p.inputPreview === 'string' ? p.inputPreview : ''
+      void (async () => {
+        const approved = await showConfirm(t('app.confirm.agentToolApproval', { tool, input }))
+        const { agentToolApprove } = await import('./tauriChat')
+        await agentToolApprove(approvalId, approved)
+      })().catch((e) => {
+        setError(e instanceof Error ? e.message : String(e))
+      })
+    })
+
     subscribe('ai_change_set', (rawPayload) => {
       const p = parsePayload(rawPayload)
       if (!p) return
@@ -3805,7 +3891,7 @@ function App() {
       disposed = true
       for (const u of unlistenFns) u()
     }
-  }, [appSettings, diffContext, onOpenDiffView, refreshTree, t, upsertAssistantVersion])
+  }, [appSettings, diffContext, onOpenDiffView, refreshTree, showConfirm, t, upsertAssistantVersion])
 
   useEffect(() => {
     if (!isTauriApp()) return
@@ -5166,8 +5252,43 @@ function App() {
                     value={editingProvider.model_name ?? ''}
                     onChange={(e) => setEditingProvider((p) => ({ ...p, model_name: e.target.value }))}
                     placeholder={t('app.model.modelIdPlaceholder')}
+                    list={catalogModelNames.length > 0 ? 'provider-model-names' : undefined}
                   />
+                  {catalogModelNames.length > 0 ? (
+                    <datalist id="provider-model-names">
+                      {catalogModelNames.map((name) => (
+                        <option key={name} value={name} />
+                      ))}
+                    </datalist>
+                  ) : null}
                 </div>
+                {editingProvider.kind === 'Ollama' ? (
+                  <>
+                    <div className="form-group">
+                      <label>{t('app.model.ollamaKeepAlive')}</label>
+                      <input
+                        value={editingProvider.ollama?.keep_alive ?? ''}
+                        onChange={(e) =>
+                          setEditingProvider((p) => ({ ...p, ollama: { ...p.ollama, keep_alive: e.target.value } }))
+                        }
+                        placeholder="30m"
+                      />
+                    </div>
+                    <div className="form-group">
+                      <label>{t('app.model.ollamaNumPredict')}</label>
+                      <input
+                        type="number"
+                        value={editingProvider.ollama?.num_predict ?? 0}
+                        onChange={(e) =>
+                          setEditingProvider((p) => ({
+                            ...p,
+                            ollama: { ...p.ollama, num_predict: Math.trunc(Number(e.target.value) || 0) },
+                          }))
+                        }
+                      />
+                    </div>
+                  </>
+                ) : null}
                 <div className="form-group">
                   <label>{t('app.model.apiKey')}</label>
                   <input
@@ -5216,10 +5337,13 @@ function App() {
                       api_key: '',
                       base_url: editingProvider.base_url?.trim() ?? '',
                       model_name: editingProvider.model_name?.trim() ?? '',
+                      ollama: editingProvider.ollama,
+                      prices: editingProvider.prices,
+                      generation: editingProvider.generation,
                     }
                     const rawKey = (editingProvider.api_key ?? '').trim()
                     const pid = normalizedProvider.id
-                    if (isNewProvider && pid && !rawKey) {
+                    if (isNewProvider && pid && !rawKey && !providerIsKeyless(normalizedProvider.kind)) {
                       const ok = await showConfirm(t('app.model.confirmNoApiKey'))
                       if (!ok) return
                     }
@@ -15,6 +15,7 @@ export const CUSTOM_PROVIDER_PRESET_KEY = 'custom'
 export const COMMON_PROVIDER_PRESETS: ProviderPreset[] = [
   { key: 'openai', name: 'OpenAI', kind: 'OpenAI', base_url: 'https://api.openai.com/v1', model_name: 'gpt-4o-mini' },
   { key: 'claude', name: 'Claude (Anthropic)', kind: 'Anthropic', base_url: 'https://api.anthropic.com', model_name: 'claude-3-5-sonnet-20241022' },
+  { key: 'gemini', name: 'Gemini (Google)', kind: 'Gemini', base_url: 'https://generativelanguage.googleapis.com/v1beta', model_name: 'gemini-2.0-flash' },
   { key: 'deepseek', name: 'DeepSeek', kind: 'OpenAICompatible', base_url: 'https://api.deepseek.com', model_name: 'deepseek-chat' },
   { key: 'openrouter', name: 'OpenRouter', kind: 'OpenAICompatible', base_url: 'https://openrouter.ai/api/v1', model_name: 'openai/gpt-4o-mini' },
   { key: 'moonshot', name: 'Moonshot (Kimi)', kind: 'OpenAICompatible', base_url: 'https://api.moonshot.cn/v1', model_name: 'moonshot-v1-8k' },
@@ -23,7 +24,8 @@ export const COMMON_PROVIDER_PRESETS: ProviderPreset[] = [
   { key: 'minimax', name: 'MiniMax', kind: 'OpenAICompatible', base_url: 'https://api.minimax.chat/v1', model_name: 'MiniMax-Text-01' },
   { key: 'siliconflow', name: 'SiliconFlow', kind: 'OpenAICompatible', base_url: 'https://api.siliconflow.cn/v1', model_name: 'deepseek-ai/DeepSeek-V3' },
   { key: 'groq', name: 'Groq', kind: 'OpenAICompatible', base_url: 'https://api.groq.com/openai/v1', model_name: 'llama-3.3-70b-versatile' },
-  { key: 'ollama', name: 'Ollama (Local)', kind: 'OpenAICompatible', base_url: 'http://localhost:11434/v1', model_name: 'qwen2.5:14b' },
+  { key: 'ollama', name: 'Ollama (Local)', kind: 'Ollama', base_url: 'http://localhost:11434', model_name: 'qwen2.5:14b' },
+  { key: 'scripted', name: 'Scripted (Offline Fixture)', kind: 'Scripted', base_url: '.novel/fixtures/scripted.json', model_name: 'scripted' },
 ]
 
 export function normalizeProviderBaseUrl(url?: string): string {
@@ -44,11 +46,22 @@ export function providerKindLabel(kind: ModelProvider['kind']): string {
       return 'OpenAI API'
     case 'Anthropic':
       return 'Claude API'
+    case 'Gemini':
+      return 'Gemini API'
+    case 'Ollama':
+      return 'Ollama API'
+    case 'Scripted':
+      return 'Scripted fixture'
     default:
       return 'OpenAI API'
   }
 }
 
+/** Providers that run without an API key. */
+export function providerIsKeyless(kind: ModelProvider['kind'] | undefined): boolean {
+  return kind === 'Ollama' || kind === 'Scripted'
+}
+
 export function kindFromCustomProviderApiFormat(format: CustomProviderApiFormat): ModelProvider['kind'] {
   return format === 'claude' ? 'Anthropic' : 'OpenAICompatible'
 }
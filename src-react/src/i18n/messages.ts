@@ -416,6 +416,7 @@ export const MESSAGES: Record<AppLocale, TranslationDict> = {
     'app.confirm.unsavedClose': 'File "{name}" has unsaved changes. Close anyway?',
     'app.confirm.deleteEntry': 'Delete "{name}"? This action cannot be undone.',
     'app.confirm.deleteUnsavedEntry': '"{name}" has unsaved changes. Delete anyway?',
+    'app.confirm.agentToolApproval': 'The assistant wants to run {tool}:\n{input}\n\nAllow it?',
 
     'app.settings.generalTitle': 'General',
     'app.settings.markdownOutput': 'Markdown Output',
@@ -494,6 +495,8 @@ export const MESSAGES: Record<AppLocale, TranslationDict> = {
     'app.model.baseUrlPlaceholder': 'https://api.example.com/v1',
     'app.model.modelId': 'Model ID',
     'app.model.modelIdPlaceholder': 'e.g. gpt-4o, claude-sonnet-4-5, deepseek-chat',
+    'app.model.ollamaKeepAlive': 'Keep model loaded (keep_alive)',
+    'app.model.ollamaNumPredict': 'Max tokens per reply (num_predict, 0 = default)',
     'app.model.apiKey': 'API Key',
     'app.model.apiKeyAlreadySetPlaceholder': 'Already set (leave blank to keep)',
     'app.model.viewDetails': 'View details',
@@ -1009,6 +1012,8 @@ export const MESSAGES: Record<AppLocale, TranslationDict> = {
     'app.model.baseUrlPlaceholder': 'https://api.example.com/v1',
     'app.model.modelId': '模型 ID',
     'app.model.modelIdPlaceholder': '例如：gpt-4o、claude-sonnet-4-5、deepseek-chat',
+    'app.model.ollamaKeepAlive': '模型驻留时长（keep_alive）',
+    'app.model.ollamaNumPredict': '单次回复最大 token 数（num_predict，0 为默认）',
     'app.model.apiKey': 'API Key',
     'app.model.apiKeyAlreadySetPlaceholder': '已设置（留空表示保持不变）',
     'app.model.viewDetails': '查看详情',
@@ -1394,6 +1399,7 @@ export const MESSAGES: Record<AppLocale, TranslationDict> = {
     'chat.trace.updatingMemory': '\u6b63\u5728\u4fdd\u5b58\u4f5c\u54c1\u8bb0\u5fc6',
     'app.confirm.deleteEntry': '\u786e\u5b9a\u5220\u9664\u201c{name}\u201d\uff1f\u6b64\u64cd\u4f5c\u4e0d\u53ef\u64a4\u9500\u3002',
     'app.confirm.deleteUnsavedEntry': '\u201c{name}\u201d\u6709\u672a\u4fdd\u5b58\u4fee\u6539\uff0c\u4ecd\u8981\u5220\u9664\u5417\uff1f',
+    'app.confirm.agentToolApproval': 'AI \u52a9\u624b\u8bf7\u6c42\u6267\u884c {tool}\uff1a\n{input}\n\n\u662f\u5426\u5141\u8bb8\uff1f',
     ...MESSAGE_OVERRIDES['zh-CN'],
   },
 }
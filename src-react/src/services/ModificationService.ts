@@ -24,6 +24,8 @@ export interface ChangeSet {
     deletions: number;
   };
   status: 'pending' | 'partial' | 'accepted' | 'rejected';
+  /** Set when the change creates a new file or deletes an existing one. */
+  fileOp?: 'create' | 'delete';
 }
 
 /**
@@ -243,7 +245,11 @@ export class ModificationService {
       throw new Error(`ChangeSet with id ${changeSetId} not found`);
     }
 
-    await this.rollbackFile(changeSetId, changeSet.filePath);
+    if (changeSet.fileOp === 'create') {
+      await this.deleteFile(changeSet.filePath);
+    } else {
+      await this.rollbackFile(changeSetId, changeSet.filePath);
+    }
     for (const mod of changeSet.modifications) {
       mod.status = 'rejected';
     }
@@ -312,6 +318,11 @@ export class ModificationService {
     changeSet: ChangeSet,
     statusOverrides: Record<string, Modification['status']>,
   ): Promise<void> {
+    if (changeSet.fileOp) {
+      await this.persistFileOp(changeSetId, changeSet, statusOverrides);
+      return;
+    }
+
     const currentContent = await this.readFile(changeSet.filePath);
     const expectedCurrent = this.buildContentFromStatuses(changeSetId, changeSet);
 
@@ -329,6 +340,49 @@ export class ModificationService {
     await this.writeFile(changeSet.filePath, nextContent);
   }
 
+  /**
+   * Persist a change set that creates or deletes its file: a created file exists
+   * once any modification is accepted, a deleted file is removed once all are.
+   */
+  private async persistFileOp(
+    changeSetId: string,
+    changeSet: ChangeSet,
+    statusOverrides: Record<string, Modification['status']>,
+  ): Promise<void> {
+    const currentContent = await this.readFile(changeSet.filePath).catch(() => null);
+    const expectedCurrent = this.buildContentFromStatuses(changeSetId, changeSet);
+
+    if ((currentContent ?? '') !== expectedCurrent) {
+      throw new Error(
+        `File ${changeSet.filePath} changed since the AI suggestion was created. Reload the file and regenerate the suggestion before applying it.`,
+      );
+    }
+
+    const nextStatuses = changeSet.modifications.map(mod => statusOverrides[mod.id] ?? mod.status);
+    const shouldExist = changeSet.fileOp === 'create'
+      ? nextStatuses.some(status => status === 'accepted')
+      : !nextStatuses.every(status => status === 'accepted');
+
+    if (!shouldExist) {
+      if (currentContent !== null) {
+        await this.deleteFile(changeSet.filePath);
+      }
+      return;
+    }
+
+    const nextContent = this.buildContentFromStatuses(changeSetId, changeSet, statusOverrides);
+    if (currentContent === null) {
+      const parent = changeSet.filePath.split('/').slice(0, -1).join('/');
+      if (parent) {
+        await invoke('create_dir', { relativePath: parent });
+      }
+    } else if (nextContent === currentContent) {
+      return;
+    }
+
+    await this.writeFile(changeSet.filePath, nextContent);
+  }
+
   private buildContentFromStatuses(
     changeSetId: string,
     changeSet: ChangeSet,
@@ -415,6 +469,18 @@ export class ModificationService {
     }
   }
 
+  /**
+   * Delete a file using Tauri API
+   * @param filePath - The path to the file (relative to workspace root)
+   */
+  private async deleteFile(filePath: string): Promise<void> {
+    try {
+      await invoke('delete_entry', { relativePath: filePath });
+    } catch (error) {
+      throw new Error(`Failed to delete file ${filePath}: ${error instanceof Error ? error.message : String(error)}`);
+    }
+  }
+
   /**
    * Generate a unique ID for a change set
    * @returns A unique ID string
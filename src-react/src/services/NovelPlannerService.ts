@@ -585,7 +585,15 @@ export class NovelPlannerService {
   }
 
   private async saveSessionStore(store: SessionPlannerStore): Promise<void> {
-    await this.writeJson(SESSION_STATE_PATH, store)
+    // The backend keeps agent run checkpoints in the same file; keep its keys.
+    let rest: Record<string, unknown> = {}
+    try {
+      const parsed: unknown = JSON.parse(await this.readOptional(SESSION_STATE_PATH))
+      if (isRecord(parsed)) rest = parsed
+    } catch {
+      rest = {}
+    }
+    await this.writeJson(SESSION_STATE_PATH, { ...rest, sessions: store.sessions })
   }
 
   async getSessionState(sessionId: string): Promise<SessionPlannerState> {
@@ -22,12 +22,25 @@ export type AIActionTraceItem = {
   writeLines?: number
   writeChars?: number
   writePreview?: string
+  /** Set on actions of a delegated sub-agent run. */
+  parentActionId?: string
+  delegateAgent?: string
+  delegateAnswerPreview?: string
+  /** Provider whose model step requested the action. */
+  provider?: string
   timestamp: number
   startedAt?: number
   finishedAt?: number
   durationMs?: number
 }
 
+/** Delegated actions sort under the top-level step that started them. */
+function actionSortStep(item: AIActionTraceItem): number {
+  if (!item.parentActionId) return item.step
+  const root = /^step-(\d+)/.exec(item.actionId)
+  return root ? Number(root[1]) : item.step
+}
+
 function actionIdentity(item: Pick<AIActionTraceItem, 'actionId' | 'step' | 'tool'>): string {
   return item.actionId || `step-${item.step}:${item.tool}`
 }
@@ -47,7 +60,9 @@ export function upsertAIActionTrace(
       startedAt: incoming.startedAt ?? incoming.timestamp,
     }
     return [...base, normalizedIncoming].sort((left, right) => {
-      if (left.step !== right.step) return left.step - right.step
+      const leftStep = actionSortStep(left)
+      const rightStep = actionSortStep(right)
+      if (leftStep !== rightStep) return leftStep - rightStep
       return left.timestamp - right.timestamp
     })
   }
@@ -71,6 +86,10 @@ export function upsertAIActionTrace(
     writeLines: incoming.writeLines ?? current.writeLines,
     writeChars: incoming.writeChars ?? current.writeChars,
     writePreview: incoming.writePreview ?? current.writePreview,
+    parentActionId: incoming.parentActionId ?? current.parentActionId,
+    delegateAgent: incoming.delegateAgent ?? current.delegateAgent,
+    delegateAnswerPreview: incoming.delegateAnswerPreview ?? current.delegateAnswerPreview,
+    provider: incoming.provider ?? current.provider,
     startedAt: incoming.startedAt ?? current.startedAt ?? incoming.timestamp,
     finishedAt: incoming.finishedAt ?? current.finishedAt,
     durationMs: incoming.durationMs ?? current.durationMs,
@@ -16,10 +16,15 @@ export type AppSettings = {
   active_agent_id: string
   launch_mode: LaunchMode
   ai_edit_apply_mode: AiEditApplyMode
+  tool_permissions?: Record<string, ToolPermission>
+  tool_timeout_secs?: Record<string, number>
+  provider_max_attempts?: number
+  provider_fallback_ids?: string[]
 }
 
 export type LaunchMode = 'picker' | 'auto_last'
 export type AiEditApplyMode = 'auto_apply' | 'review'
+export type ToolPermission = 'allow' | 'ask' | 'deny'
 
 export type ProjectSource = 'default' | 'external'
 export type WorkSource = ProjectSource
@@ -45,10 +50,52 @@ export type BookshelfState = ProjectPickerState
 export type ModelProvider = {
   id: string
   name: string
-  kind: 'OpenAI' | 'Anthropic' | 'OpenAICompatible'
+  kind: 'OpenAI' | 'Anthropic' | 'OpenAICompatible' | 'Gemini' | 'Ollama' | 'Scripted'
   api_key: string
   base_url: string
   model_name: string
+  native_tool_calls?: boolean
+  context_window?: number
+  ollama?: OllamaOptions
+  /** Price per million tokens, keyed by model name. */
+  prices?: Record<string, ModelPrice>
+  /** Sampling defaults; agents override single fields. */
+  generation?: GenerationParams
+}
+
+export type GenerationParams = {
+  top_p?: number
+  max_tokens?: number
+  stop?: string[]
+  presence_penalty?: number
+  frequency_penalty?: number
+  seed?: number
+  reasoning_effort?: 'low' | 'medium' | 'high'
+}
+
+export type ModelPrice = {
+  input_per_mtok: number
+  output_per_mtok: number
+}
+
+export type OllamaOptions = {
+  keep_alive?: string
+  num_predict?: number
+}
+
+export type CatalogModel = {
+  id: string
+  display_name?: string
+  context_length?: number
+  max_output_tokens?: number
+  capabilities: string[]
+}
+
+export type ProviderCatalog = {
+  provider_id: string
+  base_url: string
+  fetched_at: number
+  models: CatalogModel[]
 }
 
 export type FsEntry = {
@@ -64,6 +111,10 @@ export type ProjectWritingSettings = {
   auto_max_chars: number
   auto_max_rounds: number
   auto_max_chapter_advances: number
+  tool_permissions?: Record<string, ToolPermission>
+  agent_max_steps?: number | null
+  agent_react_timeout_secs?: number | null
+  agent_stop_marker?: string | null
 }
 
 export type ComposerDirectiveParseResult = {
@@ -219,6 +270,32 @@ export async function writeText(path: string, content: string): Promise<void> {
   return invoke<void>('write_text', { relativePath: path, content })
 }
 
+export type TextSearchHit = {
+  path: string
+  line: number
+  column: number
+  snippet: string
+}
+
+export type TextSearchResult = {
+  hits: TextSearchHit[]
+  files_scanned: number
+  truncated: boolean
+}
+
+export async function searchText(
+  query: string,
+  options: { regex?: boolean; caseSensitive?: boolean; pathGlob?: string | null; maxResults?: number } = {},
+): Promise<TextSearchResult> {
+  return invoke<TextSearchResult>('search_text', {
+    query,
+    regex: options.regex ?? false,
+    caseSensitive: options.caseSensitive ?? false,
+    pathGlob: options.pathGlob ?? null,
+    maxResults: options.maxResults ?? null,
+  })
+}
+
 export async function createFile(path: string): Promise<void> {
   return invoke<void>('create_file', { relativePath: path })
 }
@@ -265,6 +342,40 @@ export async function testProviderConnectivity(provider: ModelProvider, apiKey?:
   })
 }
 
+export async function listProviderModels(
+  provider: ModelProvider,
+  apiKey?: string | null,
+  refresh = false,
+): Promise<ProviderCatalog> {
+  return invoke<ProviderCatalog>('list_provider_models', {
+    provider,
+    apiKey: apiKey ?? null,
+    refresh,
+  })
+}
+
+export type UsageTotals = {
+  requests: number
+  input_tokens: number
+  output_tokens: number
+  cost: number | null
+}
+
+export type UsageGroupBy = 'day' | 'provider' | 'agent'
+
+export type UsageReport = {
+  groups: Array<UsageTotals & { key: string }>
+  total: UsageTotals
+}
+
+export async function getUsageReport(args: { groupBy: UsageGroupBy; since?: string | null; until?: string | null }): Promise<UsageReport> {
+  return invoke<UsageReport>('get_usage_report', {
+    groupBy: args.groupBy,
+    since: args.since ?? null,
+    until: args.until ?? null,
+  })
+}
+
 export type Agent = {
   id: string
   name: string
@@ -272,6 +383,10 @@ export type Agent = {
   system_prompt: string
   temperature: number
   max_tokens: number
+  max_steps?: number
+  react_timeout_secs?: number
+  stop_marker?: string
+  generation?: GenerationParams
 }
 export type WritingAssistant = Agent
 
@@ -627,3 +742,130 @@ export async function 拆书Analyze(content: string, title: string): Promise<Boo
 export async function 拆书ExtractTechniques(content: string): Promise<WritingTechnique[]> {
   return invoke<WritingTechnique[]>('拆书_extract__echniques', { content })
 }
+
+export type AgentRunSummary = {
+  stream_id: string
+  started_at: number
+  agent_id: string
+  provider_id: string
+  steps: number
+  tool_calls: number
+  ok: boolean | null
+  size_bytes: number
+}
+
+export type AgentRunTraceLine = {
+  ts: number
+  kind: 'start' | 'system_prompt' | 'model_output' | 'tool_call' | 'finish'
+  [key: string]: unknown
+}
+
+export type AgentRunReplayStep = {
+  step: number
+  action_id: string
+  tool: string
+  recorded_ok: boolean
+  valid: boolean
+  issues: unknown
+  executed: boolean
+  observation: unknown | null
+  matches_recorded: boolean | null
+}
+
+export async function listAgentRuns(max?: number): Promise<AgentRunSummary[]> {
+  return invoke<AgentRunSummary[]>('list_agent_runs', { max: max ?? null })
+}
+
+export async function loadAgentRun(streamId: string): Promise<AgentRunTraceLine[]> {
+  return invoke<AgentRunTraceLine[]>('load_agent_run', { streamId, stream_id: streamId })
+}
+
+export type AgentRunReplay = {
+  steps: AgentRunReplayStep[]
+  change_set: unknown | null
+}
+
+export async function replayAgentRun(streamId: string): Promise<AgentRunReplay> {
+  return invoke<AgentRunReplay>('replay_agent_run', { streamId, stream_id: streamId })
+}
+
+export type MemoryScope = 'global' | 'character' | 'location' | 'chapter'
+
+export type MemoryItem = {
+  id: string
+  key: string
+  value: string
+  scope: MemoryScope
+  subject: string
+  pinned: boolean
+  source: { origin: string; run_id: string | null; path: string | null }
+  created_at: number
+  updated_at: number
+}
+
+export type MemoryItemFilter = {
+  scope?: MemoryScope
+  subject?: string
+  query?: string
+  pinnedOnly?: boolean
+}
+
+export type MemoryItemInput = {
+  id?: string
+  key: string
+  value: string
+  scope?: MemoryScope
+  subject?: string
+  pinned?: boolean
+}
+
+export async function listMemoryItems(filter: MemoryItemFilter = {}): Promise<MemoryItem[]> {
+  return invoke<MemoryItem[]>('list_memory_items', {
+    scope: filter.scope ?? null,
+    subject: filter.subject ?? null,
+    query: filter.query ?? null,
+    pinnedOnly: filter.pinnedOnly ?? null,
+    pinned_only: filter.pinnedOnly ?? null,
+  })
+}
+
+export async function saveMemoryItem(item: MemoryItemInput): Promise<MemoryItem> {
+  return invoke<MemoryItem>('save_memory_item', {
+    id: item.id ?? null,
+    key: item.key,
+    value: item.value,
+    scope: item.scope ?? null,
+    subject: item.subject ?? null,
+    pinned: item.pinned ?? null,
+  })
+}
+
+export async function deleteMemoryItem(id: string): Promise<boolean> {
+  return invoke<boolean>('delete_memory_item', { id })
+}
+
+export type StorySearchSource = 'memory' | 'chapters' | 'concepts'
+
+export type StorySearchHit = {
+  kind: 'memory' | 'chapter' | 'concept'
+  score: number
+  path: string | null
+  line_start: number | null
+  line_end: number | null
+  memory_id: string | null
+  memory_key: string | null
+  memory_scope: MemoryScope | null
+  memory_subject: string | null
+  snippet: string
+}
+
+export async function storySearch(
+  query: string,
+  options: { sources?: StorySearchSource[]; limit?: number } = {},
+): Promise<StorySearchHit[]> {
+  return invoke<StorySearchHit[]>('story_search', {
+    query,
+    sources: options.sources ?? null,
+    limit: options.limit ?? null,
+  })
+}
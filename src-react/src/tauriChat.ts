@@ -38,3 +38,29 @@ export async function chatCancelStream(streamId: string): Promise<void> {
     stream_id: streamId,
   })
 }
+
+export async function chatResumeStream(args: {
+  streamId: string
+  fromStreamId?: string | null
+  useMarkdown: boolean
+  providerId?: string | null
+}): Promise<void> {
+  return invoke<void>('chat_resume_stream', {
+    streamId: args.streamId,
+    stream_id: args.streamId,
+    fromStreamId: args.fromStreamId ?? null,
+    from_stream_id: args.fromStreamId ?? null,
+    useMarkdown: args.useMarkdown,
+    use_markdown: args.useMarkdown,
+    providerId: args.providerId ?? null,
+    provider_id: args.providerId ?? null,
+  })
+}
+
+export async function agentToolApprove(approvalId: string, approved: boolean): Promise<void> {
+  return invoke<void>('agent_tool_approve', {
+    approvalId,
+    approval_id: approvalId,
+    approved,
+  })
+}
@@ -0,0 +1,351 @@
+use crate::story_index::{self, Bm25};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What a memory item is about. `subject` on the item names the character,
+/// location or chapter; global items have no subject.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryScope {
+  #[default]
+  Global,
+  Character,
+  Location,
+  Chapter,
+}
+
+impl MemoryScope {
+  pub fn parse(raw: &str) -> Result<Self, String> {
+    match raw.trim().to_ascii_lowercase().as_str() {
+      "" | "global" => Ok(Self::Global),
+      "character" => Ok(Self::Character),
+      "location" => Ok(Self::Location),
+      "chapter" => Ok(Self::Chapter),
+      other => Err(format!("unknown memory scope: {other}")),
+    }
+  }
+
+  fn label(self) -> &'static str {
+    match self {
+      Self::Global => "global",
+      Self::Character => "character",
+      Self::Location => "location",
+      Self::Chapter => "chapter",
+    }
+  }
+}
+
+/// Where a memory item came from.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct MemorySource {
+  /// `agent` for tool writes, `user` for edits from the UI.
+  pub origin: String,
+  /// Stream id of the agent run that wrote the item.
+  pub run_id: Option<String>,
+  /// Workspace file the fact was taken from.
+  pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MemoryItem {
+  pub id: String,
+  pub key: String,
+  pub value: String,
+  pub scope: MemoryScope,
+  pub subject: String,
+  /// Pinned items are injected into every agent prompt.
+  pub pinned: bool,
+  pub source: MemorySource,
+  /// Unix milliseconds; 0 for items written before timestamps were kept.
+  pub created_at: i64,
+  pub updated_at: i64,
+}
+
+impl MemoryItem {
+  fn same_slot(&self, scope: MemoryScope, subject: &str, key: &str) -> bool {
+    self.scope == scope && self.subject == subject && self.key == key
+  }
+}
+
+/// Fields of an insert or update; items are identified by scope, subject and key.
+pub struct MemoryUpsert {
+  pub key: String,
+  pub value: String,
+  pub scope: MemoryScope,
+  pub subject: String,
+  /// `None` keeps the current pin state (unpinned for new items).
+  pub pinned: Option<bool>,
+  pub source: MemorySource,
+}
+
+#[derive(Default)]
+pub struct MemoryFilter {
+  pub scope: Option<MemoryScope>,
+  pub subject: Option<String>,
+  pub pinned_only: bool,
+  /// Case-insensitive substring match on key, value and subject.
+  pub query: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MemoryStoreData {
+  pub long_term: Vec<MemoryItem>,
+}
+
+pub struct MemoryStore {
+  path: PathBuf,
+  data: MemoryStoreData,
+}
+
+impl MemoryStore {
+  pub fn load(workspace_root: &Path) -> Self {
+    let path = workspace_root.join(".novel").join(".cache").join("agent_memory.json");
+    let mut store = Self {
+      path,
+      data: MemoryStoreData::default(),
+    };
+    store.reload();
+    store
+  }
+
+  /// Re-reads the file so writes from the UI and other runs are not lost.
+  pub fn reload(&mut self) {
+    self.data = fs::read_to_string(&self.path)
+      .ok()
+      .and_then(|raw| serde_json::from_str::<MemoryStoreData>(&raw).ok())
+      .unwrap_or_default();
+    for item in self.data.long_term.iter_mut().filter(|it| it.id.is_empty()) {
+      item.id = uuid::Uuid::new_v4().to_string();
+    }
+  }
+
+  pub fn save(&self) -> Result<(), String> {
+    if let Some(parent) = self.path.parent() {
+      fs::create_dir_all(parent).map_err(|e| format!("create memory dir failed: {e}"))?;
+    }
+    let raw = serde_json::to_string_pretty(&self.data).map_err(|e| format!("serialize memory failed: {e}"))?;
+    fs::write(&self.path, raw).map_err(|e| format!("write memory failed: {e}"))
+  }
+
+  /// Returns the stored item and whether it was newly created.
+  pub fn upsert(&mut self, input: MemoryUpsert) -> Result<(MemoryItem, bool), String> {
+    let key = input.key.trim();
+    if key.is_empty() {
+      return Err("memory key is required".to_string());
+    }
+    let subject = input.subject.trim();
+    if input.scope != MemoryScope::Global && subject.is_empty() {
+      return Err(format!("{} memory needs a subject", input.scope.label()));
+    }
+    let now = Utc::now().timestamp_millis();
+    if let Some(it) = self
+      .data
+      .long_term
+      .iter_mut()
+      .find(|it| it.same_slot(input.scope, subject, key))
+    {
+      it.value = input.value;
+      it.pinned = input.pinned.unwrap_or(it.pinned);
+      it.source = input.source;
+      it.updated_at = now;
+      return Ok((it.clone(), false));
+    }
+    let item = MemoryItem {
+      id: uuid::Uuid::new_v4().to_string(),
+      key: key.to_string(),
+      value: input.value,
+      scope: input.scope,
+      subject: subject.to_string(),
+      pinned: input.pinned.unwrap_or(false),
+      source: input.source,
+      created_at: now,
+      updated_at: now,
+    };
+    self.data.long_term.push(item.clone());
+    Ok((item, true))
+  }
+
+  /// Edits the item `id` in place, including moving it to another slot.
+  pub fn update(&mut self, id: &str, input: MemoryUpsert) -> Result<MemoryItem, String> {
+    let key = input.key.trim();
+    let subject = input.subject.trim();
+    if key.is_empty() {
+      return Err("memory key is required".to_string());
+    }
+    if input.scope != MemoryScope::Global && subject.is_empty() {
+      return Err(format!("{} memory needs a subject", input.scope.label()));
+    }
+    if self
+      .data
+      .long_term
+      .iter()
+      .any(|it| it.id != id && it.same_slot(input.scope, subject, key))
+    {
+      return Err(format!("memory item {key} already exists in this scope"));
+    }
+    let it = self
+      .data
+      .long_term
+      .iter_mut()
+      .find(|it| it.id == id)
+      .ok_or_else(|| format!("memory item not found: {id}"))?;
+    it.key = key.to_string();
+    it.value = input.value;
+    it.scope = input.scope;
+    it.subject = subject.to_string();
+    it.pinned = input.pinned.unwrap_or(it.pinned);
+    it.source = input.source;
+    it.updated_at = Utc::now().timestamp_millis();
+    Ok(it.clone())
+  }
+
+  pub fn delete(&mut self, id: &str) -> Option<MemoryItem> {
+    let idx = self.data.long_term.iter().position(|it| it.id == id)?;
+    Some(self.data.long_term.remove(idx))
+  }
+
+  pub fn delete_slot(&mut self, scope: MemoryScope, subject: &str, key: &str) -> Option<MemoryItem> {
+    let idx = self
+      .data
+      .long_term
+      .iter()
+      .position(|it| it.same_slot(scope, subject.trim(), key.trim()))?;
+    Some(self.data.long_term.remove(idx))
+  }
+
+  /// Matching items, most recently updated first.
+  pub fn list(&self, filter: &MemoryFilter) -> Vec<MemoryItem> {
+    let query = filter
+      .query
+      .as_deref()
+      .map(|q| q.trim().to_lowercase())
+      .filter(|q| !q.is_empty());
+    let mut out: Vec<MemoryItem> = self
+      .data
+      .long_term
+      .iter()
+      .filter(|it| filter.scope.map(|s| it.scope == s).unwrap_or(true))
+      .filter(|it| {
+        filter
+          .subject
+          .as_deref()
+          .map(|s| it.subject.eq_ignore_ascii_case(s.trim()))
+          .unwrap_or(true)
+      })
+      .filter(|it| !filter.pinned_only || it.pinned)
+      .filter(|it| {
+        query
+          .as_deref()
+          .map(|q| {
+            it.key.to_lowercase().contains(q) || it.value.to_lowercase().contains(q) || it.subject.to_lowercase().contains(q)
+          })
+          .unwrap_or(true)
+      })
+      .cloned()
+      .collect();
+    out.sort_by_key(|it| std::cmp::Reverse(it.updated_at));
+    out
+  }
+
+  pub fn items(&self) -> &[MemoryItem] {
+    &self.data.long_term
+  }
+
+  /// BM25-ranked items; falls back to substring matching when the query has
+  /// no indexable terms (e.g. a single Latin letter).
+  pub fn search(&self, query: &str, limit: usize) -> Vec<MemoryItem> {
+    if story_index::tokenize(query).is_empty() {
+      let mut hits = self.list(&MemoryFilter {
+        query: Some(query.to_string()),
+        ..Default::default()
+      });
+      hits.truncate(limit);
+      return hits;
+    }
+    self.rank(query, limit, |_| true)
+  }
+
+  fn rank(&self, query: &str, limit: usize, keep: impl Fn(&MemoryItem) -> bool) -> Vec<MemoryItem> {
+    let mut index = Bm25::default();
+    for it in self.data.long_term.iter().filter(|it| keep(it)) {
+      index.insert(&it.id, &story_index::memory_document(it));
+    }
+    index
+      .search(query, limit, |_| true)
+      .into_iter()
+      .filter_map(|(id, _)| self.data.long_term.iter().find(|it| it.id == id).cloned())
+      .collect()
+  }
+
+  /// Items for the system prompt: every pinned item, then the unpinned items
+  /// ranked against `request`, at most `limit` in total.
+  pub fn select_for_prompt(&self, request: &str, limit: usize) -> Vec<MemoryItem> {
+    let mut pinned = self.list(&MemoryFilter {
+      pinned_only: true,
+      ..Default::default()
+    });
+    pinned.truncate(limit);
+    let rest = limit - pinned.len();
+    if rest > 0 {
+      pinned.extend(self.rank(request, rest, |it| !it.pinned));
+    }
+    pinned
+  }
+}
+
+/// Renders items as prompt lines, e.g. `- [character: 林默] age: 27`.
+pub fn render_items(items: &[MemoryItem]) -> String {
+  let mut out = String::new();
+  for it in items {
+    out.push_str("- ");
+    if it.scope != MemoryScope::Global {
+      out.push_str(&format!("[{}: {}] ", it.scope.label(), it.subject));
+    }
+    out.push_str(it.key.trim());
+    out.push_str(": ");
+    out.push_str(it.value.trim());
+    out.push('\n');
+  }
+  out.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn item(key: &str, value: &str, scope: MemoryScope, subject: &str, pinned: bool) -> MemoryItem {
+    MemoryItem {
+      id: key.to_string(),
+      key: key.to_string(),
+      value: value.to_string(),
+      scope,
+      subject: subject.to_string(),
+      pinned,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn selects_pinned_and_relevant_items() {
+    let store = MemoryStore {
+      path: PathBuf::new(),
+      data: MemoryStoreData {
+        long_term: vec![
+          item("tone", "冷峻克制", MemoryScope::Global, "", true),
+          item("年龄", "二十七岁", MemoryScope::Character, "林默", false),
+          item("weather", "always raining", MemoryScope::Location, "Harbor", false),
+          item("伏笔", "林默的怀表", MemoryScope::Chapter, "stories/ch3.md", false),
+        ],
+      },
+    };
+    let picked = store.select_for_prompt("写林默在第三章的独白", 10);
+    let keys = picked.iter().map(|it| it.key.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys.len(), 3);
+    assert_eq!(keys[0], "tone");
+    assert!(!keys.contains(&"weather"));
+  }
+}
@@ -1,14 +1,29 @@
-use crate::ai_types::ChatMessage;
-use crate::app_settings::AiEditApplyMode;
+use crate::agent_memory::{self, MemoryFilter, MemoryScope, MemorySource, MemoryStore, MemoryUpsert};
+use crate::agents;
+use crate::ai_types::{ChatMessage, ModelTurn, ToolCall, ToolSpec};
+use crate::app_settings::{AiEditApplyMode, ToolPermission};
+use crate::cancel_token::CancelToken;
 use crate::commands;
+use crate::context_budget;
+use crate::generation_params::GenerationParams;
+use crate::modification_types::ChangeSet;
 use crate::prompt_config;
+use crate::prompt_tools::{self, PromptTool};
+use crate::run_checkpoint::RunCheckpoint;
+use crate::run_trace::{RunTraceWriter, TraceLine, TraceRecord};
+use crate::story_index;
+use crate::text_search;
+use crate::tool_schema::{self, SchemaIssue};
+use crate::workspace_overlay::WorkspaceOverlay;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::future::Future;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 fn ensure_default_ext(path: &str) -> String {
   let rel_norm = path.trim().replace('\\', "/");
@@ -29,12 +44,202 @@ fn ensure_default_ext(path: &str) -> String {
 #[derive(Clone)]
 pub struct ToolContext {
   pub workspace_root: PathBuf,
+  pub memory: Arc<Mutex<MemoryStore>>,
+  /// Content hash per file as the model last saw it, keyed by normalized path.
+  pub seen_versions: Arc<Mutex<HashMap<String, String>>>,
+  /// Stream id recorded as the source of memory written by this run.
+  pub run_id: Option<String>,
+  /// Dry-run overlay; when set, file tools record changes instead of writing.
+  pub overlay: Option<Arc<Mutex<WorkspaceOverlay>>>,
+  /// Raised when the stream is cancelled; long tools check it between units of work.
+  pub cancel: CancelToken,
 }
 
-pub type ToolFn = Box<dyn Fn(&ToolContext, Value) -> Result<Value, String> + Send + Sync>;
+impl ToolContext {
+  pub fn remember_version(&self, rel_path: &str, content: &str) {
+    if let Ok(mut seen) = self.seen_versions.lock() {
+      seen.insert(normalize_seen_key(rel_path), blake3::hash(content.as_bytes()).to_hex().to_string());
+    }
+  }
+
+  /// Fails unless `current` is exactly what the model last read or wrote.
+  pub fn ensure_seen_version(&self, rel_path: &str, current: &str) -> Result<(), String> {
+    let seen = self
+      .seen_versions
+      .lock()
+      .map_err(|_| "seen versions lock poisoned".to_string())?;
+    match seen.get(&normalize_seen_key(rel_path)) {
+      None => Err(format!("read {rel_path} before patching it")),
+      Some(hash) if *hash != blake3::hash(current.as_bytes()).to_hex().to_string() => Err(format!(
+        "{rel_path} changed since it was last read; read it again and rebuild the patch"
+      )),
+      Some(_) => Ok(()),
+    }
+  }
+
+  fn with_overlay<T>(&self, f: impl FnOnce(&mut WorkspaceOverlay) -> Result<T, String>) -> Option<Result<T, String>> {
+    let overlay = self.overlay.as_ref()?;
+    Some(match overlay.lock() {
+      Ok(mut overlay) => f(&mut overlay),
+      Err(_) => Err("workspace overlay lock poisoned".to_string()),
+    })
+  }
+
+  pub fn read_text(&self, path: &str) -> Result<String, String> {
+    let rel = commands::validate_relative_path(path)?;
+    if let Some(result) = self.with_overlay(|o| o.read_text(&overlay_key(&rel))) {
+      return result;
+    }
+    fs::read_to_string(self.workspace_root.join(rel)).map_err(|e| format!("read failed: {e}"))
+  }
+
+  /// `Some("file")`, `Some("dir")`, or `None` when nothing exists at `path`.
+  pub fn path_kind(&self, path: &str) -> Result<Option<&'static str>, String> {
+    let rel = commands::validate_relative_path(path)?;
+    if let Some(result) = self.with_overlay(|o| Ok(o.kind(&overlay_key(&rel)))) {
+      return result;
+    }
+    match fs::metadata(self.workspace_root.join(rel)) {
+      Ok(md) => Ok(Some(if md.is_dir() { "dir" } else { "file" })),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(format!("stat failed: {e}")),
+    }
+  }
+
+  /// Entries of a directory as `(name, kind)`; an empty path is the root.
+  pub fn list_dir(&self, path: &str) -> Result<Vec<(String, &'static str)>, String> {
+    let rel = if path.trim().is_empty() {
+      PathBuf::new()
+    } else {
+      commands::validate_relative_path(path)?
+    };
+    if let Some(result) = self.with_overlay(|o| o.list_dir(&overlay_key(&rel))) {
+      return result;
+    }
+    let mut items = Vec::new();
+    for e in fs::read_dir(self.workspace_root.join(rel)).map_err(|e| format!("read dir failed: {e}"))? {
+      let e = e.map_err(|e| format!("read entry failed: {e}"))?;
+      let p = e.path();
+      let name = p.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+      items.push((name, if p.is_dir() { "dir" } else { "file" }));
+    }
+    Ok(items)
+  }
+
+  pub fn write_text(&self, path: &str, content: &str, create_parent_dirs: bool, history_reason: &str) -> Result<(), String> {
+    let rel = commands::validate_relative_path(path)?;
+    if let Some(result) = self.with_overlay(|o| o.write_text(&overlay_key(&rel), content, create_parent_dirs)) {
+      return result;
+    }
+    commands::write_text_internal(&self.workspace_root, path, content, create_parent_dirs, history_reason)
+  }
+
+  pub fn create_dir(&self, path: &str) -> Result<(), String> {
+    let rel = commands::validate_relative_path(path)?;
+    if let Some(result) = self.with_overlay(|o| o.create_dir(&overlay_key(&rel))) {
+      return result;
+    }
+    fs::create_dir_all(self.workspace_root.join(rel)).map_err(|e| format!("create dir failed: {e}"))
+  }
+
+  /// Creates an empty file and its parents; an existing file is left as is.
+  pub fn create_file(&self, path: &str) -> Result<(), String> {
+    if self.path_kind(path)?.is_some() {
+      return Ok(());
+    }
+    let rel = commands::validate_relative_path(path)?;
+    if let Some(result) = self.with_overlay(|o| o.write_text(&overlay_key(&rel), "", true)) {
+      return result;
+    }
+    let target = self.workspace_root.join(rel);
+    if let Some(parent) = target.parent() {
+      fs::create_dir_all(parent).map_err(|e| format!("create parent dir failed: {e}"))?;
+    }
+    fs::write(&target, "").map_err(|e| format!("create file failed: {e}"))
+  }
+
+  /// Deletes a file or directory; a missing path is not an error.
+  pub fn delete_entry(&self, path: &str) -> Result<(), String> {
+    let rel = commands::validate_relative_path(path)?;
+    if let Some(result) = self.with_overlay(|o| o.delete_entry(&overlay_key(&rel))) {
+      return result;
+    }
+    let target = self.workspace_root.join(rel);
+    if !target.exists() {
+      return Ok(());
+    }
+    let md = fs::metadata(&target).map_err(|e| format!("stat failed: {e}"))?;
+    if md.is_dir() {
+      fs::remove_dir_all(&target).map_err(|e| format!("remove dir failed: {e}"))
+    } else {
+      fs::remove_file(&target).map_err(|e| format!("remove file failed: {e}"))
+    }
+  }
+
+  pub fn rename_entry(&self, from: &str, to: &str) -> Result<(), String> {
+    let from_rel = commands::validate_relative_path(from)?;
+    let to_rel = commands::validate_relative_path(to)?;
+    if let Some(result) = self.with_overlay(|o| o.rename_entry(&overlay_key(&from_rel), &overlay_key(&to_rel))) {
+      return result;
+    }
+    let to_abs = self.workspace_root.join(to_rel);
+    if let Some(parent) = to_abs.parent() {
+      fs::create_dir_all(parent).map_err(|e| format!("create parent dir failed: {e}"))?;
+    }
+    fs::rename(self.workspace_root.join(from_rel), to_abs).map_err(|e| format!("rename failed: {e}"))
+  }
+}
+
+fn overlay_key(rel: &Path) -> String {
+  rel.to_string_lossy().replace('\\', "/").trim_end_matches('/').to_string()
+}
+
+fn normalize_seen_key(rel_path: &str) -> String {
+  rel_path.trim().replace('\\', "/").trim_start_matches("./").to_string()
+}
+
+pub type ToolFn = Arc<dyn Fn(&ToolContext, Value) -> Result<Value, String> + Send + Sync>;
+pub type AsyncToolFn = Box<dyn Fn(ToolContext, Value) -> BoxFuture<Result<Value, String>> + Send + Sync>;
+
+enum ToolHandler {
+  Sync(ToolFn),
+  Async(AsyncToolFn),
+}
+
+pub struct ToolDef {
+  pub description: String,
+  pub schema: Value,
+  /// Read-only tools may run concurrently within one agent step.
+  pub read_only: bool,
+  /// Limit for one call of an async tool; sync tools cannot be interrupted.
+  pub timeout: Option<Duration>,
+  handler: ToolHandler,
+}
+
+/// Why a tool call did not produce a result.
+#[derive(Debug)]
+pub enum ToolError {
+  Unknown(String),
+  InvalidArgs(Vec<SchemaIssue>),
+  Failed(String),
+}
+
+impl ToolError {
+  /// Structured observation handed back to the model.
+  pub fn to_observation(&self, tool: &str) -> Value {
+    match self {
+      ToolError::Unknown(name) => serde_json::json!({ "error": format!("unknown tool: {name}") }),
+      ToolError::InvalidArgs(issues) => serde_json::json!({
+        "error": format!("invalid arguments for {tool}"),
+        "issues": issues,
+      }),
+      ToolError::Failed(e) => serde_json::json!({ "error": e }),
+    }
+  }
+}
 
 pub struct ToolRegistry {
-  tools: HashMap<String, ToolFn>,
+  tools: HashMap<String, ToolDef>,
 }
 
 impl ToolRegistry {
@@ -42,99 +247,225 @@ impl ToolRegistry {
     Self { tools: HashMap::new() }
   }
 
-  pub fn register<F>(&mut self, name: &str, f: F)
+  pub fn register<F>(&mut self, name: &str, description: &str, schema: Value, f: F)
   where
     F: Fn(&ToolContext, Value) -> Result<Value, String> + Send + Sync + 'static,
   {
-    self.tools.insert(name.to_string(), Box::new(f));
+    self.insert(name, description, schema, false, ToolHandler::Sync(Arc::new(f)));
   }
 
-  pub fn call(&self, ctx: &ToolContext, name: &str, args: Value) -> Result<Value, String> {
-    let f = self.tools.get(name).ok_or_else(|| format!("unknown tool: {name}"))?;
-    f(ctx, args)
+  pub fn register_read_only<F>(&mut self, name: &str, description: &str, schema: Value, f: F)
+  where
+    F: Fn(&ToolContext, Value) -> Result<Value, String> + Send + Sync + 'static,
+  {
+    self.insert(name, description, schema, true, ToolHandler::Sync(Arc::new(f)));
   }
 
-  pub fn list(&self) -> Vec<String> {
-    let mut out = self.tools.keys().cloned().collect::<Vec<_>>();
-    out.sort();
-    out
+  /// Registers a tool whose handler returns a future. The future is polled
+  /// inside the stream task, so cancelling the stream (aborting its handle in
+  /// `AppState::ai_stream_tasks`) drops it mid-call; handlers must not detach
+  /// work that outlives it. Calls fail once `timeout` elapses.
+  pub fn register_async<F, Fut>(
+    &mut self,
+    name: &str,
+    description: &str,
+    schema: Value,
+    read_only: bool,
+    timeout: Duration,
+    f: F,
+  ) where
+    F: Fn(ToolContext, Value) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Value, String>> + Send + 'static,
+  {
+    let handler: AsyncToolFn = Box::new(move |ctx, args| Box::pin(f(ctx, args)));
+    self.insert(name, description, schema, read_only, ToolHandler::Async(handler));
+    self.set_timeout(name, timeout);
   }
-}
 
-#[derive(Serialize, Deserialize, Clone, Default)]
-pub struct MemoryItem {
-  pub key: String,
-  pub value: String,
-}
+  fn insert(&mut self, name: &str, description: &str, schema: Value, read_only: bool, handler: ToolHandler) {
+    self.tools.insert(
+      name.to_string(),
+      ToolDef {
+        description: description.to_string(),
+        schema,
+        read_only,
+        timeout: None,
+        handler,
+      },
+    );
+  }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
-pub struct MemoryStoreData {
-  pub long_term: Vec<MemoryItem>,
-}
+  /// Overrides the call limit of an async tool; ignored for sync tools.
+  pub fn set_timeout(&mut self, name: &str, timeout: Duration) {
+    if let Some(def) = self.tools.get_mut(name) {
+      if matches!(def.handler, ToolHandler::Async(_)) {
+        def.timeout = Some(timeout);
+      }
+    }
+  }
 
-pub struct MemoryStore {
-  path: PathBuf,
-  data: MemoryStoreData,
-}
+  pub fn remove(&mut self, name: &str) {
+    self.tools.remove(name);
+  }
 
-impl MemoryStore {
-  pub fn load(workspace_root: &Path) -> Self {
-    let path = workspace_root.join(".novel").join(".cache").join("agent_memory.json");
-    let data = fs::read_to_string(&path)
-      .ok()
-      .and_then(|raw| serde_json::from_str::<MemoryStoreData>(&raw).ok())
-      .unwrap_or_default();
-    Self { path, data }
+  pub fn is_read_only(&self, name: &str) -> bool {
+    self.tools.get(name).map(|def| def.read_only).unwrap_or(false)
   }
 
-  pub fn save(&self) -> Result<(), String> {
-    if let Some(parent) = self.path.parent() {
-      fs::create_dir_all(parent).map_err(|e| format!("create memory dir failed: {e}"))?;
+  pub fn validate(&self, name: &str, args: &Value) -> Result<(), ToolError> {
+    let def = self.tools.get(name).ok_or_else(|| ToolError::Unknown(name.to_string()))?;
+    let issues = tool_schema::validate(&def.schema, args);
+    if issues.is_empty() {
+      Ok(())
+    } else {
+      Err(ToolError::InvalidArgs(issues))
     }
-    let raw = serde_json::to_string_pretty(&self.data).map_err(|e| format!("serialize memory failed: {e}"))?;
-    fs::write(&self.path, raw).map_err(|e| format!("write memory failed: {e}"))
   }
 
-  pub fn upsert(&mut self, key: &str, value: &str) {
-    if let Some(it) = self.data.long_term.iter_mut().find(|x| x.key == key) {
-      it.value = value.to_string();
-      return;
+  pub async fn call_async(&self, ctx: &ToolContext, name: &str, args: Value) -> Result<Value, ToolError> {
+    self.validate(name, &args)?;
+    let def = self.tools.get(name).ok_or_else(|| ToolError::Unknown(name.to_string()))?;
+    let call = match &def.handler {
+      ToolHandler::Sync(f) => return f(ctx, args).map_err(ToolError::Failed),
+      ToolHandler::Async(f) => f(ctx.clone(), args),
+    };
+    let limited = async {
+      match def.timeout {
+        Some(limit) => tokio::time::timeout(limit, call)
+          .await
+          .map_err(|_| format!("{name} timed out after {}s", limit.as_secs()))?,
+        None => call.await,
+      }
+    };
+    match ctx.cancel.run_until_cancelled(limited).await {
+      Some(result) => result.map_err(ToolError::Failed),
+      None => Err(ToolError::Failed(RUN_CANCELLED.to_string())),
     }
-    self.data.long_term.push(MemoryItem {
-      key: key.to_string(),
-      value: value.to_string(),
-    });
   }
 
-  pub fn search(&self, query: &str, limit: usize) -> Vec<MemoryItem> {
-    let q = query.to_lowercase();
-    self.data
-      .long_term
-      .iter()
-      .filter(|it| it.key.to_lowercase().contains(&q) || it.value.to_lowercase().contains(&q))
-      .take(limit)
-      .cloned()
+  /// Like `call_async`, but a sync handler runs on the blocking pool so the
+  /// calls of a read-only batch overlap.
+  pub async fn call_blocking(&self, ctx: &ToolContext, name: &str, args: Value) -> Result<Value, ToolError> {
+    self.validate(name, &args)?;
+    let def = self.tools.get(name).ok_or_else(|| ToolError::Unknown(name.to_string()))?;
+    let f = match &def.handler {
+      ToolHandler::Sync(f) => f.clone(),
+      ToolHandler::Async(_) => return self.call_async(ctx, name, args).await,
+    };
+    let ctx = ctx.clone();
+    tauri::async_runtime::spawn_blocking(move || f(&ctx, args))
+      .await
+      .map_err(|e| ToolError::Failed(format!("{name} task failed: {e}")))?
+      .map_err(ToolError::Failed)
+  }
+
+  pub fn list(&self) -> Vec<String> {
+    let mut out = self.tools.keys().cloned().collect::<Vec<_>>();
+    out.sort();
+    out
+  }
+
+  pub fn specs(&self) -> Vec<ToolSpec> {
+    self
+      .list()
+      .into_iter()
+      .filter_map(|name| {
+        let def = self.tools.get(&name)?;
+        Some(ToolSpec {
+          description: def.description.clone(),
+          parameters: def.schema.clone(),
+          name,
+        })
+      })
       .collect()
   }
 
-  pub fn render(&self, limit: usize) -> String {
-    let mut out = String::new();
-    for it in self.data.long_term.iter().take(limit) {
-      out.push_str("- ");
-      out.push_str(it.key.trim());
-      out.push_str(": ");
-      out.push_str(it.value.trim());
-      out.push('\n');
-    }
-    out.trim().to_string()
+  /// One line per tool for the text protocol's `{{TOOLS}}` placeholder.
+  pub fn render_prompt_list(&self) -> String {
+    self
+      .specs()
+      .iter()
+      .map(|spec| tool_schema::render_tool_line(&spec.name, &spec.description, &spec.parameters))
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+}
+
+fn str_arg<'a>(args: &'a Value, key: &str) -> &'a str {
+  args.get(key).and_then(|v| v.as_str()).unwrap_or("")
+}
+
+fn path_schema(description: &str) -> Value {
+  serde_json::json!({
+    "type": "object",
+    "properties": {
+      "path": { "type": "string", "minLength": 1, "description": description }
+    },
+    "required": ["path"],
+    "additionalProperties": false
+  })
+}
+
+/// Parses a `sources` list such as `["memory", "chapters"]`; missing or empty means all.
+pub(crate) fn story_sources(value: Option<&Value>) -> story_index::StorySources {
+  let listed = value
+    .and_then(|v| v.as_array())
+    .map(|arr| arr.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>())
+    .unwrap_or_default();
+  if listed.is_empty() {
+    return story_index::StorySources {
+      memory: true,
+      chapters: true,
+      concepts: true,
+    };
+  }
+  story_index::StorySources {
+    memory: listed.contains(&"memory"),
+    chapters: listed.contains(&"chapters"),
+    concepts: listed.contains(&"concepts"),
   }
 }
 
+const DELEGATE_TOOL: &str = "delegate";
+/// Error returned by `run_react` when the run's cancel token is raised.
+pub const RUN_CANCELLED: &str = "agent run cancelled";
+const MEMORY_SCOPES: [&str; 4] = ["global", "character", "location", "chapter"];
+/// Memory items injected into the system prompt (pinned ones count too).
+const PROMPT_MEMORY_ITEMS: usize = 30;
+const SEARCH_TOOL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Mutating tools that only change workspace files, so a dry run keeps them off the disk.
+const FILE_WRITE_TOOLS: [&str; 6] = [
+  "fs_create_dir",
+  "fs_create_file",
+  "fs_delete_entry",
+  "fs_rename_entry",
+  "fs_write_text",
+  "fs_patch_text",
+];
+
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct AgentPerf {
   pub steps: u32,
+  pub tool_calls: u32,
   pub model_ms: u128,
   pub tool_ms: u128,
+  /// Estimated prompt-token budget; 0 when no budget was set.
+  pub context_budget: usize,
+  pub peak_prompt_tokens: usize,
+  pub compacted_messages: u32,
+  pub compacted_tokens: usize,
+  /// `final_answer`, `stop_marker` or `max_steps`.
+  pub stop_reason: String,
+  /// Provider-reported tokens of this run's own model calls; delegated runs
+  /// and prompt tools are not included.
+  #[serde(default)]
+  pub input_tokens: u64,
+  #[serde(default)]
+  pub output_tokens: u64,
+  /// Provider of each model step, in order; differs from the run's provider
+  /// after a fallback.
+  #[serde(default)]
+  pub step_providers: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -147,313 +478,1178 @@ pub struct AgentToolEvent {
   pub ok: Option<bool>,
   pub observation: Option<Value>,
   pub duration_ms: Option<u128>,
+  /// Set on events of a delegated child run.
+  #[serde(default)]
+  pub parent_action_id: Option<String>,
+  /// Provider whose model step requested the call.
+  #[serde(default)]
+  pub provider_id: Option<String>,
+}
+
+/// Outcome of one recorded tool call in `AgentRuntime::replay_trace`.
+#[derive(Serialize, Clone)]
+pub struct ReplayStep {
+  pub step: u32,
+  pub action_id: String,
+  pub tool: String,
+  pub recorded_ok: bool,
+  pub valid: bool,
+  pub issues: Value,
+  pub executed: bool,
+  pub observation: Option<Value>,
+  pub matches_recorded: Option<bool>,
+}
+
+/// Result of `AgentRuntime::replay_trace`.
+#[derive(Serialize, Clone)]
+pub struct RunReplay {
+  pub steps: Vec<ReplayStep>,
+  /// What the replayed file writes would change, left pending for review.
+  pub change_set: Option<ChangeSet>,
+}
+
+/// Sent when a tool with the `ask` permission is about to run.
+#[derive(Serialize, Clone)]
+pub struct ToolApprovalRequest {
+  pub action_id: String,
+  pub step: u32,
+  pub tool: String,
+  pub args: Value,
 }
 
 pub struct AgentRuntime {
   ctx: ToolContext,
   tools: ToolRegistry,
-  memory: MemoryStore,
+  permissions: BTreeMap<String, ToolPermission>,
+  context_budget: Option<usize>,
+  max_steps: u32,
+  stop_marker: Option<String>,
+  trace: Option<RunTraceWriter>,
+  /// `AppSettings::tool_timeout_secs`, reapplied to delegated runs.
+  tool_timeouts: BTreeMap<String, u32>,
+  /// Agents reachable through the `delegate` tool.
+  delegates: Vec<agents::Agent>,
+  max_delegation_depth: u32,
+  /// 0 for the top-level run, +1 per delegation.
+  depth: u32,
+  /// Action id of the `delegate` call that started this (child) run.
+  parent_action_id: Option<String>,
+  /// Sampling temperature of a delegated agent; `None` leaves it to the caller.
+  temperature: Option<f32>,
+  /// Sampling overrides of a delegated agent; `None` leaves them to the caller.
+  generation: Option<GenerationParams>,
+  /// Workspace tools from `.novel/tools`, answered by one model call each.
+  prompt_tools: HashMap<String, PromptTool>,
+  /// Snapshot taken at every step boundary of the top-level run.
+  checkpoint: Arc<Mutex<Option<RunCheckpoint>>>,
+  /// Checkpoint the next run continues from instead of starting over.
+  resume: Option<RunCheckpoint>,
+  /// Agent this runtime runs as; sent with every model request.
+  agent_id: String,
 }
 
+/// Callbacks of one `run_react` call, shared with delegated child runs.
+struct RunHooks<'h> {
+  call_model: &'h (dyn Fn(ModelRequest) -> BoxFuture<Result<ModelTurn, String>> + Sync),
+  approve_tool: &'h (dyn Fn(ToolApprovalRequest) -> BoxFuture<bool> + Sync),
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type BoxFutureRef<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
 impl AgentRuntime {
   pub fn new(workspace_root: PathBuf) -> Self {
     let ctx = ToolContext {
       workspace_root: workspace_root.clone(),
+      memory: Arc::new(Mutex::new(MemoryStore::load(&workspace_root))),
+      seen_versions: Arc::new(Mutex::new(HashMap::new())),
+      run_id: None,
+      overlay: None,
+      cancel: CancelToken::new(),
     };
-    let memory = MemoryStore::load(&workspace_root);
     let mut tools = ToolRegistry::new();
-    tools.register("fs_read_text", |ctx, args| {
-      let path = args
-        .get("path")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.path".to_string())?;
-      if path.trim().is_empty() {
-        return Err("empty path".to_string());
-      }
-      let rel = commands::validate_relative_path(path)?;
-      let target = ctx.workspace_root.join(rel);
-      let raw = fs::read_to_string(target).map_err(|e| format!("read failed: {e}"))?;
-      Ok(serde_json::json!({ "text": raw }))
-    });
-    tools.register("fs_list_dir", |ctx, args| {
-      let path = args.get("path").and_then(|v| v.as_str()).unwrap_or("");
-      let rel = if path.trim().is_empty() {
-        PathBuf::from("")
-      } else {
-        commands::validate_relative_path(path)?
-      };
-      let target = ctx.workspace_root.join(rel);
-      let mut items: Vec<Value> = Vec::new();
-      for e in fs::read_dir(target).map_err(|e| format!("read dir failed: {e}"))? {
-        let e = e.map_err(|e| format!("read entry failed: {e}"))?;
-        let p = e.path();
-        let name = p.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
-        let kind = if p.is_dir() { "dir" } else { "file" };
-        items.push(serde_json::json!({ "name": name, "kind": kind }));
-      }
-      Ok(serde_json::json!({ "items": items }))
-    });
-    tools.register("fs_exists", |ctx, args| {
-      let path = args
-        .get("path")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.path".to_string())?;
-      if path.trim().is_empty() {
-        return Err("empty path".to_string());
-      }
-      let rel = commands::validate_relative_path(path)?;
-      let target = ctx.workspace_root.join(rel);
-      let md = match fs::metadata(&target) {
-        Ok(v) => v,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-          return Ok(serde_json::json!({ "exists": false }))
+    tools.register_read_only(
+      "fs_read_text",
+      "Read a UTF-8 text file.",
+      path_schema("Relative file path."),
+      |ctx, args| {
+        let raw = ctx.read_text(str_arg(&args, "path"))?;
+        ctx.remember_version(str_arg(&args, "path"), &raw);
+        Ok(serde_json::json!({ "text": raw }))
+      },
+    );
+    tools.register_read_only(
+      "fs_read_lines",
+      "Read a page of a text file with 1-based line numbers, plus total line and character counts.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "path": { "type": "string", "minLength": 1, "description": "Relative file path." },
+          "start_line": { "type": "integer", "minimum": 1, "description": "First line to return (default 1)." },
+          "max_lines": { "type": "integer", "minimum": 1, "maximum": 1000, "description": "Page size (default 200)." }
+        },
+        "required": ["path"],
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let path = str_arg(&args, "path");
+        let raw = ctx.read_text(path)?;
+        ctx.remember_version(path, &raw);
+        let start = args.get("start_line").and_then(|v| v.as_u64()).unwrap_or(1).max(1) as usize;
+        let max_lines = args.get("max_lines").and_then(|v| v.as_u64()).unwrap_or(200) as usize;
+        Ok(numbered_page(&raw, start, max_lines))
+      },
+    );
+    tools.register_read_only(
+      "fs_list_dir",
+      "List entries of a directory; an empty path lists the project root.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "path": { "type": "string", "description": "Relative directory path." }
+        },
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let items = ctx
+          .list_dir(str_arg(&args, "path"))?
+          .into_iter()
+          .map(|(name, kind)| serde_json::json!({ "name": name, "kind": kind }))
+          .collect::<Vec<_>>();
+        Ok(serde_json::json!({ "items": items }))
+      },
+    );
+    tools.register_read_only(
+      "fs_exists",
+      "Check whether a file or directory exists.",
+      path_schema("Relative path to check."),
+      |ctx, args| {
+        match ctx.path_kind(str_arg(&args, "path"))? {
+          Some(kind) => Ok(serde_json::json!({ "exists": true, "kind": kind })),
+          None => Ok(serde_json::json!({ "exists": false })),
         }
-        Err(e) => return Err(format!("stat failed: {e}")),
-      };
-      let kind = if md.is_dir() { "dir" } else { "file" };
-      Ok(serde_json::json!({ "exists": true, "kind": kind }))
-    });
-    tools.register("fs_create_dir", |ctx, args| {
-      let path = args
-        .get("path")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.path".to_string())?;
-      if path.trim().is_empty() {
-        return Err("empty path".to_string());
-      }
-      let rel = commands::validate_relative_path(path)?;
-      let target = ctx.workspace_root.join(rel);
-      fs::create_dir_all(&target).map_err(|e| format!("create dir failed: {e}"))?;
-      Ok(serde_json::json!({ "ok": true }))
-    });
-    tools.register("fs_create_file", |ctx, args| {
-      let path = args
-        .get("path")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.path".to_string())?;
-      if path.trim().is_empty() {
-        return Err("empty path".to_string());
-      }
-      let fixed = ensure_default_ext(path);
-      let rel = commands::validate_relative_path(fixed.as_str())?;
-      let target = ctx.workspace_root.join(rel);
-      if let Some(parent) = target.parent() {
-        fs::create_dir_all(parent).map_err(|e| format!("create parent dir failed: {e}"))?;
-      }
-      if !target.exists() {
-        fs::write(&target, "").map_err(|e| format!("create file failed: {e}"))?;
-      }
-      Ok(serde_json::json!({ "ok": true }))
-    });
-    tools.register("fs_delete_entry", |ctx, args| {
-      let path = args
-        .get("path")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.path".to_string())?;
-      if path.trim().is_empty() {
-        return Err("empty path".to_string());
-      }
-      let rel = commands::validate_relative_path(path)?;
-      let target = ctx.workspace_root.join(rel);
-      if !target.exists() {
-        return Ok(serde_json::json!({ "ok": true }));
-      }
-      let md = fs::metadata(&target).map_err(|e| format!("stat failed: {e}"))?;
-      if md.is_dir() {
-        fs::remove_dir_all(&target).map_err(|e| format!("remove dir failed: {e}"))?;
-      } else {
-        fs::remove_file(&target).map_err(|e| format!("remove file failed: {e}"))?;
-      }
-      Ok(serde_json::json!({ "ok": true }))
-    });
-    tools.register("fs_rename_entry", |ctx, args| {
-      let from = args
-        .get("from")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.from".to_string())?;
-      let to = args
-        .get("to")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.to".to_string())?;
-      if from.trim().is_empty() || to.trim().is_empty() {
-        return Err("empty path".to_string());
-      }
-      let to_fixed = ensure_default_ext(to);
-      let from_rel = commands::validate_relative_path(from)?;
-      let to_rel = commands::validate_relative_path(to_fixed.as_str())?;
-      let from_abs = ctx.workspace_root.join(from_rel);
-      let to_abs = ctx.workspace_root.join(to_rel);
-      if let Some(parent) = to_abs.parent() {
-        fs::create_dir_all(parent).map_err(|e| format!("create parent dir failed: {e}"))?;
-      }
-      fs::rename(from_abs, to_abs).map_err(|e| format!("rename failed: {e}"))?;
-      Ok(serde_json::json!({ "ok": true }))
-    });
-    tools.register("fs_write_text", |ctx, args| {
-      let path = args
-        .get("path")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| "missing args.path".to_string())?;
-      let text = args
-        .get("text")
-        .or_else(|| args.get("content"))
-        .and_then(|v| v.as_str())
-        .unwrap_or("");
-      if path.trim().is_empty() {
-        return Err("empty path".to_string());
+      },
+    );
+    tools.register_async(
+      "fs_search_text",
+      "Search project text files; returns path, line, column and a snippet for each match.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "query": { "type": "string", "minLength": 1, "description": "Literal text, or a regex when regex=true." },
+          "regex": { "type": "boolean" },
+          "case_sensitive": { "type": "boolean" },
+          "path_glob": { "type": "string", "description": "e.g. stories/**/*.md; without '/' it matches file names." },
+          "max_results": { "type": "integer", "minimum": 1, "maximum": 200 }
+        },
+        "required": ["query"],
+        "additionalProperties": false
+      }),
+      true,
+      SEARCH_TOOL_TIMEOUT,
+      |ctx, args| async move {
+        let options = text_search::SearchOptions {
+          query: str_arg(&args, "query").to_string(),
+          regex: args.get("regex").and_then(|v| v.as_bool()).unwrap_or(false),
+          case_sensitive: args.get("case_sensitive").and_then(|v| v.as_bool()).unwrap_or(false),
+          path_glob: args.get("path_glob").and_then(|v| v.as_str()).map(|v| v.to_string()),
+          max_results: args.get("max_results").and_then(|v| v.as_u64()).unwrap_or(30) as usize,
+        };
+        let root = ctx.workspace_root.clone();
+        // A dry run searches a snapshot of its overlay so the walk does not hold the lock.
+        let overlay = ctx.with_overlay(|o| Ok(o.clone())).transpose()?;
+        let cancel = ctx.cancel.clone();
+        let result = tauri::async_runtime::spawn_blocking(move || {
+          commands::search_text_internal(&root, overlay.as_ref(), &options, Some(&cancel))
+        })
+        .await
+        .map_err(|e| format!("search task failed: {e}"))??;
+        serde_json::to_value(result).map_err(|e| format!("encode search result failed: {e}"))
+      },
+    );
+    tools.register(
+      "fs_create_dir",
+      "Create a directory recursively.",
+      path_schema("Relative directory path."),
+      |ctx, args| {
+        ctx.create_dir(str_arg(&args, "path"))?;
+        Ok(serde_json::json!({ "ok": true }))
+      },
+    );
+    tools.register(
+      "fs_create_file",
+      "Create an empty file; parent directories are created automatically.",
+      path_schema("Relative file path; stories/, concept/ and outline/ default to .md."),
+      |ctx, args| {
+        ctx.create_file(&ensure_default_ext(str_arg(&args, "path")))?;
+        Ok(serde_json::json!({ "ok": true }))
+      },
+    );
+    tools.register(
+      "fs_delete_entry",
+      "Delete a file or directory. Only use when the user explicitly asks.",
+      path_schema("Relative path to delete."),
+      |ctx, args| {
+        ctx.delete_entry(str_arg(&args, "path"))?;
+        Ok(serde_json::json!({ "ok": true }))
+      },
+    );
+    tools.register(
+      "fs_rename_entry",
+      "Move or rename a file or directory from `from` to `to`.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "from": { "type": "string", "minLength": 1, "description": "Existing relative path." },
+          "to": { "type": "string", "minLength": 1, "description": "New relative path." }
+        },
+        "required": ["from", "to"],
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let to_fixed = ensure_default_ext(str_arg(&args, "to"));
+        ctx.rename_entry(str_arg(&args, "from"), &to_fixed)?;
+        Ok(serde_json::json!({ "ok": true }))
+      },
+    );
+    tools.register(
+      "fs_write_text",
+      "Create or overwrite a file with its full text; parent directories are created automatically.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "path": { "type": "string", "minLength": 1, "description": "Relative file path." },
+          "text": { "type": "string", "description": "Complete new file content." },
+          "content": { "type": "string", "description": "Alias of text." }
+        },
+        "required": ["path"],
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let text = args
+          .get("text")
+          .or_else(|| args.get("content"))
+          .and_then(|v| v.as_str())
+          .unwrap_or("");
+        let fixed = ensure_default_ext(str_arg(&args, "path"));
+        ctx.write_text(fixed.as_str(), text, true, "ai-write")?;
+        ctx.remember_version(fixed.as_str(), text);
+        Ok(serde_json::json!({ "ok": true }))
+      },
+    );
+    tools.register(
+      "fs_patch_text",
+      "Edit part of an existing file: op=replace/delete a line range, insert before start_line, or replace a unique anchor string. Read the file first.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "path": { "type": "string", "minLength": 1, "description": "Relative file path." },
+          "op": { "type": "string", "enum": ["replace", "insert", "delete", "anchor"] },
+          "start_line": { "type": "integer", "minimum": 1, "description": "1-based first line." },
+          "end_line": { "type": "integer", "minimum": 1, "description": "1-based last line, inclusive." },
+          "anchor": { "type": "string", "minLength": 1, "description": "Exact text that occurs once in the file." },
+          "text": { "type": "string", "description": "New text for replace/insert/anchor." }
+        },
+        "required": ["path", "op"],
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let path = str_arg(&args, "path");
+        let current = ctx.read_text(path)?;
+        ctx.ensure_seen_version(path, &current)?;
+        let patched = apply_text_patch(&current, &args)?;
+        ctx.write_text(path, &patched, false, "ai-patch")?;
+        ctx.remember_version(path, &patched);
+        Ok(serde_json::json!({
+          "ok": true,
+          "changed": patched != current,
+          "total_lines": patched.lines().count()
+        }))
+      },
+    );
+    tools.register_read_only(
+      "memory_search",
+      "Search long-term memory for stable facts.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "query": { "type": "string", "minLength": 1 },
+          "limit": { "type": "integer", "minimum": 1, "maximum": 100 }
+        },
+        "required": ["query"],
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(10) as usize;
+        let memory = ctx.memory.lock().map_err(|_| "memory lock poisoned".to_string())?;
+        let hits = memory.search(str_arg(&args, "query"), limit);
+        Ok(serde_json::to_value(hits).unwrap_or_else(|_| serde_json::json!([])))
+      },
+    );
+    tools.register_read_only(
+      "story_search",
+      "Ranked search over long-term memory, chapters and concept files; returns the best matching passages with line ranges.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "query": { "type": "string", "minLength": 1 },
+          "sources": {
+            "type": "array",
+            "items": { "type": "string", "enum": ["memory", "chapters", "concepts"] },
+            "description": "Defaults to all sources."
+          },
+          "limit": { "type": "integer", "minimum": 1, "maximum": 50 }
+        },
+        "required": ["query"],
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(8) as usize;
+        let items = {
+          let memory = ctx.memory.lock().map_err(|_| "memory lock poisoned".to_string())?;
+          memory.items().to_vec()
+        };
+        let overrides = ctx.with_overlay(|o| Ok(o.touched_files())).transpose()?.unwrap_or_default();
+        let hits = story_index::search(
+          &ctx.workspace_root,
+          &items,
+          &overrides,
+          str_arg(&args, "query"),
+          story_sources(args.get("sources")),
+          limit,
+        )?;
+        Ok(serde_json::json!({ "hits": hits }))
+      },
+    );
+    tools.register_read_only(
+      "memory_list",
+      "List long-term memory items, newest first, optionally filtered by scope and subject.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "scope": { "type": "string", "enum": MEMORY_SCOPES },
+          "subject": { "type": "string", "description": "Character, location or chapter path the items are about." },
+          "pinned_only": { "type": "boolean" },
+          "limit": { "type": "integer", "minimum": 1, "maximum": 200 }
+        },
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let filter = MemoryFilter {
+          scope: args.get("scope").and_then(|v| v.as_str()).map(MemoryScope::parse).transpose()?,
+          subject: args.get("subject").and_then(|v| v.as_str()).map(str::to_string),
+          pinned_only: args.get("pinned_only").and_then(|v| v.as_bool()).unwrap_or(false),
+          query: None,
+        };
+        let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
+        let memory = ctx.memory.lock().map_err(|_| "memory lock poisoned".to_string())?;
+        let mut items = memory.list(&filter);
+        let total = items.len();
+        items.truncate(limit);
+        Ok(serde_json::json!({ "items": items, "total": total }))
+      },
+    );
+    tools.register(
+      "memory_upsert",
+      "Persist a stable fact to long-term memory under a key, scoped to a character, location or chapter when it is about one.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "key": { "type": "string", "minLength": 1 },
+          "value": { "type": "string" },
+          "scope": { "type": "string", "enum": MEMORY_SCOPES, "description": "Defaults to global." },
+          "subject": { "type": "string", "description": "Required unless scope is global, e.g. a character name or chapter path." },
+          "pinned": { "type": "boolean", "description": "Pinned items are always in the prompt; use sparingly." },
+          "source_path": { "type": "string", "description": "File the fact was taken from." }
+        },
+        "required": ["key", "value"],
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let input = MemoryUpsert {
+          key: str_arg(&args, "key").to_string(),
+          value: str_arg(&args, "value").to_string(),
+          scope: MemoryScope::parse(str_arg(&args, "scope"))?,
+          subject: str_arg(&args, "subject").to_string(),
+          pinned: args.get("pinned").and_then(|v| v.as_bool()),
+          source: MemorySource {
+            origin: "agent".to_string(),
+            run_id: ctx.run_id.clone(),
+            path: Some(str_arg(&args, "source_path").trim().to_string()).filter(|p| !p.is_empty()),
+          },
+        };
+        let mut memory = ctx.memory.lock().map_err(|_| "memory lock poisoned".to_string())?;
+        memory.reload();
+        let (item, created) = memory.upsert(input)?;
+        memory.save()?;
+        Ok(serde_json::json!({ "ok": true, "id": item.id, "created": created }))
+      },
+    );
+    tools.register(
+      "memory_delete",
+      "Delete a long-term memory item by id, or by key within a scope and subject.",
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "id": { "type": "string", "minLength": 1 },
+          "key": { "type": "string", "minLength": 1 },
+          "scope": { "type": "string", "enum": MEMORY_SCOPES },
+          "subject": { "type": "string" }
+        },
+        "additionalProperties": false
+      }),
+      |ctx, args| {
+        let mut memory = ctx.memory.lock().map_err(|_| "memory lock poisoned".to_string())?;
+        memory.reload();
+        let removed = match args.get("id").and_then(|v| v.as_str()) {
+          Some(id) => memory.delete(id),
+          None if !str_arg(&args, "key").trim().is_empty() => memory.delete_slot(
+            MemoryScope::parse(str_arg(&args, "scope"))?,
+            str_arg(&args, "subject"),
+            str_arg(&args, "key"),
+          ),
+          None => return Err("id or key is required".to_string()),
+        };
+        if removed.is_some() {
+          memory.save()?;
+        }
+        Ok(serde_json::json!({ "ok": true, "deleted": removed.is_some() }))
+      },
+    );
+    let (workspace_tools, errors) = prompt_tools::load(&workspace_root);
+    for e in errors {
+      eprintln!("skipped workspace tool {e}");
+    }
+    let mut prompt_tools = HashMap::new();
+    for tool in workspace_tools {
+      if tool.name == DELEGATE_TOOL || tools.tools.contains_key(&tool.name) {
+        eprintln!("skipped workspace tool {}: name is taken by a built-in tool", tool.name);
+        continue;
       }
-      let fixed = ensure_default_ext(path);
-      commands::write_text_internal(&ctx.workspace_root, fixed.as_str(), text, true, "ai-write")?;
-      Ok(serde_json::json!({ "ok": true }))
-    });
-    Self { ctx, tools, memory }
+      tools.register(
+        &tool.name,
+        &tool.description,
+        tool.input_schema.clone(),
+        |_, _| Err("prompt tools only run inside the agent loop".to_string()),
+      );
+      prompt_tools.insert(tool.name.clone(), tool);
+    }
+    Self {
+      ctx,
+      tools,
+      permissions: BTreeMap::new(),
+      context_budget: None,
+      max_steps: agents::DEFAULT_MAX_STEPS,
+      stop_marker: None,
+      trace: None,
+      tool_timeouts: BTreeMap::new(),
+      delegates: Vec::new(),
+      max_delegation_depth: 0,
+      depth: 0,
+      parent_action_id: None,
+      temperature: None,
+      generation: None,
+      prompt_tools,
+      checkpoint: Arc::new(Mutex::new(None)),
+      resume: None,
+      agent_id: String::new(),
+    }
   }
 
-  pub fn tools(&self) -> Vec<String> {
-    let mut out = self.tools.list();
-    out.push("memory_upsert".to_string());
-    out.push("memory_search".to_string());
-    out.sort();
-    out
+  /// Enables the `delegate` tool for `agents` (the running agent excluded by
+  /// the caller); child runs may delegate again until `max_depth` is reached.
+  pub fn set_delegates(&mut self, agents: Vec<agents::Agent>, max_depth: u32) {
+    self.delegates = agents;
+    self.max_delegation_depth = max_depth;
+    if self.delegates.is_empty() || self.depth >= max_depth {
+      self.tools.remove(DELEGATE_TOOL);
+      return;
+    }
+    let ids = self.delegates.iter().map(|a| a.id.clone()).collect::<Vec<_>>();
+    let roster = self
+      .delegates
+      .iter()
+      .map(|a| format!("{} ({})", a.id, a.name))
+      .collect::<Vec<_>>()
+      .join(", ");
+    self.tools.register(
+      DELEGATE_TOOL,
+      &format!(
+        "Hand a self-contained sub-task (review, continuity check, research) to another assistant and get its final answer. Assistants: {roster}."
+      ),
+      serde_json::json!({
+        "type": "object",
+        "properties": {
+          "agent_id": { "type": "string", "enum": ids },
+          "task": { "type": "string", "minLength": 1, "description": "What the assistant should do and return." },
+          "context": { "type": "string", "description": "Facts or excerpts the assistant needs; it does not see this conversation." }
+        },
+        "required": ["agent_id", "task"],
+        "additionalProperties": false
+      }),
+      |_, _| Err("delegate only runs inside the agent loop".to_string()),
+    );
+  }
+
+  fn child_runtime(&self, agent: &agents::Agent, parent_action_id: &str) -> AgentRuntime {
+    let mut child = AgentRuntime::new(self.ctx.workspace_root.clone());
+    child.ctx = self.ctx.clone();
+    child.permissions = self.permissions.clone();
+    child.context_budget = self.context_budget;
+    child.set_tool_timeouts(&self.tool_timeouts);
+    child.trace = self.trace.as_ref().map(|trace| trace.for_delegate(parent_action_id));
+    child.set_run_limits(agent.max_steps, agent.stop_marker.clone());
+    child.depth = self.depth + 1;
+    child.parent_action_id = Some(parent_action_id.to_string());
+    child.temperature = Some(agent.temperature);
+    child.generation = Some(agent.generation_overrides());
+    child.agent_id = agent.id.clone();
+    let others = self
+      .delegates
+      .iter()
+      .filter(|a| a.id != agent.id)
+      .cloned()
+      .collect::<Vec<_>>();
+    child.set_delegates(others, self.max_delegation_depth);
+    child
+  }
+
+  /// Runs a `delegate` call as a child run and returns its final answer.
+  async fn delegate(
+    &self,
+    call: &ToolCall,
+    action_id: &str,
+    edit_apply_mode: AiEditApplyMode,
+    native_tools: bool,
+    hooks: &RunHooks<'_>,
+    on_tool_event: &mut (dyn FnMut(AgentToolEvent) + Send),
+  ) -> Result<Value, ToolError> {
+    self.tools.validate(&call.name, &call.arguments)?;
+    if self.depth >= self.max_delegation_depth {
+      return Err(ToolError::Failed(format!(
+        "delegation depth limit ({}) reached",
+        self.max_delegation_depth
+      )));
+    }
+    let agent_id = str_arg(&call.arguments, "agent_id");
+    let agent = self
+      .delegates
+      .iter()
+      .find(|a| a.id == agent_id)
+      .cloned()
+      .ok_or_else(|| ToolError::Failed(format!("unknown agent: {agent_id}")))?;
+    let mut prompt = str_arg(&call.arguments, "task").trim().to_string();
+    let context = str_arg(&call.arguments, "context").trim();
+    if !context.is_empty() {
+      prompt.push_str("\n\nContext:\n");
+      prompt.push_str(context);
+    }
+    let mut child = self.child_runtime(&agent, action_id);
+    let (answer, perf) = child
+      .run_loop(
+        vec![ChatMessage::new("user", prompt)],
+        agent.system_prompt.clone(),
+        edit_apply_mode,
+        native_tools,
+        hooks,
+        on_tool_event,
+      )
+      .await
+      .map_err(ToolError::Failed)?;
+    Ok(serde_json::json!({
+      "agent_id": agent.id,
+      "answer": answer,
+      "steps": perf.steps,
+      "tool_calls": perf.tool_calls,
+      "stop_reason": perf.stop_reason,
+    }))
+  }
+
+  /// Answers a workspace prompt tool call with one model completion.
+  async fn run_prompt_tool(&self, call: &ToolCall, hooks: &RunHooks<'_>) -> Result<Value, ToolError> {
+    self.tools.validate(&call.name, &call.arguments)?;
+    let tool = self
+      .prompt_tools
+      .get(&call.name)
+      .ok_or_else(|| ToolError::Unknown(call.name.clone()))?;
+    let mut messages = Vec::new();
+    if let Some(system) = tool.system.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+      messages.push(ChatMessage::new("system", system));
+    }
+    messages.push(ChatMessage::new("user", tool.render(&call.arguments)));
+    let turn = (hooks.call_model)(ModelRequest {
+      messages,
+      tools: Vec::new(),
+      temperature: tool.temperature.or(self.temperature),
+      generation: self.generation.clone(),
+      quiet: true,
+      agent_id: self.agent_id.clone(),
+    })
+    .await
+    .map_err(ToolError::Failed)?;
+    Ok(serde_json::json!({ "output": turn.text.trim() }))
+  }
+
+  /// Stream id stored as the source of memory items this run writes.
+  pub fn set_run_id(&mut self, run_id: String) {
+    self.ctx.run_id = Some(run_id);
+  }
+
+  pub fn set_agent_id(&mut self, agent_id: String) {
+    self.agent_id = agent_id;
+  }
+
+  /// Shares the stream's cancel token with the run and its tools.
+  pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+    self.ctx.cancel = cancel;
+  }
+
+  /// Makes the next `run_react` continue `checkpoint`; its base messages and
+  /// system prompt are ignored because the checkpoint carries the full prompt.
+  pub fn resume_from(&mut self, checkpoint: RunCheckpoint) {
+    if let Some(overlay) = checkpoint.overlay.clone() {
+      let overlay = overlay.with_root(self.ctx.workspace_root.clone());
+      self.ctx.overlay = Some(Arc::new(Mutex::new(overlay)));
+    }
+    self.resume = Some(checkpoint);
+  }
+
+  /// The last step-boundary snapshot of the top-level run, also available
+  /// after the run future was dropped by a timeout.
+  pub fn checkpoint(&self) -> Option<RunCheckpoint> {
+    self.checkpoint.lock().ok()?.clone()
+  }
+
+  fn save_checkpoint(&self, step: u32, messages: &[ChatMessage]) {
+    if self.depth > 0 {
+      return;
+    }
+    let overlay = self
+      .ctx
+      .overlay
+      .as_ref()
+      .and_then(|o| o.lock().ok().map(|o| o.clone()));
+    if let Ok(mut slot) = self.checkpoint.lock() {
+      *slot = Some(RunCheckpoint {
+        step,
+        messages: messages.to_vec(),
+        overlay,
+      });
+    }
+  }
+
+  /// Routes file tools through an in-memory overlay; nothing is written to
+  /// the workspace until the resulting change set is accepted.
+  pub fn enable_dry_run(&mut self) {
+    if self.ctx.overlay.is_none() {
+      self.ctx.overlay = Some(Arc::new(Mutex::new(WorkspaceOverlay::new(self.ctx.workspace_root.clone()))));
+    }
+  }
+
+  /// File changes recorded by a dry run, if any.
+  pub fn overlay_change_set(&self) -> Option<ChangeSet> {
+    self.ctx.overlay.as_ref()?.lock().ok()?.change_set()
   }
 
-  pub async fn run_react<F, Fut, OnToolEvent>(
+  /// Records the system prompt, model outputs and tool calls of `run_react`.
+  pub fn set_trace(&mut self, trace: RunTraceWriter) {
+    self.trace = Some(trace);
+  }
+
+  fn record(&self, record: TraceRecord) {
+    if let Some(trace) = self.trace.as_ref() {
+      trace.record(record);
+    }
+  }
+
+  /// Sets the step limit and an optional marker that ends the run early once a
+  /// model reply contains it (after that turn's tool calls have run).
+  pub fn set_run_limits(&mut self, max_steps: u32, stop_marker: String) {
+    self.max_steps = max_steps.max(1);
+    self.stop_marker = Some(stop_marker.trim().to_string()).filter(|m| !m.is_empty());
+  }
+
+  /// Caps the estimated prompt size; older observations are compacted to fit.
+  pub fn set_context_budget(&mut self, tokens: usize) {
+    self.context_budget = Some(tokens);
+  }
+
+  /// Applies `AppSettings::tool_timeout_secs` to the registered async tools.
+  pub fn set_tool_timeouts(&mut self, timeouts: &BTreeMap<String, u32>) {
+    for (name, secs) in timeouts {
+      self.tools.set_timeout(name, Duration::from_secs(u64::from((*secs).max(1))));
+    }
+    self.tool_timeouts = timeouts.clone();
+  }
+
+  /// Replaces the permission policy; tools not listed are allowed.
+  pub fn set_tool_permissions(&mut self, permissions: BTreeMap<String, ToolPermission>) {
+    self.permissions = permissions;
+  }
+
+  fn permission_for(&self, tool: &str) -> ToolPermission {
+    self.permissions.get(tool).copied().unwrap_or(ToolPermission::Allow)
+  }
+
+  pub fn tool_specs(&self) -> Vec<ToolSpec> {
+    self.tools.specs()
+  }
+
+  /// Drives the model/tool loop. With `native_tools` the tool declarations are
+  /// sent through the provider's function-calling API and results come back as
+  /// `role = "tool"` messages; otherwise the ACTION/INPUT text protocol is used.
+  /// Tools whose permission is `ask` wait for `approve_tool` before running.
+  /// A `delegate` call runs a child loop with the same callbacks; workspace
+  /// prompt tools are answered through `call_model`.
+  #[allow(clippy::too_many_arguments)]
+  pub async fn run_react<F, Fut, OnToolEvent, Approve, ApproveFut>(
     &mut self,
     base_messages: Vec<ChatMessage>,
     agent_system_prompt: String,
     edit_apply_mode: AiEditApplyMode,
+    native_tools: bool,
     call_model: F,
     mut on_tool_event: OnToolEvent,
+    approve_tool: Approve,
   ) -> Result<(String, AgentPerf), String>
   where
-    F: Fn(Vec<ChatMessage>) -> Fut,
-    Fut: Future<Output = Result<String, String>>,
-    OnToolEvent: FnMut(AgentToolEvent),
+    F: Fn(ModelRequest) -> Fut + Sync,
+    Fut: Future<Output = Result<ModelTurn, String>> + Send + 'static,
+    OnToolEvent: FnMut(AgentToolEvent) + Send,
+    Approve: Fn(ToolApprovalRequest) -> ApproveFut + Sync,
+    ApproveFut: Future<Output = bool> + Send + 'static,
   {
-    let mut perf = AgentPerf::default();
-    let tool_list = self.tools();
-    let memory_text = self.memory.render(50);
-    let prompts = prompt_config::agent_prompts();
-    let mode_instruction = match edit_apply_mode {
-      AiEditApplyMode::AutoApply => prompts.mode_auto_apply.trim(),
-      AiEditApplyMode::Review => prompts.mode_review.trim(),
+    let call_model = |request| -> BoxFuture<Result<ModelTurn, String>> { Box::pin(call_model(request)) };
+    let approve_tool = |request| -> BoxFuture<bool> { Box::pin(approve_tool(request)) };
+    let hooks = RunHooks {
+      call_model: &call_model,
+      approve_tool: &approve_tool,
     };
-    let mut messages: Vec<ChatMessage> = Vec::new();
-    let tool_list_text = tool_list.join(", ");
-    let react_prompt = prompts
-      .runtime_prompt_template
-      .replace("{{SYS}}", agent_system_prompt.trim())
-      .replace("{{TOOLS}}", tool_list_text.as_str());
-    let react_prompt = format!("{react_prompt}\n\n{mode_instruction}");
-    messages.push(ChatMessage {
-      role: "system".to_string(),
-      content: if memory_text.is_empty() {
-        react_prompt
+    self
+      .run_loop(
+        base_messages,
+        agent_system_prompt,
+        edit_apply_mode,
+        native_tools,
+        &hooks,
+        &mut on_tool_event,
+      )
+      .await
+  }
+
+  /// Boxed so delegated child runs can recurse into it.
+  fn run_loop<'a>(
+    &'a mut self,
+    base_messages: Vec<ChatMessage>,
+    agent_system_prompt: String,
+    edit_apply_mode: AiEditApplyMode,
+    native_tools: bool,
+    hooks: &'a RunHooks<'_>,
+    on_tool_event: &'a mut (dyn FnMut(AgentToolEvent) + Send),
+  ) -> BoxFutureRef<'a, Result<(String, AgentPerf), String>> {
+    Box::pin(async move {
+      if edit_apply_mode == AiEditApplyMode::Review {
+        self.enable_dry_run();
+      }
+      let mut perf = AgentPerf::default();
+      let tool_specs = if native_tools { self.tool_specs() } else { Vec::new() };
+      let tool_spec_tokens = serde_json::to_string(&tool_specs)
+        .map(|raw| context_budget::estimate_tokens(&raw))
+        .unwrap_or(0);
+      let request_text = base_messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.as_str())
+        .unwrap_or("");
+      let memory_text = self
+        .ctx
+        .memory
+        .lock()
+        .map(|memory| agent_memory::render_items(&memory.select_for_prompt(request_text, PROMPT_MEMORY_ITEMS)))
+        .unwrap_or_default();
+      let prompts = prompt_config::agent_prompts();
+      let mode_instruction = match edit_apply_mode {
+        AiEditApplyMode::AutoApply => prompts.mode_auto_apply.trim(),
+        AiEditApplyMode::Review => prompts.mode_review.trim(),
+      };
+      let tool_protocol = if native_tools {
+        prompts.tool_protocol_native.trim()
       } else {
-        format!("{react_prompt}\n\nLong-term memory:\n{memory_text}")
-      },
-    });
-    messages.extend(base_messages);
-    let mut step = 0u32;
-    let max_steps = 10u32;
-    loop {
-      if step >= max_steps {
-        let last = messages
-          .iter()
-          .rev()
-          .find(|m| m.role == "assistant")
-          .map(|m| m.content.clone())
-          .unwrap_or_default();
-        if parse_tool_call(&last).is_some() {
-          return Ok((
-            "Model repeatedly called tools but did not produce a final answer. Ask it to summarize changes in one sentence, or inspect the file tree to verify results.".to_string(),
-            perf,
-          ));
-        }
-        return Ok((last, perf));
+        prompts.tool_protocol_text.trim()
+      };
+      let mut messages: Vec<ChatMessage> = Vec::new();
+      let tool_list_text = self.tools.render_prompt_list();
+      let react_prompt = prompts
+        .runtime_prompt_template
+        .replace("{{SYS}}", agent_system_prompt.trim())
+        .replace("{{TOOLS}}", tool_list_text.as_str())
+        .replace("{{TOOL_PROTOCOL}}", tool_protocol);
+      let react_prompt = format!("{react_prompt}\n\n{mode_instruction}");
+      messages.push(ChatMessage::new(
+        "system",
+        if memory_text.is_empty() {
+          react_prompt
+        } else {
+          format!("{react_prompt}\n\nLong-term memory:\n{memory_text}")
+        },
+      ));
+      self.record(TraceRecord::SystemPrompt {
+        text: messages[0].content.clone(),
+      });
+      messages.extend(base_messages);
+      let mut step = 0u32;
+      if let Some(resume) = self.resume.take().filter(|_| self.depth == 0) {
+        messages = resume.messages;
+        step = resume.step;
       }
-      step += 1;
-      perf.steps = step;
-      let t0 = Instant::now();
-      let out = call_model(messages.clone()).await?;
-      perf.model_ms += t0.elapsed().as_millis();
-      if let Some(call) = parse_tool_call(&out) {
-        let tool_name = call.tool.clone();
-        let tool_args = call.args.clone();
-        let action_id = format!("step-{}", step);
-        on_tool_event(AgentToolEvent {
-          action_id: action_id.clone(),
+      loop {
+        self.save_checkpoint(step, &messages);
+        if self.ctx.cancel.is_cancelled() {
+          return Err(RUN_CANCELLED.to_string());
+        }
+        if step >= self.max_steps {
+          perf.stop_reason = "max_steps".to_string();
+          let last = messages
+            .iter()
+            .rev()
+            .find(|m| m.role == "assistant")
+            .cloned()
+            .unwrap_or_default();
+          if !last.tool_calls.is_empty() || parse_tool_call(&last.content).is_some() {
+            return Ok((
+              "Model repeatedly called tools but did not produce a final answer. Ask it to summarize changes in one sentence, or inspect the file tree to verify results.".to_string(),
+              perf,
+            ));
+          }
+          return Ok((last.content, perf));
+        }
+        step += 1;
+        perf.steps = step;
+        if let Some(budget) = self.context_budget {
+          let stats = context_budget::compact_messages(&mut messages, budget.saturating_sub(tool_spec_tokens));
+          perf.context_budget = budget;
+          perf.compacted_messages += stats.compacted_messages;
+          perf.compacted_tokens += stats.tokens_saved();
+        }
+        perf.peak_prompt_tokens = perf
+          .peak_prompt_tokens
+          .max(context_budget::estimate_messages_tokens(&messages) + tool_spec_tokens);
+        let t0 = Instant::now();
+        let turn = self
+          .ctx
+          .cancel
+          .run_until_cancelled((hooks.call_model)(ModelRequest {
+            messages: messages.clone(),
+            tools: tool_specs.clone(),
+            temperature: self.temperature,
+            generation: self.generation.clone(),
+            quiet: false,
+            agent_id: self.agent_id.clone(),
+          }))
+          .await
+          .ok_or_else(|| RUN_CANCELLED.to_string())??;
+        let model_ms = t0.elapsed().as_millis();
+        perf.model_ms += model_ms;
+        perf.input_tokens += turn.usage.input_tokens;
+        perf.output_tokens += turn.usage.output_tokens;
+        perf.step_providers.push(turn.provider_id.clone());
+        let step_provider = Some(turn.provider_id.clone()).filter(|id| !id.is_empty());
+        self.record(TraceRecord::ModelOutput {
           step,
-          tool: tool_name.clone(),
-          args: tool_args.clone(),
-          phase: "start".to_string(),
-          ok: None,
-          observation: None,
-          duration_ms: None,
+          text: turn.text.clone(),
+          tool_calls: turn.tool_calls.clone(),
+          duration_ms: model_ms as u64,
         });
-        let t1 = Instant::now();
-        let result = if call.tool == "memory_upsert" {
-          let key = call
-            .args
-            .get("key")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| "missing args.key".to_string())
-            .and_then(|s| if s.trim().is_empty() { Err("empty args.key".to_string()) } else { Ok(s) });
-          let value = call
-            .args
-            .get("value")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| "missing args.value".to_string());
-          match (key, value) {
-            (Ok(k), Ok(v)) => {
-              self.memory.upsert(k, v);
-              let _ = self.memory.save();
-              Ok(serde_json::json!({ "ok": true }))
-            }
-            (Err(e), _) | (_, Err(e)) => Err(e),
-          }
-        } else if call.tool == "memory_search" {
-          let query = call
-            .args
-            .get("query")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| "missing args.query".to_string())?;
-          let limit = call.args.get("limit").and_then(|v| v.as_u64()).unwrap_or(10) as usize;
-          let hits = self.memory.search(query, limit);
-          Ok(serde_json::to_value(hits).unwrap_or_else(|_| serde_json::json!([])))
+        let calls = if native_tools {
+          turn.tool_calls.clone()
         } else {
-          self.tools.call(&self.ctx, &call.tool, call.args.clone())
+          parse_tool_calls(&turn.text)
+            .into_iter()
+            .enumerate()
+            .map(|(idx, call)| ToolCall {
+              id: format!("step-{step}-{}", idx + 1),
+              name: call.tool,
+              arguments: call.args,
+            })
+            .collect::<Vec<_>>()
         };
-        let duration_ms = t1.elapsed().as_millis();
-        perf.tool_ms += duration_ms;
-        let (obs, ok) = match result {
-          Ok(v) => (v, true),
-          Err(e) => (serde_json::json!({ "error": e }), false),
+        let hit_stop_marker = self
+          .stop_marker
+          .as_deref()
+          .map(|marker| turn.text.contains(marker))
+          .unwrap_or(false);
+        if calls.is_empty() {
+          perf.stop_reason = if hit_stop_marker { "stop_marker" } else { "final_answer" }.to_string();
+          return Ok((turn.text, perf));
+        }
+        let stop_reply = match self.stop_marker.as_deref() {
+          Some(marker) if hit_stop_marker => reply_before_tool_calls(&turn.text, marker),
+          _ => String::new(),
         };
-        on_tool_event(AgentToolEvent {
-          action_id,
-          step,
-          tool: tool_name,
-          args: tool_args,
-          phase: "finish".to_string(),
-          ok: Some(ok),
-          observation: Some(obs.clone()),
-          duration_ms: Some(duration_ms),
-        });
-        let obs_text = serde_json::to_string_pretty(&obs).unwrap_or_else(|_| obs.to_string());
+
         messages.push(ChatMessage {
           role: "assistant".to_string(),
-          content: out,
-        });
-        messages.push(ChatMessage {
-          role: "user".to_string(),
-          content: format!("OBSERVATION:\n{obs_text}"),
+          content: turn.text,
+          tool_calls: if native_tools { calls.clone() } else { Vec::new() },
+          ..Default::default()
         });
+        let action_ids = calls
+          .iter()
+          .enumerate()
+          .map(|(idx, _)| {
+            let id = if calls.len() == 1 {
+              format!("step-{step}")
+            } else {
+              format!("step-{step}-{}", idx + 1)
+            };
+            match self.parent_action_id.as_deref() {
+              Some(parent) => format!("{parent}/{id}"),
+              None => id,
+            }
+          })
+          .collect::<Vec<_>>();
+        let mut observations: Vec<Value> = Vec::with_capacity(calls.len());
+        for batch in plan_tool_batches(&calls, |name| self.tools.is_read_only(name)) {
+          let mut blocked: HashMap<usize, Value> = HashMap::new();
+          for idx in batch.clone() {
+            let call = &calls[idx];
+            on_tool_event(AgentToolEvent {
+              action_id: action_ids[idx].clone(),
+              step,
+              tool: call.name.clone(),
+              args: call.arguments.clone(),
+              phase: "start".to_string(),
+              ok: None,
+              observation: None,
+              duration_ms: None,
+              parent_action_id: self.parent_action_id.clone(),
+              provider_id: step_provider.clone(),
+            });
+            match self.permission_for(&call.name) {
+              ToolPermission::Allow => {}
+              ToolPermission::Deny => {
+                blocked.insert(
+                  idx,
+                  serde_json::json!({ "error": format!("{} is disabled by the tool permission policy", call.name) }),
+                );
+              }
+              ToolPermission::Ask => {
+                if let Err(e) = self.tools.validate(&call.name, &call.arguments) {
+                  blocked.insert(idx, e.to_observation(&call.name));
+                  continue;
+                }
+                let approved = self
+                  .ctx
+                  .cancel
+                  .run_until_cancelled((hooks.approve_tool)(ToolApprovalRequest {
+                    action_id: action_ids[idx].clone(),
+                    step,
+                    tool: call.name.clone(),
+                    args: call.arguments.clone(),
+                  }))
+                  .await
+                  .unwrap_or(false);
+                if !approved {
+                  blocked.insert(
+                    idx,
+                    serde_json::json!({
+                      "error": format!("the user denied {}", call.name),
+                      "denied": true
+                    }),
+                  );
+                }
+              }
+            }
+          }
+          let runnable = batch.clone().filter(|idx| !blocked.contains_key(idx)).collect::<Vec<_>>();
+          let runnable_calls = runnable.iter().map(|&idx| calls[idx].clone()).collect::<Vec<_>>();
+          let t1 = Instant::now();
+          let batch_results = match runnable_calls.as_slice() {
+            [call] if call.name == DELEGATE_TOOL => {
+              let result = self
+                .delegate(
+                  call,
+                  &action_ids[runnable[0]],
+                  edit_apply_mode.clone(),
+                  native_tools,
+                  hooks,
+                  &mut *on_tool_event,
+                )
+                .await;
+              vec![(result, t1.elapsed().as_millis())]
+            }
+            [call] if self.prompt_tools.contains_key(&call.name) => {
+              let result = self.run_prompt_tool(call, hooks).await;
+              vec![(result, t1.elapsed().as_millis())]
+            }
+            _ => self.run_tool_batch(&runnable_calls).await,
+          };
+          let mut results = runnable.into_iter().zip(batch_results).collect::<HashMap<_, _>>();
+          perf.tool_ms += t1.elapsed().as_millis();
+          for idx in batch {
+            let (obs, ok, duration_ms) = match (blocked.remove(&idx), results.remove(&idx)) {
+              (Some(obs), _) => (obs, false, 0),
+              (None, Some((Ok(v), duration_ms))) => (v, true, duration_ms),
+              (None, Some((Err(e), duration_ms))) => (e.to_observation(&calls[idx].name), false, duration_ms),
+              (None, None) => (serde_json::json!({ "error": "tool did not run" }), false, 0),
+            };
+            perf.tool_calls += 1;
+            self.record(TraceRecord::ToolCall {
+              step,
+              action_id: action_ids[idx].clone(),
+              tool: calls[idx].name.clone(),
+              args: calls[idx].arguments.clone(),
+              ok,
+              observation: obs.clone(),
+              duration_ms: duration_ms as u64,
+            });
+            on_tool_event(AgentToolEvent {
+              action_id: action_ids[idx].clone(),
+              step,
+              tool: calls[idx].name.clone(),
+              args: calls[idx].arguments.clone(),
+              phase: "finish".to_string(),
+              ok: Some(ok),
+              observation: Some(obs.clone()),
+              duration_ms: Some(duration_ms),
+              parent_action_id: self.parent_action_id.clone(),
+              provider_id: step_provider.clone(),
+            });
+            observations.push(obs);
+          }
+        }
+
+        if native_tools {
+          for (call, obs) in calls.iter().zip(observations.iter()) {
+            messages.push(ChatMessage {
+              role: "tool".to_string(),
+              content: serde_json::to_string(obs).unwrap_or_else(|_| obs.to_string()),
+              tool_call_id: Some(call.id.clone()),
+              name: Some(call.name.clone()),
+              ..Default::default()
+            });
+          }
+        } else if calls.len() == 1 {
+          let obs_text = serde_json::to_string_pretty(&observations[0]).unwrap_or_else(|_| observations[0].to_string());
+          messages.push(ChatMessage::new("user", format!("OBSERVATION:\n{obs_text}")));
+        } else {
+          let mut text = String::new();
+          for (idx, (call, obs)) in calls.iter().zip(observations.iter()).enumerate() {
+            let obs_text = serde_json::to_string_pretty(obs).unwrap_or_else(|_| obs.to_string());
+            if !text.is_empty() {
+              text.push_str("\n\n");
+            }
+            text.push_str(&format!("OBSERVATION [{}] {}:\n{obs_text}", idx + 1, call.name));
+          }
+          messages.push(ChatMessage::new("user", text));
+        }
+        if hit_stop_marker {
+          perf.stop_reason = "stop_marker".to_string();
+          return Ok((stop_reply, perf));
+        }
+      }
+    })
+  }
+
+  /// Re-checks every recorded tool call against the current registry without
+  /// touching the workspace: arguments are validated, and read-only tools and
+  /// file writes run in trace order against a dry-run overlay and are compared
+  /// with the recorded observation. Memory writes and other mutating tools are
+  /// skipped.
+  pub async fn replay_trace(&mut self, lines: &[TraceLine]) -> RunReplay {
+    self.enable_dry_run();
+    let mut steps = Vec::new();
+    for line in lines {
+      let TraceRecord::ToolCall {
+        step,
+        action_id,
+        tool,
+        args,
+        ok,
+        observation,
+        ..
+      } = &line.record
+      else {
         continue;
+      };
+      let mut replay = ReplayStep {
+        step: *step,
+        action_id: action_id.clone(),
+        tool: tool.clone(),
+        recorded_ok: *ok,
+        valid: true,
+        issues: Value::Null,
+        executed: false,
+        observation: None,
+        matches_recorded: None,
+      };
+      if let Err(e) = self.tools.validate(tool, args) {
+        replay.valid = false;
+        replay.issues = e.to_observation(tool);
+      } else if self.tools.is_read_only(tool) || FILE_WRITE_TOOLS.contains(&tool.as_str()) {
+        let result = self.tools.call_async(&self.ctx, tool, args.clone()).await;
+        let obs = match result {
+          Ok(v) => v,
+          Err(e) => e.to_observation(tool),
+        };
+        replay.executed = true;
+        replay.matches_recorded = Some(obs == *observation);
+        replay.observation = Some(obs);
       }
-      return Ok((out, perf));
+      steps.push(replay);
+    }
+    RunReplay {
+      steps,
+      change_set: self.overlay_change_set(),
+    }
+  }
+
+  /// Runs a batch from `plan_tool_batches`. Multi-call batches only contain
+  /// read-only tools and are awaited together, sync ones on the blocking pool.
+  async fn run_tool_batch(&self, calls: &[ToolCall]) -> Vec<(Result<Value, ToolError>, u128)> {
+    let parallel = calls.len() > 1;
+    let runs = calls.iter().map(|call| async move {
+      let t = Instant::now();
+      let args = call.arguments.clone();
+      let result = if parallel {
+        self.tools.call_blocking(&self.ctx, &call.name, args).await
+      } else {
+        self.tools.call_async(&self.ctx, &call.name, args).await
+      };
+      (result, t.elapsed().as_millis())
+    });
+    futures_util::future::join_all(runs).await
+  }
+}
+
+/// Splits one step's calls into ordered batches: consecutive read-only calls
+/// share a batch, every mutating call gets a batch of its own.
+fn plan_tool_batches(calls: &[ToolCall], is_read_only: impl Fn(&str) -> bool) -> Vec<std::ops::Range<usize>> {
+  let mut batches: Vec<std::ops::Range<usize>> = Vec::new();
+  for (idx, call) in calls.iter().enumerate() {
+    let read_only = is_read_only(&call.name);
+    match batches.last_mut() {
+      Some(last) if read_only && last.clone().all(|i| is_read_only(&calls[i].name)) => last.end = idx + 1,
+      _ => batches.push(idx..idx + 1),
     }
   }
+  batches
+}
+
+/// Model input for one runtime step. An empty `tools` list means the model
+/// should be called without native tool declarations (text protocol).
+pub struct ModelRequest {
+  pub messages: Vec<ChatMessage>,
+  pub tools: Vec<ToolSpec>,
+  /// Set for delegated runs and prompt tools that bring their own temperature.
+  pub temperature: Option<f32>,
+  /// Set for delegated runs; merged over the provider's sampling defaults.
+  pub generation: Option<GenerationParams>,
+  /// Internal completion (prompt tools) that must not reach the chat stream.
+  pub quiet: bool,
+  /// Agent whose run made the request, for usage accounting.
+  pub agent_id: String,
 }
 
 #[derive(Clone)]
@@ -507,20 +1703,130 @@ fn extract_json_value(input: &str) -> Option<String> {
   None
 }
 
+/// Renders lines `start..start + max_lines` as `N| text` for `fs_read_lines`.
+fn numbered_page(raw: &str, start: usize, max_lines: usize) -> Value {
+  let total_lines = raw.lines().count();
+  let width = total_lines.max(1).to_string().len();
+  let mut text = String::new();
+  let mut end = start.saturating_sub(1);
+  for (idx, line) in raw.lines().enumerate().skip(start - 1).take(max_lines) {
+    end = idx + 1;
+    text.push_str(&format!("{:>width$}| {line}\n", idx + 1));
+  }
+  serde_json::json!({
+    "start_line": start,
+    "end_line": end,
+    "total_lines": total_lines,
+    "total_chars": raw.chars().count(),
+    "has_more": end < total_lines,
+    "lines": text
+  })
+}
+
+/// Applies one `fs_patch_text` edit. Line operations keep the file's line
+/// ending style and trailing newline.
+fn apply_text_patch(current: &str, args: &Value) -> Result<String, String> {
+  let text = args.get("text").and_then(|v| v.as_str());
+  let op = str_arg(args, "op");
+  if op == "anchor" {
+    let anchor = str_arg(args, "anchor");
+    let text = text.ok_or("text is required for op=anchor")?;
+    if anchor.is_empty() {
+      return Err("anchor is required for op=anchor".to_string());
+    }
+    return match current.matches(anchor).count() {
+      0 => Err("anchor not found".to_string()),
+      1 => Ok(current.replacen(anchor, text, 1)),
+      n => Err(format!("anchor occurs {n} times; use a longer anchor or a line range")),
+    };
+  }
+
+  let eol = if current.contains("\r\n") { "\r\n" } else { "\n" };
+  let mut lines = current.lines().map(|l| l.to_string()).collect::<Vec<_>>();
+  let total = lines.len();
+  let start = args
+    .get("start_line")
+    .and_then(|v| v.as_u64())
+    .ok_or_else(|| format!("start_line is required for op={op}"))? as usize;
+  let new_lines = text.map(|t| t.lines().map(|l| l.to_string()).collect::<Vec<_>>());
+  match op {
+    "insert" => {
+      if start > total + 1 {
+        return Err(format!("start_line {start} is past the end of the file ({total} lines)"));
+      }
+      let new_lines = new_lines.ok_or("text is required for op=insert")?;
+      lines.splice(start - 1..start - 1, new_lines);
+    }
+    "replace" | "delete" => {
+      let end = args.get("end_line").and_then(|v| v.as_u64()).map(|v| v as usize).unwrap_or(start);
+      if end < start || end > total {
+        return Err(format!("line range {start}-{end} is outside the file ({total} lines)"));
+      }
+      let replacement = if op == "delete" {
+        Vec::new()
+      } else {
+        new_lines.ok_or("text is required for op=replace")?
+      };
+      lines.splice(start - 1..end, replacement);
+    }
+    other => return Err(format!("unsupported op: {other}")),
+  }
+  let mut out = lines.join(eol);
+  if current.ends_with('\n') && !out.is_empty() {
+    out.push_str(eol);
+  }
+  Ok(out)
+}
+
+/// Final answer for a turn that both called tools and hit the stop marker:
+/// the prose before the first ACTION line, keeping the marker line itself.
+fn reply_before_tool_calls(text: &str, marker: &str) -> String {
+  let head = text
+    .lines()
+    .take_while(|line| !line.trim_start().to_ascii_uppercase().starts_with("ACTION:"))
+    .collect::<Vec<_>>()
+    .join("\n");
+  let mut out = head.trim().to_string();
+  if !out.contains(marker) {
+    for line in text.lines().filter(|line| line.contains(marker)) {
+      if !out.is_empty() {
+        out.push('\n');
+      }
+      out.push_str(line.trim());
+    }
+  }
+  out
+}
+
 pub fn parse_tool_call(text: &str) -> Option<ParsedToolCall> {
+  parse_tool_calls(text).into_iter().next()
+}
+
+/// Parses every ACTION/INPUT block in a text-protocol reply, in order.
+pub fn parse_tool_calls(text: &str) -> Vec<ParsedToolCall> {
+  let mut out: Vec<ParsedToolCall> = Vec::new();
   let mut tool: Option<String> = None;
   let mut input_joined: Option<String> = None;
   let mut collecting_input = false;
+  let mut flush = |tool: &mut Option<String>, input: &mut Option<String>| {
+    if let (Some(name), Some(raw)) = (tool.take(), input.take()) {
+      let json_text = extract_json_value(&raw).unwrap_or_else(|| raw.trim().to_string());
+      let args: Value =
+        serde_json::from_str(&json_text).unwrap_or_else(|_| serde_json::json!({ "raw": raw.trim() }));
+      out.push(ParsedToolCall { tool: name, args });
+    }
+  };
   for line in text.lines() {
     let t = line.trim_end();
     if t.trim_start().to_ascii_uppercase().starts_with("ACTION:") {
-      tool = Some(t.splitn(2, ':').nth(1)?.trim().to_string());
+      flush(&mut tool, &mut input_joined);
+      collecting_input = false;
+      tool = t.split_once(':').map(|(_, rest)| rest.trim().to_string());
       continue;
     }
     if t.trim_start().to_ascii_uppercase().starts_with("INPUT:") {
       collecting_input = true;
-      let rest = t.splitn(2, ':').nth(1)?.trim().to_string();
-      input_joined = Some(rest);
+      input_joined = t.split_once(':').map(|(_, rest)| rest.trim().to_string());
       continue;
     }
     if collecting_input {
@@ -534,13 +1840,8 @@ pub fn parse_tool_call(text: &str) -> Option<ParsedToolCall> {
       });
     }
   }
-  let tool = tool?;
-  let input = input_joined?;
-  let json_text = extract_json_value(&input).unwrap_or_else(|| input.trim().to_string());
-  let args: Value = serde_json::from_str(&json_text)
-    .ok()
-    .or_else(|| Some(serde_json::json!({ "raw": input.trim() })))?;
-  Some(ParsedToolCall { tool, args })
+  flush(&mut tool, &mut input_joined);
+  out
 }
 
 #[allow(dead_code)]
@@ -591,3 +1892,151 @@ impl McpBroker {
     self.items.values().take(limit).cloned().collect()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn patch_replaces_inserts_and_deletes_lines() {
+    let text = "a\r\nb\r\nc\r\n";
+    let out = apply_text_patch(text, &json!({ "op": "replace", "start_line": 2, "text": "B1\nB2" })).unwrap();
+    assert_eq!(out, "a\r\nB1\r\nB2\r\nc\r\n");
+    let out = apply_text_patch(text, &json!({ "op": "insert", "start_line": 4, "text": "d" })).unwrap();
+    assert_eq!(out, "a\r\nb\r\nc\r\nd\r\n");
+    let out = apply_text_patch(text, &json!({ "op": "delete", "start_line": 1, "end_line": 2 })).unwrap();
+    assert_eq!(out, "c\r\n");
+    assert!(apply_text_patch(text, &json!({ "op": "delete", "start_line": 3, "end_line": 5 })).is_err());
+  }
+
+  #[test]
+  fn numbered_page_reports_range_and_totals() {
+    let raw = (1..=12).map(|n| format!("line {n}")).collect::<Vec<_>>().join("\n");
+    let page = numbered_page(&raw, 9, 2);
+    assert_eq!(page["lines"], " 9| line 9\n10| line 10\n");
+    assert_eq!(page["end_line"], 10);
+    assert_eq!(page["total_lines"], 12);
+    assert_eq!(page["has_more"], true);
+    assert_eq!(numbered_page(&raw, 20, 5)["lines"], "");
+  }
+
+  #[test]
+  fn patch_anchor_must_be_unique() {
+    let text = "他走进门。\n她走进门。\n";
+    assert!(apply_text_patch(text, &json!({ "op": "anchor", "anchor": "走进门", "text": "x" })).is_err());
+    let out = apply_text_patch(text, &json!({ "op": "anchor", "anchor": "她走进门", "text": "她推门而入" })).unwrap();
+    assert_eq!(out, "他走进门。\n她推门而入。\n");
+  }
+
+  #[test]
+  fn patch_anchor_is_required() {
+    for current in ["他走进门。\n", ""] {
+      let err = apply_text_patch(current, &json!({ "op": "anchor", "text": "x" })).unwrap_err();
+      assert_eq!(err, "anchor is required for op=anchor");
+      let err = apply_text_patch(current, &json!({ "op": "anchor", "anchor": "", "text": "x" })).unwrap_err();
+      assert_eq!(err, "anchor is required for op=anchor");
+    }
+  }
+
+  #[test]
+  fn delegated_runs_keep_tool_timeouts_and_trace() {
+    let root = std::env::temp_dir().join(format!("novel-delegate-{}", uuid::Uuid::new_v4()));
+    let mut parent = AgentRuntime::new(root.clone());
+    parent.set_tool_timeouts(&BTreeMap::from([("fs_search_text".to_string(), 7)]));
+    parent.set_trace(RunTraceWriter::create(&root, "run-1").unwrap());
+    let editor = agents::Agent {
+      id: "editor".to_string(),
+      ..Default::default()
+    };
+    let child = parent.child_runtime(&editor, "step-2");
+    assert_eq!(child.tools.tools["fs_search_text"].timeout, Some(Duration::from_secs(7)));
+
+    child.record(TraceRecord::SystemPrompt {
+      text: "review".to_string(),
+    });
+    let lines = crate::run_trace::load_trace(&root, "run-1").unwrap();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].parent_action_id.as_deref(), Some("step-2"));
+    let _ = std::fs::remove_dir_all(&root);
+  }
+
+  #[test]
+  fn dry_run_searches_see_the_overlay() {
+    let root = std::env::temp_dir().join(format!("novel-dry-search-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(root.join("stories")).unwrap();
+    std::fs::write(root.join("stories/01.md"), "雨夜，港口无人。\n").unwrap();
+    let mut runtime = AgentRuntime::new(root.clone());
+    runtime.enable_dry_run();
+    runtime.ctx.write_text("stories/02.md", "林默推开仓库的门。\n", true, "test").unwrap();
+    runtime.ctx.delete_entry("stories/01.md").unwrap();
+
+    let search = |tool: &str, query: &str| {
+      tauri::async_runtime::block_on(runtime.tools.call_async(&runtime.ctx, tool, json!({ "query": query }))).unwrap()
+    };
+    assert_eq!(search("fs_search_text", "林默")["hits"][0]["path"], "stories/02.md");
+    assert_eq!(search("fs_search_text", "港口")["hits"], json!([]));
+    assert_eq!(search("story_search", "林默")["hits"][0]["path"], "stories/02.md");
+    assert_eq!(search("story_search", "港口")["hits"], json!([]));
+    assert!(!root.join("stories/02.md").exists());
+    let _ = std::fs::remove_dir_all(&root);
+  }
+
+  #[test]
+  fn replay_runs_recorded_writes_in_a_dry_run() {
+    let root = std::env::temp_dir().join(format!("novel-replay-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(root.join("stories")).unwrap();
+    std::fs::write(root.join("stories/01.md"), "雨夜。\n").unwrap();
+    let recorded = [
+      ("fs_read_text", json!({ "path": "stories/01.md" }), json!({ "text": "雨夜。\n" })),
+      (
+        "fs_patch_text",
+        json!({ "path": "stories/01.md", "op": "replace", "start_line": 1, "text": "晴天。" }),
+        json!({ "ok": true, "changed": true, "total_lines": 1 }),
+      ),
+      ("fs_read_text", json!({ "path": "stories/01.md" }), json!({ "text": "晴天。\n" })),
+      ("fs_rename_entry", json!({ "from": "stories/01.md", "to": "stories/02.md" }), json!({ "ok": true })),
+      ("memory_upsert", json!({ "key": "weather", "value": "sunny" }), json!({ "ok": true })),
+    ];
+    let lines = recorded
+      .into_iter()
+      .enumerate()
+      .map(|(idx, (tool, args, observation))| TraceLine {
+        ts: 0,
+        parent_action_id: None,
+        record: TraceRecord::ToolCall {
+          step: idx as u32 + 1,
+          action_id: format!("step-{}", idx + 1),
+          tool: tool.to_string(),
+          args,
+          ok: true,
+          observation,
+          duration_ms: 0,
+        },
+      })
+      .collect::<Vec<_>>();
+
+    let mut runtime = AgentRuntime::new(root.clone());
+    let replay = tauri::async_runtime::block_on(runtime.replay_trace(&lines));
+    let executed = replay.steps.iter().map(|s| s.executed).collect::<Vec<_>>();
+    assert_eq!(executed, vec![true, true, true, true, false]);
+    assert!(replay.steps[..4].iter().all(|s| s.matches_recorded == Some(true)));
+    let change_set = replay.change_set.unwrap();
+    let mut paths = change_set.files.iter().map(|f| f.file_path.as_str()).collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, vec!["stories/01.md", "stories/02.md"]);
+    assert_eq!(std::fs::read_to_string(root.join("stories/01.md")).unwrap(), "雨夜。\n");
+    assert!(!root.join("stories/02.md").exists());
+    let _ = std::fs::remove_dir_all(&root);
+  }
+
+  #[test]
+  fn parses_multiple_text_tool_calls() {
+    let calls = parse_tool_calls(
+      "ACTION: fs_read_text\nINPUT: {\"path\": \"a.md\"}\nACTION: fs_exists\nINPUT: {\"path\": \"b\"}",
+    );
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].tool, "fs_exists");
+    assert_eq!(calls[1].args["path"], "b");
+  }
+}
@@ -1,4 +1,5 @@
 use crate::app_data;
+use crate::generation_params::GenerationParams;
 use crate::prompt_config;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
@@ -16,6 +17,25 @@ pub struct Agent {
   pub max_tokens: u32,
   /// 分章目标字数，0表示不自动分章
   pub chapter_word_target: u32,
+  /// Model/tool round trips allowed per request.
+  pub max_steps: u32,
+  /// Time limit for one agent run, in seconds; waits on tool approvals do not count.
+  pub react_timeout_secs: u32,
+  /// Ends the run once a model reply contains this marker (e.g. `TASK_DONE:`); empty disables it.
+  pub stop_marker: String,
+  /// Overrides of the provider's sampling parameters; a non-zero
+  /// `max_tokens` above takes precedence over the one here.
+  pub generation: GenerationParams,
+}
+
+impl Agent {
+  /// Sampling parameters this agent sets over its provider's defaults.
+  pub fn generation_overrides(&self) -> GenerationParams {
+    GenerationParams {
+      max_tokens: Some(self.max_tokens).filter(|n| *n > 0).or(self.generation.max_tokens),
+      ..self.generation.clone()
+    }
+  }
 }
 
 impl Default for Agent {
@@ -28,10 +48,19 @@ impl Default for Agent {
       temperature: 0.7,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     }
   }
 }
 
+pub const DEFAULT_MAX_STEPS: u32 = 10;
+pub const DEFAULT_REACT_TIMEOUT_SECS: u32 = 240;
+/// How many levels deep `delegate` calls may nest.
+pub const MAX_DELEGATION_DEPTH: u32 = 2;
+
 pub fn load(app: &tauri::AppHandle) -> Result<Vec<Agent>, String> {
   let builtins = default_agents();
   let customs = load_custom(app)?;
@@ -163,6 +192,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.8,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 科幻 ====================
@@ -198,6 +231,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.7,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 言情 ====================
@@ -233,6 +270,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.75,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 都市 ====================
@@ -269,6 +310,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.7,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 悬疑推理 ====================
@@ -306,6 +351,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.65,
       max_tokens: 32000,
       chapter_word_target: 2500,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 历史 ====================
@@ -343,6 +392,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.7,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 武侠 ====================
@@ -380,6 +433,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.75,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 轻小说/二次元 ====================
@@ -418,6 +475,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.8,
       max_tokens: 32000,
       chapter_word_target: 2500,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 现实主义/职场 ====================
@@ -455,6 +516,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.65,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
 
     // ==================== 通用 ====================
@@ -490,6 +555,10 @@ pub fn default_agents() -> Vec<Agent> {
       temperature: 0.7,
       max_tokens: 32000,
       chapter_word_target: 3000,
+      max_steps: DEFAULT_MAX_STEPS,
+      react_timeout_secs: DEFAULT_REACT_TIMEOUT_SECS,
+      stop_marker: String::new(),
+      generation: GenerationParams::default(),
     },
   ];
   for agent in &mut agents {
@@ -71,6 +71,7 @@ fn parse_file_edits(
                 original_content,
                 modifications,
                 status: FileModificationStatus::Pending,
+                file_op: None,
             });
         }
     }
@@ -1,9 +1,69 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct ChatMessage {
   pub role: String,
   pub content: String,
+  /// Native tool calls requested by an assistant turn.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub tool_calls: Vec<ToolCall>,
+  /// Set on `role = "tool"` messages: the call this message answers.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tool_call_id: Option<String>,
+  /// Set on `role = "tool"` messages: the tool that produced the result.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+}
+
+impl ChatMessage {
+  pub fn new(role: &str, content: impl Into<String>) -> Self {
+    Self {
+      role: role.to_string(),
+      content: content.into(),
+      ..Default::default()
+    }
+  }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ToolCall {
+  pub id: String,
+  pub name: String,
+  pub arguments: Value,
+}
+
+/// Tool declaration sent to providers that support native function calling.
+#[derive(Serialize, Clone, Debug)]
+pub struct ToolSpec {
+  pub name: String,
+  pub description: String,
+  pub parameters: Value,
+}
+
+/// One model response: free text plus any native tool calls it requested.
+#[derive(Clone, Default)]
+pub struct ModelTurn {
+  pub text: String,
+  pub tool_calls: Vec<ToolCall>,
+  /// Billed tokens over all requests of the turn, continuations included.
+  pub usage: TokenUsage,
+  /// Provider that answered the turn.
+  pub provider_id: String,
+}
+
+/// Token counts as reported by the provider; zero when it reports none.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct TokenUsage {
+  pub input_tokens: u64,
+  pub output_tokens: u64,
+}
+
+impl TokenUsage {
+  pub fn add(&mut self, other: TokenUsage) {
+    self.input_tokens += other.input_tokens;
+    self.output_tokens += other.output_tokens;
+  }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -14,4 +74,3 @@ pub struct SelectionInfo {
   pub end_line: u32,
   pub selected_text: String,
 }
-
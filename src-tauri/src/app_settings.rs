@@ -1,8 +1,10 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 
 use crate::app_data;
+use crate::generation_params::GenerationParams;
 use crate::secrets;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +17,16 @@ pub struct AppSettings {
   pub active_agent_id: String,
   pub launch_mode: LaunchMode,
   pub ai_edit_apply_mode: AiEditApplyMode,
+  /// Per-tool agent permission policy; tools not listed are allowed.
+  pub tool_permissions: BTreeMap<String, ToolPermission>,
+  /// Per-tool call limits in seconds for async tools; unlisted tools keep
+  /// their built-in limit.
+  pub tool_timeout_secs: BTreeMap<String, u32>,
+  /// Tries per provider request, counting the first; 1 turns retries off.
+  pub provider_max_attempts: u32,
+  /// Providers an agent run moves on to, in order, when the current one
+  /// fails with a retryable or quota error.
+  pub provider_fallback_ids: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -40,6 +52,11 @@ impl Default for AppSettings {
         api_key: String::new(),
         base_url: "https://api.openai.com/v1".to_string(),
         model_name: "gpt-4o-mini".to_string(),
+        native_tool_calls: false,
+        context_window: 0,
+        ollama: OllamaOptions::default(),
+        prices: BTreeMap::new(),
+        generation: GenerationParams::default(),
       },
       ModelProvider {
         id: "claude".to_string(),
@@ -48,6 +65,11 @@ impl Default for AppSettings {
         api_key: String::new(),
         base_url: "https://api.anthropic.com".to_string(),
         model_name: "claude-3-5-sonnet-20241022".to_string(),
+        native_tool_calls: false,
+        context_window: 0,
+        ollama: OllamaOptions::default(),
+        prices: BTreeMap::new(),
+        generation: GenerationParams::default(),
       },
       ModelProvider {
         id: "deepseek".to_string(),
@@ -56,6 +78,24 @@ impl Default for AppSettings {
         api_key: String::new(),
         base_url: "https://api.deepseek.com".to_string(),
         model_name: "deepseek-chat".to_string(),
+        native_tool_calls: true,
+        context_window: 0,
+        ollama: OllamaOptions::default(),
+        prices: BTreeMap::new(),
+        generation: GenerationParams::default(),
+      },
+      ModelProvider {
+        id: "gemini".to_string(),
+        name: "Gemini".to_string(),
+        kind: ProviderKind::Gemini,
+        api_key: String::new(),
+        base_url: "https://generativelanguage.googleapis.com/v1beta".to_string(),
+        model_name: "gemini-2.0-flash".to_string(),
+        native_tool_calls: false,
+        context_window: 0,
+        ollama: OllamaOptions::default(),
+        prices: BTreeMap::new(),
+        generation: GenerationParams::default(),
       },
     ];
     Self {
@@ -66,10 +106,29 @@ impl Default for AppSettings {
       active_agent_id: "fantasy".to_string(),
       launch_mode: LaunchMode::default(),
       ai_edit_apply_mode: AiEditApplyMode::default(),
+      tool_permissions: default_tool_permissions(),
+      tool_timeout_secs: BTreeMap::new(),
+      provider_max_attempts: crate::provider_retry::DEFAULT_MAX_ATTEMPTS,
+      provider_fallback_ids: Vec::new(),
     }
   }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolPermission {
+  Allow,
+  Ask,
+  Deny,
+}
+
+fn default_tool_permissions() -> BTreeMap<String, ToolPermission> {
+  BTreeMap::from([
+    ("fs_delete_entry".to_string(), ToolPermission::Ask),
+    ("fs_rename_entry".to_string(), ToolPermission::Ask),
+  ])
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum AiEditApplyMode {
@@ -103,6 +162,66 @@ pub struct ModelProvider {
   pub api_key: String,
   pub base_url: String,
   pub model_name: String,
+  /// Whether an OpenAI-compatible endpoint accepts `tools`/`tool_calls`.
+  /// OpenAI, Anthropic and Gemini providers always use native tool calling.
+  #[serde(default)]
+  pub native_tool_calls: bool,
+  /// Model context window in tokens; 0 picks a default for the provider kind.
+  /// Ollama providers send it as `num_ctx`.
+  #[serde(default)]
+  pub context_window: u32,
+  #[serde(default)]
+  pub ollama: OllamaOptions,
+  /// Prices by model name, used to put a cost on logged token usage.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub prices: BTreeMap<String, ModelPrice>,
+  /// Sampling defaults for this provider; agents override single fields.
+  #[serde(default)]
+  pub generation: GenerationParams,
+}
+
+/// Price of a model per million tokens, in whatever currency the user keeps
+/// their accounts in.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ModelPrice {
+  pub input_per_mtok: f64,
+  pub output_per_mtok: f64,
+}
+
+/// Request options only the native Ollama API understands.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct OllamaOptions {
+  /// How long the server keeps the model loaded after a request, e.g. `"30m"`
+  /// or `"-1"` for ever; empty keeps the server default.
+  pub keep_alive: String,
+  /// Max tokens per response; 0 keeps the server default, -1 is unlimited.
+  pub num_predict: i32,
+}
+
+impl ModelProvider {
+  /// Cost of a request to the configured model, if it has a price.
+  pub fn usage_cost(&self, input_tokens: u64, output_tokens: u64) -> Option<f64> {
+    let price = self.prices.get(self.model_name.trim())?;
+    Some((input_tokens as f64 * price.input_per_mtok + output_tokens as f64 * price.output_per_mtok) / 1_000_000.0)
+  }
+
+  pub fn context_window_tokens(&self) -> u32 {
+    if self.context_window > 0 {
+      return self.context_window;
+    }
+    let model = self.model_name.to_lowercase();
+    match self.kind {
+      ProviderKind::Anthropic => 200_000,
+      ProviderKind::Gemini => 1_048_576,
+      // Ollama's own default is far smaller; this is sent as `num_ctx`.
+      ProviderKind::Ollama => 8_192,
+      ProviderKind::OpenAI => 128_000,
+      ProviderKind::OpenAICompatible if model.contains("deepseek") => 64_000,
+      ProviderKind::OpenAICompatible | ProviderKind::Scripted => 32_000,
+    }
+  }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -110,6 +229,12 @@ pub enum ProviderKind {
   OpenAI,
   Anthropic,
   OpenAICompatible, // For Ollama, DeepSeek, etc.
+  /// Native Gemini API (`generateContent`); `base_url` ends at the API version.
+  Gemini,
+  /// Local Ollama server through its native `/api/chat`; no API key.
+  Ollama,
+  /// Offline replay of a JSON fixture (`base_url` is the fixture path); no API key.
+  Scripted,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -231,6 +356,11 @@ pub fn load(app: &tauri::AppHandle) -> Result<AppSettings, String> {
             api_key: legacy.providers.openai.api_key.clone(),
             base_url: legacy.providers.openai.base_url.clone(),
             model_name: legacy.providers.openai.model.clone(),
+            native_tool_calls: false,
+            context_window: 0,
+            ollama: OllamaOptions::default(),
+            prices: BTreeMap::new(),
+            generation: GenerationParams::default(),
           },
           ModelProvider {
             id: "claude".to_string(),
@@ -239,6 +369,11 @@ pub fn load(app: &tauri::AppHandle) -> Result<AppSettings, String> {
             api_key: legacy.providers.claude.api_key.clone(),
             base_url: "https://api.anthropic.com".to_string(),
             model_name: legacy.providers.claude.model.clone(),
+            native_tool_calls: false,
+            context_window: 0,
+            ollama: OllamaOptions::default(),
+            prices: BTreeMap::new(),
+            generation: GenerationParams::default(),
           },
           ModelProvider {
             id: "wenxin".to_string(),
@@ -247,6 +382,11 @@ pub fn load(app: &tauri::AppHandle) -> Result<AppSettings, String> {
             api_key: legacy.providers.wenxin.api_key.clone(),
             base_url: legacy.providers.wenxin.base_url.clone(),
             model_name: legacy.providers.wenxin.model.clone(),
+            native_tool_calls: false,
+            context_window: 0,
+            ollama: OllamaOptions::default(),
+            prices: BTreeMap::new(),
+            generation: GenerationParams::default(),
           },
         ];
         if !providers.iter().any(|p| p.id == "deepseek") {
@@ -257,6 +397,11 @@ pub fn load(app: &tauri::AppHandle) -> Result<AppSettings, String> {
             api_key: String::new(),
             base_url: "https://api.deepseek.com".to_string(),
             model_name: "deepseek-chat".to_string(),
+            native_tool_calls: true,
+            context_window: 0,
+            ollama: OllamaOptions::default(),
+            prices: BTreeMap::new(),
+            generation: GenerationParams::default(),
           });
         }
 
@@ -268,6 +413,10 @@ pub fn load(app: &tauri::AppHandle) -> Result<AppSettings, String> {
           active_agent_id: legacy.active_agent_id,
           launch_mode: LaunchMode::default(),
           ai_edit_apply_mode: AiEditApplyMode::default(),
+          tool_permissions: default_tool_permissions(),
+          tool_timeout_secs: BTreeMap::new(),
+          provider_max_attempts: crate::provider_retry::DEFAULT_MAX_ATTEMPTS,
+          provider_fallback_ids: Vec::new(),
         };
         migrated = ensure_sane(migrated);
 
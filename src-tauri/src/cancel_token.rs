@@ -0,0 +1,58 @@
+//! Cooperative cancellation shared by a stream task, its agent run and the
+//! tools it calls. Cancelling only raises a flag: the run stops at the next
+//! step boundary, and awaited work (model calls, async tools, approvals) is
+//! dropped through `run_until_cancelled`.
+
+use futures_util::future::{self, Either};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+#[derive(Clone, Default)]
+pub struct CancelToken {
+  inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+  cancelled: AtomicBool,
+  notify: Notify,
+}
+
+impl CancelToken {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn cancel(&self) {
+    self.inner.cancelled.store(true, Ordering::SeqCst);
+    self.inner.notify.notify_waiters();
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.inner.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Resolves once `cancel` has been called.
+  pub async fn cancelled(&self) {
+    loop {
+      let notified = self.inner.notify.notified();
+      if self.is_cancelled() {
+        return;
+      }
+      notified.await;
+    }
+  }
+
+  /// Awaits `fut` unless the token is cancelled first, in which case `fut` is
+  /// dropped and `None` is returned.
+  pub async fn run_until_cancelled<F: Future>(&self, fut: F) -> Option<F::Output> {
+    let fut = std::pin::pin!(fut);
+    let cancelled = std::pin::pin!(self.cancelled());
+    match future::select(fut, cancelled).await {
+      Either::Left((output, _)) => Some(output),
+      Either::Right(_) => None,
+    }
+  }
+}
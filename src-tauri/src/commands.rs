@@ -1,19 +1,32 @@
 use crate::app_settings;
 use crate::agents;
 use crate::agent_system;
-use crate::ai_types::ChatMessage;
+use crate::cancel_token::CancelToken;
+use crate::ai_types::{ChatMessage, ModelTurn, TokenUsage, ToolCall, ToolSpec};
 use crate::app_data;
 use crate::chat_history;
 use crate::secrets;
 use crate::skills::{Skill, SkillManager};
-use crate::state::AppState;
+use crate::state::{AppState, PendingToolApproval};
+use crate::agent_memory;
+use crate::usage_log;
+use crate::provider_catalog::{self, CatalogModel, ProviderCatalog};
+use crate::provider_retry;
+use crate::generation_params::TranslatedParams;
+use crate::run_checkpoint;
+use crate::run_deadline::RunDeadline;
+use crate::run_trace;
+use crate::scripted_provider;
+use crate::story_index;
+use crate::text_search;
+use crate::workspace_overlay::WorkspaceOverlay;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::{Component, Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use tauri::AppHandle;
 use tauri::Emitter;
@@ -540,6 +553,18 @@ pub struct ProjectWritingSettings {
   pub auto_max_chars: u32,
   pub auto_max_rounds: u32,
   pub auto_max_chapter_advances: u32,
+  /// Project overrides for `AppSettings::tool_permissions`.
+  #[serde(default)]
+  pub tool_permissions: BTreeMap<String, app_settings::ToolPermission>,
+  /// Overrides `Agent::max_steps` for every agent in this project.
+  #[serde(default)]
+  pub agent_max_steps: Option<u32>,
+  /// Overrides `Agent::react_timeout_secs`.
+  #[serde(default)]
+  pub agent_react_timeout_secs: Option<u32>,
+  /// Overrides `Agent::stop_marker`; an empty string disables the agent's marker.
+  #[serde(default)]
+  pub agent_stop_marker: Option<String>,
 }
 
 impl Default for ProjectWritingSettings {
@@ -550,6 +575,10 @@ impl Default for ProjectWritingSettings {
       auto_max_chars: 2400,
       auto_max_rounds: 120,
       auto_max_chapter_advances: 24,
+      tool_permissions: BTreeMap::new(),
+      agent_max_steps: None,
+      agent_react_timeout_secs: None,
+      agent_stop_marker: None,
     }
   }
 }
@@ -561,6 +590,10 @@ struct PartialProjectWritingSettings {
   auto_max_chars: Option<u32>,
   auto_max_rounds: Option<u32>,
   auto_max_chapter_advances: Option<u32>,
+  tool_permissions: Option<BTreeMap<String, app_settings::ToolPermission>>,
+  agent_max_steps: Option<u32>,
+  agent_react_timeout_secs: Option<u32>,
+  agent_stop_marker: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -619,6 +652,8 @@ fn normalize_project_writing_settings(mut v: ProjectWritingSettings) -> ProjectW
   }
   v.auto_max_rounds = v.auto_max_rounds.clamp(1, 1_000);
   v.auto_max_chapter_advances = v.auto_max_chapter_advances.clamp(0, 200);
+  v.agent_max_steps = v.agent_max_steps.map(|n| n.clamp(1, 200));
+  v.agent_react_timeout_secs = v.agent_react_timeout_secs.map(|n| n.clamp(30, 3_600));
   v
 }
 
@@ -648,6 +683,12 @@ fn load_project_writing_settings_internal(root: &Path) -> Result<ProjectWritingS
     if let Some(v) = parsed.auto_max_chapter_advances {
       settings.auto_max_chapter_advances = v;
     }
+    if let Some(v) = parsed.tool_permissions {
+      settings.tool_permissions = v;
+    }
+    settings.agent_max_steps = parsed.agent_max_steps;
+    settings.agent_react_timeout_secs = parsed.agent_react_timeout_secs;
+    settings.agent_stop_marker = parsed.agent_stop_marker;
   }
   Ok(normalize_project_writing_settings(settings))
 }
@@ -1038,15 +1079,21 @@ fn history_entry_from_record(record: &HistoryEntryRecord) -> HistoryEntry {
   }
 }
 
-fn should_track_history_path(relative_path: &str) -> bool {
+/// Internal bookkeeping paths that are neither snapshotted nor searched.
+pub(crate) fn is_internal_workspace_path(relative_path: &str) -> bool {
   let norm = relative_path.replace('\\', "/").to_lowercase();
-  if norm.starts_with(".novel/.history/")
+  norm.starts_with(".novel/.history/")
     || norm.starts_with(".novel/.cache/")
     || norm.starts_with(".novel/state/")
+    || norm.starts_with(".novel/.logs/")
     || norm.starts_with(".git/")
-  {
+}
+
+fn should_track_history_path(relative_path: &str) -> bool {
+  if is_internal_workspace_path(relative_path) {
     return false;
   }
+  let norm = relative_path.replace('\\', "/").to_lowercase();
   norm.starts_with("stories/")
     || norm.starts_with("outline/")
     || norm.starts_with("concept/")
@@ -1117,6 +1164,55 @@ pub fn read_text(state: State<'_, AppState>, relative_path: String) -> Result<St
   fs::read_to_string(target).map_err(|e| format!("read failed: {e}"))
 }
 
+/// With `cancel`, the walk skips everything left once the token is raised and
+/// the search fails instead of returning partial hits. With `overlay`, the
+/// search sees the dry run's files instead of the disk.
+pub(crate) fn search_text_internal(
+  root: &Path,
+  overlay: Option<&WorkspaceOverlay>,
+  options: &text_search::SearchOptions,
+  cancel: Option<&CancelToken>,
+) -> Result<text_search::SearchResult, String> {
+  let cancelled = || cancel.map(|c| c.is_cancelled()).unwrap_or(false);
+  let skip_path = |rel: &str| cancelled() || is_internal_workspace_path(rel);
+  let result = match overlay {
+    Some(overlay) => text_search::search_tree(
+      options,
+      skip_path,
+      |dir| {
+        let entries = overlay.list_dir(dir).unwrap_or_default();
+        entries.into_iter().map(|(name, kind)| (name, kind == "dir")).collect()
+      },
+      |rel| overlay.read_text(rel).ok(),
+    )?,
+    None => text_search::search_workspace(root, options, skip_path)?,
+  };
+  if cancelled() {
+    return Err(agent_system::RUN_CANCELLED.to_string());
+  }
+  Ok(result)
+}
+
+#[tauri::command]
+pub fn search_text(
+  state: State<'_, AppState>,
+  query: String,
+  regex: Option<bool>,
+  case_sensitive: Option<bool>,
+  path_glob: Option<String>,
+  max_results: Option<usize>,
+) -> Result<text_search::SearchResult, String> {
+  let root = get_workspace_root(&state)?;
+  let options = text_search::SearchOptions {
+    query,
+    regex: regex.unwrap_or(false),
+    case_sensitive: case_sensitive.unwrap_or(false),
+    path_glob,
+    max_results: max_results.unwrap_or(200).clamp(1, 2000),
+  };
+  search_text_internal(&root, None, &options, None)
+}
+
 #[tauri::command]
 pub fn write_text(
   state: State<'_, AppState>,
@@ -1177,6 +1273,9 @@ pub(crate) fn write_text_internal(
   if rel_norm.starts_with("concept/") && rel_norm.to_lowercase().ends_with(".md") {
     update_concept_index(root, &rel_norm, content)?;
   }
+  if let Err(e) = story_index::update_file(root, &rel_norm, content) {
+    eprintln!("update story index failed: {e}");
+  }
 
   Ok(())
 }
@@ -1426,11 +1525,20 @@ pub struct ProviderConnectivityResult {
   pub message: String,
 }
 
+/// Providers that run without credentials (local Ollama servers, scripted
+/// fixtures).
+fn provider_is_keyless(provider: &app_settings::ModelProvider) -> bool {
+  matches!(
+    provider.kind,
+    app_settings::ProviderKind::Ollama | app_settings::ProviderKind::Scripted
+  )
+}
+
 fn provider_has_configured_api_key(
   app: &AppHandle,
   provider: &app_settings::ModelProvider,
 ) -> bool {
-  if !provider.api_key.trim().is_empty() {
+  if !provider.api_key.trim().is_empty() || provider_is_keyless(provider) {
     return true;
   }
   matches!(
@@ -1439,6 +1547,18 @@ fn provider_has_configured_api_key(
   )
 }
 
+fn provider_supports_native_tools(provider: &app_settings::ModelProvider) -> bool {
+  match provider.kind {
+    app_settings::ProviderKind::OpenAI
+    | app_settings::ProviderKind::Anthropic
+    | app_settings::ProviderKind::Gemini
+    | app_settings::ProviderKind::Scripted => true,
+    app_settings::ProviderKind::OpenAICompatible | app_settings::ProviderKind::Ollama => {
+      provider.native_tool_calls
+    }
+  }
+}
+
 fn resolve_current_provider(
   app: &AppHandle,
   settings: &app_settings::AppSettings,
@@ -1494,9 +1614,86 @@ fn ensure_provider_ready(
       provider.id
     ));
   }
+  warn_if_model_not_in_catalog(app, provider);
   Ok(())
 }
 
+/// Background catalog fetches for the model check give up after this long.
+const CATALOG_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A typo in `model_name` otherwise only shows up as an HTTP 404 mid-run, so
+/// check it against the provider's catalog and tell the UI. Without a fresh
+/// cached catalog one is fetched in the background, so the run does not wait.
+fn warn_if_model_not_in_catalog(app: &AppHandle, provider: &app_settings::ModelProvider) {
+  let cached = {
+    let state = app.state::<AppState>();
+    let Ok(catalogs) = state.provider_catalogs.lock() else {
+      return;
+    };
+    let now = Utc::now().timestamp_millis();
+    catalogs.get(&provider.id).filter(|c| c.is_fresh(provider.base_url.trim(), now)).cloned()
+  };
+  if let Some(catalog) = cached {
+    warn_on_catalog(app, provider, &catalog);
+    return;
+  }
+  let (app, provider) = (app.clone(), provider.clone());
+  tauri::async_runtime::spawn(async move {
+    let fetch = async {
+      let key = resolve_provider_api_key(&app, &provider, None)?;
+      refresh_provider_catalog(&app, &build_http_client()?, &provider, key.as_str()).await
+    };
+    let result = tokio::time::timeout(CATALOG_CHECK_TIMEOUT, fetch)
+      .await
+      .unwrap_or_else(|_| Err(format!("timed out after {}s", CATALOG_CHECK_TIMEOUT.as_secs())));
+    match result {
+      Ok(catalog) => warn_on_catalog(&app, &provider, &catalog),
+      Err(e) => {
+        eprintln!("model catalog check for provider={} failed: {e}", provider.id);
+        // Remember the miss so every run does not ask again; an empty catalog never warns.
+        cache_provider_catalog(&app, ProviderCatalog {
+          provider_id: provider.id.clone(),
+          base_url: provider.base_url.trim().to_string(),
+          fetched_at: Utc::now().timestamp_millis(),
+          models: Vec::new(),
+        });
+      }
+    }
+  });
+}
+
+fn warn_on_catalog(app: &AppHandle, provider: &app_settings::ModelProvider, catalog: &ProviderCatalog) {
+  if catalog.models.is_empty() || catalog.contains(&provider.model_name) {
+    return;
+  }
+  let message = format!(
+    "model {} is not in the catalog of provider={}; check the model name in Settings > Models.",
+    provider.model_name.trim(),
+    provider.id
+  );
+  eprintln!("{message}");
+  let _ = app.emit(
+    "provider_model_warning",
+    serde_json::json!({
+      "providerId": provider.id,
+      "model": provider.model_name.trim(),
+      "message": message,
+    }),
+  );
+}
+
+/// Output-token limit the cached catalog reports for the provider's model.
+fn catalog_max_output_tokens(app: &AppHandle, provider: &app_settings::ModelProvider) -> Option<u32> {
+  let state = app.state::<AppState>();
+  let catalogs = state.provider_catalogs.lock().ok()?;
+  let catalog = catalogs.get(&provider.id).filter(|c| c.base_url == provider.base_url.trim())?;
+  catalog
+    .models
+    .iter()
+    .find(|m| provider_catalog::has_model(std::slice::from_ref(m), &provider.model_name))
+    .and_then(|m| m.max_output_tokens)
+}
+
 fn resolve_chat_provider(
   app: &AppHandle,
   settings: &app_settings::AppSettings,
@@ -1525,6 +1722,35 @@ fn resolve_chat_provider(
   Ok(provider)
 }
 
+/// `primary` followed by the configured fallback providers that are ready
+/// and use the same tool-calling mode, since the run's messages depend on it.
+fn resolve_fallback_chain(
+  app: &AppHandle,
+  settings: &app_settings::AppSettings,
+  primary: &app_settings::ModelProvider,
+) -> Vec<app_settings::ModelProvider> {
+  let mut chain = vec![primary.clone()];
+  for id in &settings.provider_fallback_ids {
+    let Some(provider) = settings.providers.iter().find(|p| p.id == id.trim()) else {
+      eprintln!("fallback provider not found: {id}");
+      continue;
+    };
+    if chain.iter().any(|p| p.id == provider.id) {
+      continue;
+    }
+    if let Err(e) = ensure_provider_ready(app, provider) {
+      eprintln!("fallback provider skipped: {e}");
+      continue;
+    }
+    if provider_supports_native_tools(provider) != provider_supports_native_tools(primary) {
+      eprintln!("fallback provider skipped: {} uses a different tool-calling mode", provider.id);
+      continue;
+    }
+    chain.push(provider.clone());
+  }
+  chain
+}
+
 fn resolve_provider_api_key(
   app: &AppHandle,
   provider: &app_settings::ModelProvider,
@@ -1540,7 +1766,7 @@ fn resolve_provider_api_key(
     Ok(Some(v)) if !v.trim().is_empty() => Ok(v),
     Ok(_) => {
       let fallback = provider.api_key.trim().to_string();
-      if fallback.is_empty() {
+      if fallback.is_empty() && !provider_is_keyless(provider) {
         Err(format!(
           "no API key configured for provider={}. Set it in Settings > Models.",
           provider.id
@@ -1553,13 +1779,49 @@ fn resolve_provider_api_key(
   }
 }
 
+/// Connectivity result of a probe request: success, or the status with the
+/// start of the error body.
+async fn probe_response_result(
+  resp: ProviderResponse,
+  started: Instant,
+) -> Result<ProviderConnectivityResult, String> {
+  let status = resp.status;
+  if status.is_success() {
+    return Ok(ProviderConnectivityResult {
+      ok: true,
+      status_code: status.as_u16(),
+      latency_ms: started.elapsed().as_millis(),
+      message: "Provider reachable and model responded.".to_string(),
+    });
+  }
+  let raw = resp.text().await.unwrap_or_default();
+  let snippet = if raw.chars().count() > 260 {
+    format!("{}...", raw.chars().take(260).collect::<String>())
+  } else {
+    raw
+  };
+  Err(format!("http {}: {}", status.as_u16(), snippet))
+}
+
 async fn probe_provider_connectivity(
   client: &reqwest::Client,
   provider: &app_settings::ModelProvider,
   api_key: &str,
+  workspace_root: Option<PathBuf>,
+  max_attempts: u32,
 ) -> Result<ProviderConnectivityResult, String> {
   let started = Instant::now();
   match provider.kind {
+    app_settings::ProviderKind::Scripted => {
+      let path = scripted_provider::resolve_fixture_path(&provider.base_url, workspace_root.as_deref());
+      let count = scripted_provider::probe(&path)?;
+      Ok(ProviderConnectivityResult {
+        ok: true,
+        status_code: 200,
+        latency_ms: started.elapsed().as_millis(),
+        message: format!("Scripted fixture loaded with {count} responses."),
+      })
+    }
     app_settings::ProviderKind::OpenAI | app_settings::ProviderKind::OpenAICompatible => {
       let base = provider.base_url.trim().trim_end_matches('/');
       if base.is_empty() {
@@ -1573,29 +1835,11 @@ async fn probe_provider_connectivity(
         "temperature": 0.0,
         "stream": false
       });
-      let resp = client
-        .post(url.as_str())
-        .bearer_auth(api_key.trim())
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| format!("request failed: {e}"))?;
-      let status = resp.status();
-      if status.is_success() {
-        return Ok(ProviderConnectivityResult {
-          ok: true,
-          status_code: status.as_u16(),
-          latency_ms: started.elapsed().as_millis(),
-          message: "Provider reachable and model responded.".to_string(),
-        });
-      }
-      let raw = resp.text().await.unwrap_or_else(|_| String::new());
-      let snippet = if raw.chars().count() > 260 {
-        format!("{}...", raw.chars().take(260).collect::<String>())
-      } else {
-        raw
-      };
-      Err(format!("http {}: {}", status.as_u16(), snippet))
+      let resp = send_with_retry(max_attempts, |_, _| {}, || {
+        send_http(client.post(url.as_str()).bearer_auth(api_key.trim()).json(&body))
+      })
+      .await?;
+      probe_response_result(resp, started).await
     }
     app_settings::ProviderKind::Anthropic => {
       let base = provider.base_url.trim().trim_end_matches('/');
@@ -1611,30 +1855,45 @@ async fn probe_provider_connectivity(
         "max_tokens": 16,
         "messages": [{"role":"user","content":"Reply with OK."}]
       });
-      let resp = client
-        .post(endpoint.as_str())
-        .header("x-api-key", api_key.trim())
-        .header("anthropic-version", "2023-06-01")
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| format!("request failed: {e}"))?;
-      let status = resp.status();
-      if status.is_success() {
-        return Ok(ProviderConnectivityResult {
-          ok: true,
-          status_code: status.as_u16(),
-          latency_ms: started.elapsed().as_millis(),
-          message: "Provider reachable and model responded.".to_string(),
-        });
+      let resp = send_with_retry(max_attempts, |_, _| {}, || {
+        send_http(
+          client
+            .post(endpoint.as_str())
+            .header("x-api-key", api_key.trim())
+            .header("anthropic-version", "2023-06-01")
+            .json(&body),
+        )
+      })
+      .await?;
+      probe_response_result(resp, started).await
+    }
+    app_settings::ProviderKind::Gemini => {
+      let url = format!("{}:generateContent", gemini_model_endpoint(provider));
+      let body = serde_json::json!({
+        "contents": [{"role":"user","parts":[{"text":"Reply with OK."}]}],
+        "generationConfig": {"maxOutputTokens": 8, "temperature": 0.0}
+      });
+      let resp = send_with_retry(max_attempts, |_, _| {}, || {
+        send_http(client.post(url.as_str()).header("x-goog-api-key", api_key.trim()).json(&body))
+      })
+      .await?;
+      probe_response_result(resp, started).await
+    }
+    app_settings::ProviderKind::Ollama => {
+      let models = fetch_provider_models(client, provider, api_key).await?;
+      if !provider_catalog::has_model(&models, &provider.model_name) {
+        return Err(format!(
+          "Ollama is reachable but model {} is not installed; run `ollama pull {}`.",
+          provider.model_name.trim(),
+          provider.model_name.trim()
+        ));
       }
-      let raw = resp.text().await.unwrap_or_else(|_| String::new());
-      let snippet = if raw.chars().count() > 260 {
-        format!("{}...", raw.chars().take(260).collect::<String>())
-      } else {
-        raw
-      };
-      Err(format!("http {}: {}", status.as_u16(), snippet))
+      Ok(ProviderConnectivityResult {
+        ok: true,
+        status_code: 200,
+        latency_ms: started.elapsed().as_millis(),
+        message: format!("Ollama reachable with {} installed models.", models.len()),
+      })
     }
   }
 }
@@ -1659,7 +1918,170 @@ pub async fn test_provider_connectivity(
   let merged_key = apiKey.or(api_key);
   let key = resolve_provider_api_key(&app, &provider, merged_key)?;
   let client = build_http_client()?;
-  probe_provider_connectivity(&client, &provider, key.as_str()).await
+  probe_provider_connectivity(
+    &client,
+    &provider,
+    key.as_str(),
+    current_workspace_root(&app),
+    provider_max_attempts(&app),
+  )
+  .await
+}
+
+fn anthropic_models_endpoint(provider: &app_settings::ModelProvider) -> String {
+  let base = provider.base_url.trim().trim_end_matches('/');
+  let base = base.strip_suffix("/messages").unwrap_or(base);
+  if base.is_empty() {
+    "https://api.anthropic.com/v1/models".to_string()
+  } else if base.ends_with("/v1") {
+    format!("{base}/models")
+  } else {
+    format!("{base}/v1/models")
+  }
+}
+
+async fn get_catalog_json(req: reqwest::RequestBuilder) -> Result<serde_json::Value, String> {
+  let resp = req.send().await.map_err(|e| format!("request failed: {e}"))?;
+  let status = resp.status();
+  if !status.is_success() {
+    let raw = resp.text().await.unwrap_or_default();
+    return Err(format!("http {}: {}", status.as_u16(), raw));
+  }
+  resp.json().await.map_err(|e| format!("decode failed: {e}"))
+}
+
+/// Queries the model catalog endpoint of the provider's kind, following
+/// pagination where the API has it.
+async fn fetch_provider_models(
+  client: &reqwest::Client,
+  provider: &app_settings::ModelProvider,
+  api_key: &str,
+) -> Result<Vec<CatalogModel>, String> {
+  const MAX_PAGES: usize = 20;
+  match provider.kind {
+    app_settings::ProviderKind::Scripted => Ok(vec![CatalogModel {
+      id: provider.model_name.trim().to_string(),
+      capabilities: vec!["tools".to_string()],
+      ..Default::default()
+    }]),
+    app_settings::ProviderKind::OpenAI | app_settings::ProviderKind::OpenAICompatible => {
+      let url = format!("{}/models", provider.base_url.trim().trim_end_matches('/'));
+      let value = get_catalog_json(client.get(url.as_str()).bearer_auth(api_key.trim())).await?;
+      Ok(provider_catalog::parse_openai_models(&value))
+    }
+    app_settings::ProviderKind::Anthropic => {
+      let url = anthropic_models_endpoint(provider);
+      let mut models = Vec::new();
+      let mut after_id: Option<String> = None;
+      for _ in 0..MAX_PAGES {
+        let mut req = client
+          .get(url.as_str())
+          .header("x-api-key", api_key.trim())
+          .header("anthropic-version", "2023-06-01")
+          .query(&[("limit", "1000")]);
+        if let Some(after) = after_id.as_deref() {
+          req = req.query(&[("after_id", after)]);
+        }
+        let value = get_catalog_json(req).await?;
+        models.extend(provider_catalog::parse_anthropic_models(&value));
+        after_id = value["last_id"].as_str().map(|s| s.to_string());
+        if value["has_more"].as_bool() != Some(true) || after_id.is_none() {
+          break;
+        }
+      }
+      Ok(models)
+    }
+    app_settings::ProviderKind::Gemini => {
+      let base = provider.base_url.trim().trim_end_matches('/');
+      let base = if base.is_empty() {
+        "https://generativelanguage.googleapis.com/v1beta"
+      } else {
+        base
+      };
+      let url = format!("{base}/models");
+      let mut models = Vec::new();
+      let mut page_token: Option<String> = None;
+      for _ in 0..MAX_PAGES {
+        let mut req = client
+          .get(url.as_str())
+          .header("x-goog-api-key", api_key.trim())
+          .query(&[("pageSize", "1000")]);
+        if let Some(token) = page_token.as_deref() {
+          req = req.query(&[("pageToken", token)]);
+        }
+        let value = get_catalog_json(req).await?;
+        models.extend(provider_catalog::parse_gemini_models(&value));
+        page_token = value["nextPageToken"].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+        if page_token.is_none() {
+          break;
+        }
+      }
+      Ok(models)
+    }
+    app_settings::ProviderKind::Ollama => {
+      let url = format!("{}/api/tags", ollama_base_url(provider));
+      let value = get_catalog_json(client.get(url.as_str())).await?;
+      Ok(provider_catalog::parse_ollama_models(&value))
+    }
+  }
+}
+
+/// Lists the models the provider's endpoint offers. Results are cached per
+/// provider for an hour; `refresh` forces a new request.
+#[allow(non_snake_case)]
+#[tauri::command]
+pub async fn list_provider_models(
+  app: AppHandle,
+  state: State<'_, AppState>,
+  provider: app_settings::ModelProvider,
+  apiKey: Option<String>,
+  api_key: Option<String>,
+  refresh: Option<bool>,
+) -> Result<ProviderCatalog, String> {
+  if provider.id.trim().is_empty() {
+    return Err("provider id is empty".to_string());
+  }
+  if !refresh.unwrap_or(false) {
+    let catalogs = state
+      .provider_catalogs
+      .lock()
+      .map_err(|_| "provider catalogs lock poisoned".to_string())?;
+    let now = Utc::now().timestamp_millis();
+    // An empty entry is a failed background check; ask again so the error shows.
+    let fresh = catalogs
+      .get(&provider.id)
+      .filter(|c| c.is_fresh(provider.base_url.trim(), now) && !c.models.is_empty());
+    if let Some(catalog) = fresh {
+      return Ok(catalog.clone());
+    }
+  }
+  let key = resolve_provider_api_key(&app, &provider, apiKey.or(api_key))?;
+  refresh_provider_catalog(&app, &build_http_client()?, &provider, key.as_str()).await
+}
+
+/// Fetches the provider's catalog and caches it.
+async fn refresh_provider_catalog(
+  app: &AppHandle,
+  client: &reqwest::Client,
+  provider: &app_settings::ModelProvider,
+  api_key: &str,
+) -> Result<ProviderCatalog, String> {
+  let mut models = fetch_provider_models(client, provider, api_key).await?;
+  models.sort_by(|a, b| a.id.cmp(&b.id));
+  let catalog = ProviderCatalog {
+    provider_id: provider.id.clone(),
+    base_url: provider.base_url.trim().to_string(),
+    fetched_at: Utc::now().timestamp_millis(),
+    models,
+  };
+  cache_provider_catalog(app, catalog.clone());
+  Ok(catalog)
+}
+
+fn cache_provider_catalog(app: &AppHandle, catalog: ProviderCatalog) {
+  if let Ok(mut catalogs) = app.state::<AppState>().provider_catalogs.lock() {
+    catalogs.insert(catalog.provider_id.clone(), catalog);
+  }
 }
 
 #[tauri::command]
@@ -1785,6 +2207,166 @@ fn clear_stream_task(app: &AppHandle, stream_id: &str) {
     Err(_) => return,
   };
   tasks.remove(stream_id);
+  drop(tasks);
+  if let Ok(mut cancels) = app_state.ai_stream_cancels.lock() {
+    cancels.remove(stream_id);
+  }
+  drop_pending_tool_approvals(&app_state, stream_id);
+}
+
+/// Dropping a responder resolves the waiting approval as denied.
+fn drop_pending_tool_approvals(state: &AppState, stream_id: &str) {
+  if let Ok(mut approvals) = state.tool_approvals.lock() {
+    approvals.retain(|_, pending| pending.stream_id != stream_id);
+  }
+}
+
+#[tauri::command]
+pub fn list_agent_runs(state: State<'_, AppState>, max: Option<usize>) -> Result<Vec<run_trace::RunSummary>, String> {
+  let root = get_workspace_root(&state)?;
+  run_trace::list_runs(&root, max.unwrap_or(50).clamp(1, 500))
+}
+
+/// Ranked search over memory and chapter/concept files; `sources` takes
+/// `memory`, `chapters` and `concepts` (all when empty).
+#[tauri::command]
+pub fn story_search(
+  state: State<'_, AppState>,
+  query: String,
+  sources: Option<Vec<String>>,
+  limit: Option<usize>,
+) -> Result<Vec<story_index::StoryHit>, String> {
+  let root = get_workspace_root(&state)?;
+  let memory = agent_memory::MemoryStore::load(&root);
+  let sources = sources.map(|list| serde_json::json!(list));
+  story_index::search(
+    &root,
+    memory.items(),
+    &[],
+    &query,
+    agent_system::story_sources(sources.as_ref()),
+    limit.unwrap_or(20).clamp(1, 200),
+  )
+}
+
+#[allow(non_snake_case)]
+#[tauri::command]
+pub fn list_memory_items(
+  state: State<'_, AppState>,
+  scope: Option<String>,
+  subject: Option<String>,
+  query: Option<String>,
+  pinnedOnly: Option<bool>,
+  pinned_only: Option<bool>,
+) -> Result<Vec<agent_memory::MemoryItem>, String> {
+  let root = get_workspace_root(&state)?;
+  let filter = agent_memory::MemoryFilter {
+    scope: scope.as_deref().map(agent_memory::MemoryScope::parse).transpose()?,
+    subject: subject.filter(|s| !s.trim().is_empty()),
+    pinned_only: pinnedOnly.or(pinned_only).unwrap_or(false),
+    query,
+  };
+  Ok(agent_memory::MemoryStore::load(&root).list(&filter))
+}
+
+/// Creates a memory item, or edits the item `id` when given.
+#[tauri::command]
+pub fn save_memory_item(
+  state: State<'_, AppState>,
+  id: Option<String>,
+  key: String,
+  value: String,
+  scope: Option<String>,
+  subject: Option<String>,
+  pinned: Option<bool>,
+) -> Result<agent_memory::MemoryItem, String> {
+  let root = get_workspace_root(&state)?;
+  let mut store = agent_memory::MemoryStore::load(&root);
+  let input = agent_memory::MemoryUpsert {
+    key,
+    value,
+    scope: agent_memory::MemoryScope::parse(scope.as_deref().unwrap_or(""))?,
+    subject: subject.unwrap_or_default(),
+    pinned,
+    source: agent_memory::MemorySource {
+      origin: "user".to_string(),
+      ..Default::default()
+    },
+  };
+  let item = match id.filter(|v| !v.trim().is_empty()) {
+    Some(id) => store.update(&id, input)?,
+    None => store.upsert(input)?.0,
+  };
+  store.save()?;
+  Ok(item)
+}
+
+#[tauri::command]
+pub fn delete_memory_item(state: State<'_, AppState>, id: String) -> Result<bool, String> {
+  let root = get_workspace_root(&state)?;
+  let mut store = agent_memory::MemoryStore::load(&root);
+  let removed = store.delete(&id).is_some();
+  if removed {
+    store.save()?;
+  }
+  Ok(removed)
+}
+
+#[allow(non_snake_case)]
+#[tauri::command]
+pub fn load_agent_run(
+  state: State<'_, AppState>,
+  streamId: Option<String>,
+  stream_id: Option<String>,
+) -> Result<Vec<run_trace::TraceLine>, String> {
+  let root = get_workspace_root(&state)?;
+  let stream_id = streamId.or(stream_id).unwrap_or_default();
+  if stream_id.trim().is_empty() {
+    return Err("stream_id is required".to_string());
+  }
+  run_trace::load_trace(&root, &stream_id)
+}
+
+/// Dry-run replay of a recorded run's tool calls; file writes come back as a
+/// pending change set and never reach the workspace.
+#[allow(non_snake_case)]
+#[tauri::command]
+pub async fn replay_agent_run(
+  state: State<'_, AppState>,
+  streamId: Option<String>,
+  stream_id: Option<String>,
+) -> Result<agent_system::RunReplay, String> {
+  let root = get_workspace_root(&state)?;
+  let stream_id = streamId.or(stream_id).unwrap_or_default();
+  if stream_id.trim().is_empty() {
+    return Err("stream_id is required".to_string());
+  }
+  let lines = run_trace::load_trace(&root, &stream_id)?;
+  let mut runtime = agent_system::AgentRuntime::new(root);
+  Ok(runtime.replay_trace(&lines).await)
+}
+
+#[allow(non_snake_case)]
+#[tauri::command]
+pub fn agent_tool_approve(
+  state: State<'_, AppState>,
+  approvalId: Option<String>,
+  approval_id: Option<String>,
+  approved: bool,
+) -> Result<(), String> {
+  let approval_id = approvalId.or(approval_id).unwrap_or_default();
+  let approval_id = approval_id.trim().to_string();
+  if approval_id.is_empty() {
+    return Err("approval_id is required".to_string());
+  }
+  let pending = state
+    .tool_approvals
+    .lock()
+    .map_err(|_| "tool approvals lock poisoned".to_string())?
+    .remove(&approval_id)
+    .ok_or_else(|| format!("no pending tool approval: {approval_id}"))?;
+  let _ = pending.responder.send(approved);
+  Ok(())
 }
 
 #[allow(non_snake_case)]
@@ -1800,20 +2382,51 @@ pub fn chat_cancel_stream(
   if stream_id.is_empty() {
     return Err("stream_id is required".to_string());
   }
-  let handle = {
-    let mut tasks = state
-      .ai_stream_tasks
-      .lock()
-      .map_err(|_| "stream tasks lock poisoned".to_string())?;
-    tasks.remove(&stream_id)
-  };
-  if let Some(task) = handle {
-    task.abort();
+  let cancel = state
+    .ai_stream_cancels
+    .lock()
+    .map_err(|_| "stream cancels lock poisoned".to_string())?
+    .remove(&stream_id);
+  match cancel {
+    Some(cancel) => {
+      // The run stops at its next step boundary and checkpoints itself; abort
+      // it only if a synchronous tool keeps it busy past the grace period.
+      cancel.cancel();
+      let app = window.app_handle().clone();
+      let stream_id = stream_id.clone();
+      tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(STREAM_CANCEL_GRACE).await;
+        let app_state = app.state::<AppState>();
+        let handle = app_state
+          .ai_stream_tasks
+          .lock()
+          .ok()
+          .and_then(|mut tasks| tasks.remove(&stream_id));
+        if let Some(task) = handle {
+          task.abort();
+        }
+      });
+    }
+    None => {
+      let handle = {
+        let mut tasks = state
+          .ai_stream_tasks
+          .lock()
+          .map_err(|_| "stream tasks lock poisoned".to_string())?;
+        tasks.remove(&stream_id)
+      };
+      if let Some(task) = handle {
+        task.abort();
+      }
+    }
   }
+  drop_pending_tool_approvals(&state, &stream_id);
   emit_stream_done(&window, &stream_id, true);
   Ok(())
 }
 
+const STREAM_CANCEL_GRACE: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 struct LiveStreamSession {
   window: tauri::Window,
@@ -1983,14 +2596,16 @@ impl LiveEmitGate {
   }
 }
 
-fn sse_take_line(buffer: &mut String) -> Option<String> {
-  let pos = buffer.find('\n')?;
-  let mut line = buffer[..pos].to_string();
-  if line.ends_with('\r') {
+/// Takes the next complete line off a raw byte buffer. Decoding waits for
+/// the newline so a character split across network chunks stays intact.
+fn take_byte_line(buffer: &mut Vec<u8>) -> Option<String> {
+  let pos = buffer.iter().position(|b| *b == b'\n')?;
+  let mut line = buffer.drain(..=pos).collect::<Vec<_>>();
+  line.pop();
+  if line.last() == Some(&b'\r') {
     line.pop();
   }
-  buffer.drain(..=pos);
-  Some(line)
+  Some(String::from_utf8_lossy(&line).into_owned())
 }
 
 fn build_http_client() -> Result<reqwest::Client, String> {
@@ -2031,32 +2646,135 @@ pub fn chat_generate_stream(
     .or(provider_id)
     .map(|v| v.trim().to_string())
     .filter(|v| !v.is_empty());
+  spawn_agent_stream(
+    app,
+    window,
+    &state,
+    stream_id,
+    messages,
+    use_markdown,
+    agent_id,
+    provider_id,
+    None,
+  )
+}
 
-  let app = app.clone();
-  let workspace_root = get_workspace_root(&state)?;
-  let stream_id_for_task = stream_id.clone();
-  let window_for_task = window.clone();
-  let app_for_task = app.clone();
-  let requested_provider_id = provider_id.clone();
-  let live_session = LiveStreamSession::new(window_for_task.clone(), stream_id_for_task.clone());
-
-  let task = tauri::async_runtime::spawn(async move {
-    let payload_start = serde_json::json!({ "streamId": stream_id_for_task });
-    let _ = window_for_task.emit("ai_stream_start", payload_start);
-    emit_stream_status(&window_for_task, &stream_id_for_task, "initializing");
-
-    let settings = match app_settings::load(&app) {
-      Ok(v) => v,
-      Err(e) => {
-        let _ = window_for_task.emit(
-          "ai_error",
-          serde_json::json!({
-            "streamId": stream_id_for_task,
-            "stage": "settings",
-            "message": e
-          }),
-        );
-        clear_stream_task(&app_for_task, &stream_id_for_task);
+/// Continues a run that was cancelled or timed out from its checkpoint in
+/// `.novel/state/session-state.json`; `fromStreamId` defaults to the latest
+/// interrupted run. Events are emitted under the new `streamId`.
+#[allow(non_snake_case, clippy::too_many_arguments)]
+#[tauri::command]
+pub fn chat_resume_stream(
+  app: AppHandle,
+  window: tauri::Window,
+  state: State<'_, AppState>,
+  streamId: Option<String>,
+  stream_id: Option<String>,
+  fromStreamId: Option<String>,
+  from_stream_id: Option<String>,
+  useMarkdown: Option<bool>,
+  use_markdown: Option<bool>,
+  providerId: Option<String>,
+  provider_id: Option<String>,
+) -> Result<(), String> {
+  let stream_id = streamId.or(stream_id).unwrap_or_default();
+  let stream_id = stream_id.trim().to_string();
+  if stream_id.is_empty() {
+    return Err("stream_id is required".to_string());
+  }
+  let root = get_workspace_root(&state)?;
+  let checkpoint = run_checkpoint::load(&root, fromStreamId.or(from_stream_id).as_deref())?;
+  let provider_id = providerId
+    .or(provider_id)
+    .or(Some(checkpoint.provider_id.clone()))
+    .map(|v| v.trim().to_string())
+    .filter(|v| !v.is_empty());
+  let agent_id = Some(checkpoint.agent_id.clone()).filter(|v| !v.is_empty());
+  let from_stream_id = checkpoint.stream_id.clone();
+  spawn_agent_stream(
+    app,
+    window,
+    &state,
+    stream_id,
+    Vec::new(),
+    useMarkdown.or(use_markdown).unwrap_or(false),
+    agent_id,
+    provider_id,
+    Some(checkpoint.run),
+  )?;
+  run_checkpoint::remove(&root, &from_stream_id)
+}
+
+/// Saves the last step checkpoint of an interrupted run and tells the UI it
+/// can be resumed with `chat_resume_stream`.
+fn save_interrupted_run(
+  window: &tauri::Window,
+  root: &Path,
+  stream_id: &str,
+  agent_id: &str,
+  provider_id: &str,
+  reason: &str,
+  checkpoint: Option<run_checkpoint::RunCheckpoint>,
+) {
+  let Some(run) = checkpoint else {
+    return;
+  };
+  match run_checkpoint::save(root, stream_id, agent_id, provider_id, reason, run) {
+    Ok(saved) => {
+      let _ = window.emit(
+        "ai_run_checkpoint",
+        serde_json::json!({
+          "streamId": stream_id,
+          "reason": reason,
+          "step": saved.run.step,
+        }),
+      );
+    }
+    Err(e) => eprintln!("save run checkpoint failed: {e}"),
+  }
+}
+
+/// Spawns the agent run of one stream and registers its task and cancel
+/// token in `AppState`; with `resume` the run continues that checkpoint.
+#[allow(clippy::too_many_arguments)]
+fn spawn_agent_stream(
+  app: AppHandle,
+  window: tauri::Window,
+  state: &State<'_, AppState>,
+  stream_id: String,
+  messages: Vec<ChatMessage>,
+  use_markdown: bool,
+  agent_id: Option<String>,
+  provider_id: Option<String>,
+  resume: Option<run_checkpoint::RunCheckpoint>,
+) -> Result<(), String> {
+  let app = app.clone();
+  let workspace_root = get_workspace_root(state)?;
+  let cancel = CancelToken::new();
+  let cancel_for_task = cancel.clone();
+  let stream_id_for_task = stream_id.clone();
+  let window_for_task = window.clone();
+  let app_for_task = app.clone();
+  let requested_provider_id = provider_id.clone();
+  let live_session = LiveStreamSession::new(window_for_task.clone(), stream_id_for_task.clone());
+
+  let task = tauri::async_runtime::spawn(async move {
+    let payload_start = serde_json::json!({ "streamId": stream_id_for_task });
+    let _ = window_for_task.emit("ai_stream_start", payload_start);
+    emit_stream_status(&window_for_task, &stream_id_for_task, "initializing");
+
+    let settings = match app_settings::load(&app) {
+      Ok(v) => v,
+      Err(e) => {
+        let _ = window_for_task.emit(
+          "ai_error",
+          serde_json::json!({
+            "streamId": stream_id_for_task,
+            "stage": "settings",
+            "message": e
+          }),
+        );
+        clear_stream_task(&app_for_task, &stream_id_for_task);
         emit_stream_done(&window_for_task, &stream_id_for_task, false);
         return;
       }
@@ -2077,6 +2795,7 @@ pub fn chat_generate_stream(
       .or_else(|| agents_list.first());
     let agent_system = agent.map(|a| a.system_prompt.clone()).unwrap_or_default();
     let agent_temp = agent.map(|a| a.temperature);
+    let agent_generation = agent.map(|a| a.generation_overrides()).unwrap_or_default();
     let ai_edit_apply_mode = settings.ai_edit_apply_mode.clone();
     let client = match build_http_client() {
       Ok(v) => v,
@@ -2113,20 +2832,99 @@ pub fn chat_generate_stream(
     };
 
     let workspace_root_clone = workspace_root.clone();
+    let native_tools = provider_supports_native_tools(&current_provider);
+    let project_settings = load_project_writing_settings_internal(&workspace_root).unwrap_or_default();
+    let mut tool_permissions = settings.tool_permissions.clone();
+    tool_permissions.extend(project_settings.tool_permissions.clone());
+    let max_steps = project_settings
+      .agent_max_steps
+      .or(agent.map(|a| a.max_steps))
+      .unwrap_or(agents::DEFAULT_MAX_STEPS)
+      .clamp(1, 200);
+    let react_timeout_secs = project_settings
+      .agent_react_timeout_secs
+      .or(agent.map(|a| a.react_timeout_secs))
+      .unwrap_or(agents::DEFAULT_REACT_TIMEOUT_SECS)
+      .clamp(30, 3_600);
+    let stop_marker = project_settings
+      .agent_stop_marker
+      .clone()
+      .or(agent.map(|a| a.stop_marker.clone()))
+      .unwrap_or_default();
+    let provider_chain = Arc::new(resolve_fallback_chain(&app, &settings, &current_provider));
+    for provider in provider_chain.iter() {
+      if provider.kind == app_settings::ProviderKind::Scripted {
+        scripted_provider::reset(&scripted_provider::resolve_fixture_path(
+          &provider.base_url,
+          Some(workspace_root_clone.as_path()),
+        ));
+      }
+    }
     let mut runtime = agent_system::AgentRuntime::new(workspace_root);
+    runtime.set_run_id(stream_id_for_task.clone());
+    runtime.set_agent_id(agent.map(|a| a.id.clone()).unwrap_or_default());
+    runtime.set_cancel_token(cancel_for_task);
+    if let Some(checkpoint) = resume {
+      runtime.resume_from(checkpoint);
+    }
+    runtime.set_delegates(
+      agents_list
+        .iter()
+        .filter(|a| agent.map(|current| current.id != a.id).unwrap_or(true))
+        .cloned()
+        .collect(),
+      agents::MAX_DELEGATION_DEPTH,
+    );
+    runtime.set_tool_permissions(tool_permissions);
+    runtime.set_tool_timeouts(&settings.tool_timeout_secs);
+    runtime.set_run_limits(max_steps, stop_marker);
+    runtime.set_context_budget(run_context_budget(&provider_chain));
+    let trace = run_trace::RunTraceWriter::create(&workspace_root_clone, &stream_id_for_task).ok();
+    if let Some(trace) = trace.as_ref() {
+      trace.record(run_trace::TraceRecord::Start {
+        stream_id: stream_id_for_task.clone(),
+        agent_id: agent.map(|a| a.id.clone()).unwrap_or_default(),
+        provider_id: current_provider.id.clone(),
+        model: current_provider.model_name.clone(),
+        native_tools,
+      });
+      runtime.set_trace(trace.clone());
+    }
+    let record_finish = |ok: bool, error: Option<String>, perf: serde_json::Value| {
+      if let Some(trace) = trace.as_ref() {
+        trace.record(run_trace::TraceRecord::Finish { ok, error, perf });
+      }
+    };
     let start = Instant::now();
     emit_stream_status(&window_for_task, &stream_id_for_task, "thinking");
-    let react_timeout = Duration::from_secs(240);
+    // Time spent waiting on tool approvals does not count against the limit.
+    let deadline = RunDeadline::new(Duration::from_secs(react_timeout_secs as u64));
     let live_session_for_react = live_session.clone();
-    let run_result = tokio::time::timeout(
-      react_timeout,
-      runtime.run_react(messages, agent_system.clone(), ai_edit_apply_mode.clone(), |msgs| {
-        let provider_cfg = current_provider.clone();
+    let stream_usage = Arc::new(Mutex::new(usage_log::UsageTotals::default()));
+    let active_provider = Arc::new(AtomicUsize::new(0));
+    let run_result = deadline.run(
+      runtime.run_react(messages, agent_system.clone(), ai_edit_apply_mode.clone(), native_tools, |request| {
+        let provider_chain = provider_chain.clone();
+        let active_provider = active_provider.clone();
+        let agent_generation = agent_generation.clone();
         let client = client.clone();
         let app = app.clone();
         let agent_temp = agent_temp;
         let live_session_clone = live_session_for_react.clone();
+        let usage_root = workspace_root_clone.clone();
+        let usage_stream_id = stream_id_for_task.clone();
+        let stream_usage = stream_usage.clone();
         async move {
+          let agent_system::ModelRequest {
+            messages: msgs,
+            tools,
+            temperature,
+            generation,
+            quiet,
+            agent_id,
+          } = request;
+          let generation = generation.unwrap_or_else(|| agent_generation.clone());
+          let live = if quiet { None } else { Some(&live_session_clone) };
           let mut system = String::new();
           for m in msgs.iter().filter(|m| m.role == "system") {
             if !system.is_empty() {
@@ -2136,29 +2934,21 @@ pub fn chat_generate_stream(
           }
           let filtered = msgs.into_iter().filter(|m| m.role != "system").collect::<Vec<_>>();
 
-          match provider_cfg.kind {
-            app_settings::ProviderKind::OpenAI | app_settings::ProviderKind::OpenAICompatible => {
-              call_openai_unbounded(
-                &app,
-                &client,
-                &provider_cfg, // pass full provider config
-                &filtered,
-                system.as_str(),
-                agent_temp,
-                Some(&live_session_clone),
-              ).await
-            },
-            app_settings::ProviderKind::Anthropic => {
-              call_anthropic_unbounded(
-                &app,
-                &client,
-                &provider_cfg,
-                &filtered,
-                system.as_str(),
-                Some(&live_session_clone),
-              ).await
-            },
+          let (app, client, filtered, system, tools) = (&app, &client, &filtered, system.as_str(), &tools);
+          let (turn, provider_cfg) = call_with_fallback(&provider_chain, &active_provider, |provider_cfg| {
+            let mut effective_cfg = provider_cfg.clone();
+            effective_cfg.generation = provider_cfg.generation.merged(&generation);
+            async move {
+              call_provider_turn(app, client, &effective_cfg, filtered, system, temperature.or(agent_temp), tools, live)
+                .await
+            }
+          })
+          .await?;
+          let entry = record_model_usage(&usage_root, &usage_stream_id, &agent_id, provider_cfg, turn.usage);
+          if let Ok(mut totals) = stream_usage.lock() {
+            totals.add(&entry);
           }
+          Ok(turn)
         }
       }, |tool_event| {
         let input_preview = compact_value_preview(&tool_event.args, 180);
@@ -2175,6 +2965,8 @@ pub fn chat_generate_stream(
           "phase": tool_event.phase,
           "ok": tool_event.ok,
           "durationMs": tool_event.duration_ms,
+          "parentActionId": tool_event.parent_action_id,
+          "provider": tool_event.provider_id,
           "inputPreview": input_preview,
           "observationPreview": observation_preview,
           "timestamp": Utc::now().timestamp_millis(),
@@ -2217,6 +3009,47 @@ pub fn chat_generate_stream(
                   }
                 }
               }
+              "fs_read_lines" => {
+                if let Some(path) = tool_event.args.get("path").and_then(|v| v.as_str()) {
+                  obj.insert("readPath".to_string(), serde_json::json!(path));
+                }
+                if let Some(lines) = observation.get("total_lines") {
+                  obj.insert("readLines".to_string(), lines.clone());
+                }
+                if let Some(chars) = observation.get("total_chars") {
+                  obj.insert("readChars".to_string(), chars.clone());
+                }
+                if let Some(text) = observation.get("lines").and_then(|v| v.as_str()) {
+                  obj.insert("readPreview".to_string(), serde_json::json!(compact_multiline_preview(text, 8, 420)));
+                }
+              }
+              "fs_patch_text" => {
+                if let Some(path) = tool_event.args.get("path").and_then(|v| v.as_str()) {
+                  obj.insert("writePath".to_string(), serde_json::json!(path));
+                }
+                if let Some(op) = tool_event.args.get("op").and_then(|v| v.as_str()) {
+                  obj.insert("patchOp".to_string(), serde_json::json!(op));
+                }
+              }
+              "fs_search_text" => {
+                if let Some(hits) = observation.get("hits").and_then(|v| v.as_array()) {
+                  obj.insert("searchHits".to_string(), serde_json::json!(hits.len()));
+                }
+                if let Some(truncated) = observation.get("truncated").and_then(|v| v.as_bool()) {
+                  obj.insert("searchTruncated".to_string(), serde_json::json!(truncated));
+                }
+              }
+              "delegate" => {
+                if let Some(agent_id) = observation.get("agent_id").and_then(|v| v.as_str()) {
+                  obj.insert("delegateAgent".to_string(), serde_json::json!(agent_id));
+                }
+                if let Some(answer) = observation.get("answer").and_then(|v| v.as_str()) {
+                  obj.insert(
+                    "delegateAnswerPreview".to_string(),
+                    serde_json::json!(compact_multiline_preview(answer, 8, 420)),
+                  );
+                }
+              }
               "fs_read_text" => {
                 if let Some(path) = tool_event.args.get("path").and_then(|v| v.as_str()) {
                   obj.insert("readPath".to_string(), serde_json::json!(path));
@@ -2253,12 +3086,67 @@ pub fn chat_generate_stream(
           }
         }
         let _ = window_for_task.emit("ai_agent_step", payload);
+      }, |request| {
+        let app = app.clone();
+        let window = window_for_task.clone();
+        let stream_id = stream_id_for_task.clone();
+        let deadline = deadline.clone();
+        async move {
+          let approval_id = format!("{stream_id}:{}", request.action_id);
+          let (responder, response) = tokio::sync::oneshot::channel();
+          {
+            let app_state = app.state::<AppState>();
+            let Ok(mut approvals) = app_state.tool_approvals.lock() else {
+              return false;
+            };
+            approvals.insert(
+              approval_id.clone(),
+              PendingToolApproval {
+                stream_id: stream_id.clone(),
+                responder,
+              },
+            );
+          }
+          let _ = window.emit(
+            "ai_tool_approval_request",
+            serde_json::json!({
+              "streamId": stream_id,
+              "approvalId": approval_id,
+              "actionId": request.action_id,
+              "step": request.step,
+              "tool": request.tool,
+              "args": request.args,
+              "inputPreview": compact_value_preview(&request.args, 180),
+            }),
+          );
+          emit_stream_status(&window, &stream_id, "awaiting_approval");
+          let _paused = deadline.pause();
+          let approved = response.await.unwrap_or(false);
+          emit_stream_status(&window, &stream_id, "thinking");
+          approved
+        }
       }),
     )
     .await;
+    let checkpoint_agent_id = agent.map(|a| a.id.clone()).unwrap_or_default();
     let (mut response, perf) = match run_result {
-      Ok(v) => match v {
+      Some(v) => match v {
         Ok(v) => v,
+        Err(e) if e == agent_system::RUN_CANCELLED => {
+          record_finish(false, Some(e), serde_json::Value::Null);
+          save_interrupted_run(
+            &window_for_task,
+            &workspace_root_clone,
+            &stream_id_for_task,
+            &checkpoint_agent_id,
+            &current_provider.id,
+            "cancelled",
+            runtime.checkpoint(),
+          );
+          // chat_cancel_stream already emitted ai_stream_done.
+          clear_stream_task(&app_for_task, &stream_id_for_task);
+          return;
+        }
         Err(e) => {
           eprintln!("ai_error provider={} err={}", current_provider.id, e);
           let stage = if e.contains("api key")
@@ -2271,6 +3159,7 @@ pub fn chat_generate_stream(
           } else {
             "agent"
           };
+          record_finish(false, Some(e.clone()), serde_json::Value::Null);
           let payload = serde_json::json!({
             "streamId": stream_id_for_task,
             "provider": current_provider.id,
@@ -2283,12 +3172,23 @@ pub fn chat_generate_stream(
           return;
         }
       },
-      Err(_) => {
+      None => {
+        let message = format!("AI runtime timed out after {} seconds", deadline.limit().as_secs());
+        record_finish(false, Some(message.clone()), serde_json::Value::Null);
+        save_interrupted_run(
+          &window_for_task,
+          &workspace_root_clone,
+          &stream_id_for_task,
+          &checkpoint_agent_id,
+          &current_provider.id,
+          "timeout",
+          runtime.checkpoint(),
+        );
         let payload = serde_json::json!({
           "streamId": stream_id_for_task,
           "provider": current_provider.id,
           "stage": "timeout",
-          "message": format!("AI runtime timed out after {} seconds", react_timeout.as_secs())
+          "message": message
         });
         let _ = window_for_task.emit("ai_error", payload);
         clear_stream_task(&app_for_task, &stream_id_for_task);
@@ -2296,35 +3196,58 @@ pub fn chat_generate_stream(
         return;
       }
     };
-    let _ = window_for_task.emit(
-      "ai_perf",
-      serde_json::json!({
-        "streamId": stream_id_for_task,
-        "elapsed_ms": start.elapsed().as_millis(),
-        "steps": perf.steps,
-        "model_ms": perf.model_ms,
-        "tool_ms": perf.tool_ms
-      }),
-    );
+    let perf_payload = serde_json::json!({
+      "streamId": stream_id_for_task,
+      "elapsed_ms": start.elapsed().as_millis(),
+      "steps": perf.steps,
+      "tool_calls": perf.tool_calls,
+      "model_ms": perf.model_ms,
+      "tool_ms": perf.tool_ms,
+      "context_budget": perf.context_budget,
+      "peak_prompt_tokens": perf.peak_prompt_tokens,
+      "compacted_messages": perf.compacted_messages,
+      "compacted_tokens": perf.compacted_tokens,
+      "stop_reason": perf.stop_reason,
+      "max_steps": max_steps,
+      "input_tokens": perf.input_tokens,
+      "output_tokens": perf.output_tokens,
+      "step_providers": perf.step_providers,
+      "stream_usage": stream_usage.lock().map(|t| t.clone()).unwrap_or_default(),
+    });
+    record_finish(true, None, perf_payload.clone());
+    let _ = window_for_task.emit("ai_perf", perf_payload);
 
     if !effective_use_markdown {
       response = normalize_plaintext(&response);
     }
 
     if ai_edit_apply_mode == app_settings::AiEditApplyMode::Review {
-      match crate::ai_response_parser::parse_ai_response(&response, &workspace_root_clone) {
-        Ok(Some(cs)) => {
-          let payload = serde_json::json!({
-            "streamId": stream_id_for_task,
-            "changeSet": cs
-          });
-          let _ = window_for_task.emit("ai_change_set", payload);
-        }
-        Ok(None) => {}
+      let parsed = match crate::ai_response_parser::parse_ai_response(&response, &workspace_root_clone) {
+        Ok(cs) => cs,
         Err(e) => {
           eprintln!("Failed to parse AI response for modifications: {}", e);
+          None
+        }
+      };
+      // Tool edits recorded by the dry run win over <file_edit> blocks for the same file.
+      let change_set = match (runtime.overlay_change_set(), parsed) {
+        (Some(mut overlay), Some(parsed)) => {
+          for file in parsed.files {
+            if !overlay.files.iter().any(|f| f.file_path == file.file_path) {
+              overlay.files.push(file);
+            }
+          }
+          Some(overlay)
         }
+        (overlay, parsed) => overlay.or(parsed),
       };
+      if let Some(cs) = change_set {
+        let payload = serde_json::json!({
+          "streamId": stream_id_for_task,
+          "changeSet": cs
+        });
+        let _ = window_for_task.emit("ai_change_set", payload);
+      }
     }
 
     if !live_session.has_emitted() {
@@ -2358,10 +3281,13 @@ pub fn chat_generate_stream(
       .ai_stream_tasks
       .lock()
       .map_err(|_| "stream tasks lock poisoned".to_string())?;
-    if let Some(prev) = tasks.insert(stream_id, task) {
+    if let Some(prev) = tasks.insert(stream_id.clone(), task) {
       prev.abort();
     }
   }
+  if let Ok(mut cancels) = state.ai_stream_cancels.lock() {
+    cancels.insert(stream_id, cancel);
+  }
 
   Ok(())
 }
@@ -2403,6 +3329,714 @@ fn append_chunk_with_overlap(full_text: &mut String, chunk: &str) -> (usize, Str
   (overlap, suffix)
 }
 
+struct PendingToolCall {
+  index: u64,
+  id: String,
+  name: String,
+  arguments: String,
+}
+
+fn parse_tool_arguments(raw: &str) -> serde_json::Value {
+  if raw.trim().is_empty() {
+    return serde_json::json!({});
+  }
+  serde_json::from_str(raw).unwrap_or_else(|_| serde_json::json!({ "raw": raw }))
+}
+
+fn finish_pending_tool_calls(mut pending: Vec<PendingToolCall>) -> Vec<ToolCall> {
+  pending.sort_by_key(|c| c.index);
+  pending
+    .into_iter()
+    .filter(|c| !c.name.trim().is_empty())
+    .map(|c| ToolCall {
+      id: if c.id.is_empty() { format!("call-{}", c.index) } else { c.id },
+      name: c.name,
+      arguments: parse_tool_arguments(&c.arguments),
+    })
+    .collect()
+}
+
+fn collect_openai_tool_call_deltas(delta: &serde_json::Value, pending: &mut Vec<PendingToolCall>) {
+  let Some(items) = delta["tool_calls"].as_array() else {
+    return;
+  };
+  for item in items {
+    let index = item["index"].as_u64().unwrap_or_default();
+    let pos = match pending.iter().position(|c| c.index == index) {
+      Some(pos) => pos,
+      None => {
+        pending.push(PendingToolCall {
+          index,
+          id: String::new(),
+          name: String::new(),
+          arguments: String::new(),
+        });
+        pending.len() - 1
+      }
+    };
+    let call = &mut pending[pos];
+    if let Some(id) = item["id"].as_str() {
+      call.id = id.to_string();
+    }
+    if let Some(name) = item["function"]["name"].as_str() {
+      call.name.push_str(name);
+    }
+    if let Some(args) = item["function"]["arguments"].as_str() {
+      call.arguments.push_str(args);
+    }
+  }
+}
+
+fn openai_usage(usage: &serde_json::Value) -> Option<TokenUsage> {
+  Some(TokenUsage {
+    input_tokens: usage["prompt_tokens"].as_u64()?,
+    output_tokens: usage["completion_tokens"].as_u64().unwrap_or(0),
+  })
+}
+
+/// Anthropic reports prompt-cache reads and writes apart from `input_tokens`.
+fn anthropic_input_tokens(usage: &serde_json::Value) -> u64 {
+  ["input_tokens", "cache_creation_input_tokens", "cache_read_input_tokens"]
+    .iter()
+    .filter_map(|key| usage[*key].as_u64())
+    .sum()
+}
+
+/// Thinking tokens are billed as output.
+fn gemini_usage(usage: &serde_json::Value) -> Option<TokenUsage> {
+  Some(TokenUsage {
+    input_tokens: usage["promptTokenCount"].as_u64()?,
+    output_tokens: usage["candidatesTokenCount"].as_u64().unwrap_or(0)
+      + usage["thoughtsTokenCount"].as_u64().unwrap_or(0),
+  })
+}
+
+fn parse_openai_tool_calls(message: &serde_json::Value) -> Vec<ToolCall> {
+  message["tool_calls"]
+    .as_array()
+    .map(|items| {
+      items
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, item)| {
+          let name = item["function"]["name"].as_str()?.to_string();
+          let arguments = match &item["function"]["arguments"] {
+            serde_json::Value::String(raw) => parse_tool_arguments(raw),
+            other if other.is_object() => other.clone(),
+            _ => serde_json::json!({}),
+          };
+          Some(ToolCall {
+            id: item["id"].as_str().map(str::to_string).unwrap_or_else(|| format!("call-{idx}")),
+            name,
+            arguments,
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn parse_anthropic_tool_uses(content: &serde_json::Value) -> Vec<ToolCall> {
+  content
+    .as_array()
+    .map(|parts| {
+      parts
+        .iter()
+        .filter(|part| part["type"].as_str() == Some("tool_use"))
+        .filter_map(|part| {
+          Some(ToolCall {
+            id: part["id"].as_str()?.to_string(),
+            name: part["name"].as_str()?.to_string(),
+            arguments: part["input"].clone(),
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn openai_tool_json(spec: &ToolSpec) -> serde_json::Value {
+  serde_json::json!({
+    "type": "function",
+    "function": {
+      "name": spec.name,
+      "description": spec.description,
+      "parameters": spec.parameters
+    }
+  })
+}
+
+fn anthropic_tool_json(spec: &ToolSpec) -> serde_json::Value {
+  serde_json::json!({
+    "name": spec.name,
+    "description": spec.description,
+    "input_schema": spec.parameters
+  })
+}
+
+fn openai_message_json(m: &ChatMessage) -> serde_json::Value {
+  if m.role == "tool" {
+    return serde_json::json!({
+      "role": "tool",
+      "tool_call_id": m.tool_call_id,
+      "content": m.content
+    });
+  }
+  if !m.tool_calls.is_empty() {
+    let calls = m
+      .tool_calls
+      .iter()
+      .map(|c| {
+        serde_json::json!({
+          "id": c.id,
+          "type": "function",
+          "function": { "name": c.name, "arguments": c.arguments.to_string() }
+        })
+      })
+      .collect::<Vec<_>>();
+    let content = if m.content.is_empty() {
+      serde_json::Value::Null
+    } else {
+      serde_json::json!(m.content)
+    };
+    return serde_json::json!({ "role": m.role, "content": content, "tool_calls": calls });
+  }
+  serde_json::json!({"role": m.role, "content": m.content})
+}
+
+/// Anthropic carries tool results inside user turns, so consecutive `tool`
+/// messages are folded into a single user message of `tool_result` blocks.
+fn anthropic_messages_json(messages: &[ChatMessage]) -> Vec<serde_json::Value> {
+  let mut out: Vec<serde_json::Value> = Vec::new();
+  for m in messages {
+    if m.role == "tool" {
+      let block = serde_json::json!({
+        "type": "tool_result",
+        "tool_use_id": m.tool_call_id,
+        "content": m.content
+      });
+      let merged = out.last_mut().and_then(|last| {
+        let is_results = last["role"] == "user"
+          && last["content"]
+            .as_array()
+            .map(|parts| parts.iter().all(|p| p["type"] == "tool_result"))
+            .unwrap_or(false);
+        if is_results {
+          last["content"].as_array_mut()
+        } else {
+          None
+        }
+      });
+      match merged {
+        Some(parts) => parts.push(block),
+        None => out.push(serde_json::json!({ "role": "user", "content": [block] })),
+      }
+      continue;
+    }
+    if !m.tool_calls.is_empty() {
+      let mut parts: Vec<serde_json::Value> = Vec::new();
+      if !m.content.trim().is_empty() {
+        parts.push(serde_json::json!({ "type": "text", "text": m.content }));
+      }
+      for c in &m.tool_calls {
+        parts.push(serde_json::json!({
+          "type": "tool_use",
+          "id": c.id,
+          "name": c.name,
+          "input": c.arguments
+        }));
+      }
+      out.push(serde_json::json!({ "role": m.role, "content": parts }));
+      continue;
+    }
+    out.push(serde_json::json!({"role": m.role, "content": m.content}));
+  }
+  out
+}
+
+/// Schema keywords Gemini's function declarations accept (an OpenAPI subset);
+/// anything else, such as `additionalProperties`, is rejected by the API.
+const GEMINI_SCHEMA_KEYS: &[&str] = &[
+  "type",
+  "format",
+  "description",
+  "nullable",
+  "enum",
+  "items",
+  "properties",
+  "required",
+  "minItems",
+  "maxItems",
+  "minLength",
+  "maxLength",
+  "pattern",
+  "minimum",
+  "maximum",
+  "anyOf",
+];
+
+fn gemini_schema(schema: &serde_json::Value) -> serde_json::Value {
+  let Some(obj) = schema.as_object() else {
+    return schema.clone();
+  };
+  let mut out = serde_json::Map::new();
+  for (key, value) in obj {
+    if !GEMINI_SCHEMA_KEYS.contains(&key.as_str()) {
+      continue;
+    }
+    let value = match key.as_str() {
+      "properties" => value
+        .as_object()
+        .map(|props| {
+          serde_json::Value::Object(
+            props
+              .iter()
+              .map(|(name, prop)| (name.clone(), gemini_schema(prop)))
+              .collect(),
+          )
+        })
+        .unwrap_or_else(|| value.clone()),
+      "items" => gemini_schema(value),
+      "anyOf" => value
+        .as_array()
+        .map(|items| serde_json::Value::Array(items.iter().map(gemini_schema).collect()))
+        .unwrap_or_else(|| value.clone()),
+      _ => value.clone(),
+    };
+    out.insert(key.clone(), value);
+  }
+  serde_json::Value::Object(out)
+}
+
+fn gemini_tool_json(spec: &ToolSpec) -> serde_json::Value {
+  serde_json::json!({
+    "name": spec.name,
+    "description": spec.description,
+    "parameters": gemini_schema(&spec.parameters)
+  })
+}
+
+/// Gemini names the assistant role `model` and answers tool calls with
+/// `functionResponse` parts in a user turn; consecutive tool results share
+/// one turn, as with Anthropic.
+fn gemini_contents_json(messages: &[ChatMessage]) -> Vec<serde_json::Value> {
+  let mut out: Vec<serde_json::Value> = Vec::new();
+  for m in messages {
+    if m.role == "tool" {
+      let response = match serde_json::from_str::<serde_json::Value>(&m.content) {
+        Ok(v @ serde_json::Value::Object(_)) => v,
+        Ok(v) => serde_json::json!({ "content": v }),
+        Err(_) => serde_json::json!({ "content": m.content }),
+      };
+      // The id pairs results with calls when one step calls the same tool twice.
+      let part = serde_json::json!({
+        "functionResponse": {
+          "id": m.tool_call_id,
+          "name": m.name.clone().unwrap_or_default(),
+          "response": response
+        }
+      });
+      let merged = out.last_mut().and_then(|last| {
+        let is_results = last["role"] == "user"
+          && last["parts"]
+            .as_array()
+            .map(|parts| parts.iter().all(|p| p.get("functionResponse").is_some()))
+            .unwrap_or(false);
+        if is_results {
+          last["parts"].as_array_mut()
+        } else {
+          None
+        }
+      });
+      match merged {
+        Some(parts) => parts.push(part),
+        None => out.push(serde_json::json!({ "role": "user", "parts": [part] })),
+      }
+      continue;
+    }
+    let role = if m.role == "assistant" { "model" } else { "user" };
+    let mut parts: Vec<serde_json::Value> = Vec::new();
+    if !m.content.is_empty() || m.tool_calls.is_empty() {
+      parts.push(serde_json::json!({ "text": m.content }));
+    }
+    for c in &m.tool_calls {
+      parts.push(serde_json::json!({
+        "functionCall": { "id": c.id, "name": c.name, "args": c.arguments }
+      }));
+    }
+    out.push(serde_json::json!({ "role": role, "parts": parts }));
+  }
+  out
+}
+
+/// Collects the visible text and function calls of `candidates[0]`; thought
+/// summaries of thinking models are skipped.
+fn collect_gemini_parts(value: &serde_json::Value, text: &mut String, calls: &mut Vec<ToolCall>) {
+  let Some(parts) = value["candidates"][0]["content"]["parts"].as_array() else {
+    return;
+  };
+  for part in parts {
+    if part["thought"].as_bool() == Some(true) {
+      continue;
+    }
+    if let Some(t) = part["text"].as_str() {
+      text.push_str(t);
+    }
+    if let Some(name) = part["functionCall"]["name"].as_str() {
+      let id = part["functionCall"]["id"]
+        .as_str()
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| format!("call_{}", uuid::Uuid::new_v4().simple()));
+      let args = match &part["functionCall"]["args"] {
+        serde_json::Value::Null => serde_json::json!({}),
+        v => v.clone(),
+      };
+      calls.push(ToolCall { id, name: name.to_string(), arguments: args });
+    }
+  }
+}
+
+/// Why Gemini refused to answer, if it did: a blocked prompt or a candidate
+/// stopped by its safety or recitation filters.
+fn gemini_block_reason(value: &serde_json::Value) -> Option<String> {
+  if let Some(reason) = value["promptFeedback"]["blockReason"].as_str() {
+    return Some(reason.to_string());
+  }
+  match value["candidates"][0]["finishReason"].as_str() {
+    Some(reason @ ("SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII")) => {
+      Some(reason.to_string())
+    }
+    _ => None,
+  }
+}
+
+fn gemini_model_endpoint(cfg: &app_settings::ModelProvider) -> String {
+  let base = cfg.base_url.trim().trim_end_matches('/');
+  let base = if base.is_empty() {
+    "https://generativelanguage.googleapis.com/v1beta"
+  } else {
+    base
+  };
+  let model = cfg.model_name.trim();
+  let model = model.strip_prefix("models/").unwrap_or(model);
+  format!("{base}/models/{model}")
+}
+
+/// Gemini's default filters stop on fairly mild violence; fiction needs
+/// more room, so only high-probability harm is blocked.
+fn gemini_safety_settings() -> serde_json::Value {
+  let categories = [
+    "HARM_CATEGORY_HARASSMENT",
+    "HARM_CATEGORY_HATE_SPEECH",
+    "HARM_CATEGORY_SEXUALLY_EXPLICIT",
+    "HARM_CATEGORY_DANGEROUS_CONTENT",
+  ];
+  serde_json::Value::Array(
+    categories
+      .iter()
+      .map(|c| serde_json::json!({ "category": c, "threshold": "BLOCK_ONLY_HIGH" }))
+      .collect(),
+  )
+}
+
+/// Ollama takes OpenAI-style tool declarations but structured arguments and
+/// `tool_name` instead of call ids on tool results.
+fn ollama_message_json(m: &ChatMessage) -> serde_json::Value {
+  if m.role == "tool" {
+    return serde_json::json!({
+      "role": "tool",
+      "tool_name": m.name.clone().unwrap_or_default(),
+      "content": m.content
+    });
+  }
+  if !m.tool_calls.is_empty() {
+    let calls = m
+      .tool_calls
+      .iter()
+      .map(|c| serde_json::json!({ "function": { "name": c.name, "arguments": c.arguments } }))
+      .collect::<Vec<_>>();
+    return serde_json::json!({ "role": m.role, "content": m.content, "tool_calls": calls });
+  }
+  serde_json::json!({"role": m.role, "content": m.content})
+}
+
+fn parse_ollama_tool_calls(message: &serde_json::Value) -> Vec<ToolCall> {
+  let Some(calls) = message["tool_calls"].as_array() else {
+    return Vec::new();
+  };
+  calls
+    .iter()
+    .filter_map(|call| {
+      let name = call["function"]["name"].as_str()?.to_string();
+      let arguments = match &call["function"]["arguments"] {
+        serde_json::Value::String(raw) => parse_tool_arguments(raw),
+        serde_json::Value::Null => serde_json::json!({}),
+        v => v.clone(),
+      };
+      let id = call["id"]
+        .as_str()
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| format!("call_{}", uuid::Uuid::new_v4().simple()));
+      Some(ToolCall { id, name, arguments })
+    })
+    .collect()
+}
+
+fn ollama_base_url(cfg: &app_settings::ModelProvider) -> String {
+  let base = cfg.base_url.trim().trim_end_matches('/');
+  // Accept the OpenAI-compatible URL (`.../v1`) that older presets used.
+  let base = base.strip_suffix("/v1").unwrap_or(base);
+  if base.is_empty() {
+    "http://localhost:11434".to_string()
+  } else {
+    base.to_string()
+  }
+}
+
+fn current_workspace_root(app: &AppHandle) -> Option<PathBuf> {
+  app.state::<AppState>().workspace_root.lock().ok().and_then(|root| root.clone())
+}
+
+/// Status and body of one provider request, independent of the transport.
+struct ProviderResponse {
+  status: reqwest::StatusCode,
+  /// Wait the provider asked for via `Retry-After` (or `retry-after-ms`).
+  retry_after: Option<Duration>,
+  body: futures_util::stream::BoxStream<'static, Result<Vec<u8>, String>>,
+}
+
+impl ProviderResponse {
+  async fn text(mut self) -> Result<String, String> {
+    let mut raw = Vec::new();
+    while let Some(chunk) = self.body.next().await {
+      raw.extend(chunk.map_err(|e| format!("decode failed: {e}"))?);
+    }
+    Ok(String::from_utf8_lossy(&raw).to_string())
+  }
+
+  async fn json(self) -> Result<serde_json::Value, String> {
+    let raw = self.text().await?;
+    serde_json::from_str(&raw).map_err(|e| format!("decode failed: {e}"))
+  }
+}
+
+/// A send that failed before any response arrived.
+struct SendFailure {
+  message: String,
+  retryable: bool,
+}
+
+async fn send_http(req: reqwest::RequestBuilder) -> Result<ProviderResponse, SendFailure> {
+  let resp = req.send().await.map_err(|e| SendFailure {
+    retryable: e.is_timeout() || e.is_connect(),
+    message: format!("request failed: {e}"),
+  })?;
+  let header = |name: &str| resp.headers().get(name).and_then(|v| v.to_str().ok());
+  let retry_after = header("retry-after-ms")
+    .and_then(|ms| ms.trim().parse::<u64>().ok())
+    .map(Duration::from_millis)
+    .or_else(|| header("retry-after").and_then(|v| provider_retry::parse_retry_after(v, chrono::Utc::now())));
+  Ok(ProviderResponse {
+    status: resp.status(),
+    retry_after,
+    body: resp
+      .bytes_stream()
+      .map(|item| item.map(|b| b.to_vec()).map_err(|e| e.to_string()))
+      .boxed(),
+  })
+}
+
+fn provider_max_attempts(app: &AppHandle) -> u32 {
+  app_settings::load(app)
+    .map(|s| s.provider_max_attempts)
+    .unwrap_or(provider_retry::DEFAULT_MAX_ATTEMPTS)
+    .max(1)
+}
+
+/// Repeats `send` while it fails with a connect error, a timeout or a
+/// retryable status, up to `max_attempts` in total. `on_retry` gets the
+/// upcoming attempt number before each wait.
+async fn send_with_retry<Fut>(
+  max_attempts: u32,
+  on_retry: impl Fn(u32, u32),
+  send: impl Fn() -> Fut,
+) -> Result<ProviderResponse, String>
+where
+  Fut: std::future::Future<Output = Result<ProviderResponse, SendFailure>>,
+{
+  let max_attempts = max_attempts.max(1);
+  let mut attempt = 1;
+  loop {
+    let (reason, retry_after) = match send().await {
+      Ok(resp) if attempt < max_attempts && provider_retry::is_retryable_status(resp.status.as_u16()) => {
+        (format!("http {}", resp.status.as_u16()), resp.retry_after)
+      }
+      Ok(resp) => return Ok(resp),
+      Err(failure) if failure.retryable && attempt < max_attempts => (failure.message, None),
+      Err(failure) => return Err(failure.message),
+    };
+    let delay = retry_after
+      .unwrap_or_else(|| provider_retry::backoff_delay(attempt, provider_retry::jitter()))
+      .min(provider_retry::MAX_DELAY);
+    attempt += 1;
+    eprintln!(
+      "provider request failed ({reason}); retry {attempt}/{max_attempts} in {}ms",
+      delay.as_millis()
+    );
+    on_retry(attempt, max_attempts);
+    tokio::time::sleep(delay).await;
+  }
+}
+
+/// Prompt budget of an agent run: its window less a quarter (at most 32k
+/// tokens) kept for the reply. The runtime compacts the prompt before it is
+/// known which provider of the fallback chain answers, so the smallest window
+/// of the chain sets it.
+fn run_context_budget(chain: &[app_settings::ModelProvider]) -> usize {
+  let window = chain.iter().map(|p| p.context_window_tokens() as usize).min().unwrap_or(0);
+  window - (window / 4).min(32_000)
+}
+
+/// Calls `chain[active]` and moves down the chain while a call fails with an
+/// error `is_fallback_error` accepts; `active` keeps the provider that
+/// answered for the next step.
+async fn call_with_fallback<'a, F, Fut>(
+  chain: &'a [app_settings::ModelProvider],
+  active: &AtomicUsize,
+  call: F,
+) -> Result<(ModelTurn, &'a app_settings::ModelProvider), String>
+where
+  F: Fn(&'a app_settings::ModelProvider) -> Fut,
+  Fut: std::future::Future<Output = Result<ModelTurn, String>>,
+{
+  let mut index = active.load(Ordering::Relaxed).min(chain.len().saturating_sub(1));
+  loop {
+    let provider_cfg = chain.get(index).ok_or_else(|| "no provider configured".to_string())?;
+    match call(provider_cfg).await {
+      Ok(turn) => return Ok((turn, provider_cfg)),
+      Err(e) if index + 1 < chain.len() && provider_retry::is_fallback_error(&e) => {
+        index += 1;
+        eprintln!("provider {} failed ({e}); falling back to {}", provider_cfg.id, chain[index].id);
+        active.store(index, Ordering::Relaxed);
+      }
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+/// Output tokens a reply has used over its rounds: as billed, or estimated
+/// from its text when the provider reports no usage.
+fn reply_output_tokens(usage: TokenUsage, text: &str) -> u64 {
+  if usage.output_tokens > 0 {
+    usage.output_tokens
+  } else {
+    crate::context_budget::estimate_tokens(text) as u64
+  }
+}
+
+/// The next reply of a scripted provider's fixture, streamed chunk by chunk.
+fn scripted_response(path: &Path, body: &serde_json::Value) -> Result<ProviderResponse, String> {
+  let reply = scripted_provider::respond(path, body)?;
+  let status = reqwest::StatusCode::from_u16(reply.status).map_err(|e| format!("scripted status invalid: {e}"))?;
+  Ok(ProviderResponse {
+    status,
+    retry_after: None,
+    body: futures_util::stream::iter(reply.chunks.into_iter().map(Ok)).boxed(),
+  })
+}
+
+/// Sends a provider request built by `build`, retrying transient failures;
+/// scripted providers answer from their fixture instead and never touch the
+/// network. Retries show up on the live stream as `retrying (n/max)`.
+async fn send_provider_request(
+  app: &AppHandle,
+  cfg: &app_settings::ModelProvider,
+  body: &serde_json::Value,
+  live_stream: Option<&LiveStreamSession>,
+  build: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<ProviderResponse, String> {
+  let retried = AtomicBool::new(false);
+  let on_retry = |attempt: u32, max: u32| {
+    retried.store(true, Ordering::Relaxed);
+    if let Some(live) = live_stream {
+      emit_stream_status(&live.window, &live.stream_id, &format!("retrying ({attempt}/{max})"));
+    }
+  };
+  let max_attempts = provider_max_attempts(app);
+  let resp = if cfg.kind == app_settings::ProviderKind::Scripted {
+    let path = scripted_provider::resolve_fixture_path(&cfg.base_url, current_workspace_root(app).as_deref());
+    send_with_retry(max_attempts, on_retry, || {
+      let reply = scripted_response(&path, body);
+      std::future::ready(reply.map_err(|message| SendFailure { message, retryable: false }))
+    })
+    .await?
+  } else {
+    send_with_retry(max_attempts, on_retry, || send_http(build())).await?
+  };
+  if retried.load(Ordering::Relaxed) {
+    if let Some(live) = live_stream {
+      emit_stream_status(&live.window, &live.stream_id, "thinking");
+    }
+  }
+  Ok(resp)
+}
+
+/// Logs the sampling parameters sent to `cfg` and the ones its API has no
+/// field for.
+fn log_generation_params(cfg: &app_settings::ModelProvider, temperature: Option<f32>, params: &TranslatedParams) {
+  eprintln!(
+    "generation params provider={} model={} temperature={:?} fields={} dropped={:?}",
+    cfg.id,
+    cfg.model_name,
+    temperature,
+    serde_json::Value::Object(params.fields.clone()),
+    params.dropped
+  );
+}
+
+/// Sends one model turn to `cfg` through the client of its kind, with each
+/// request's `max_tokens` capped at the output limit of the cached model
+/// catalog.
+#[allow(clippy::too_many_arguments)]
+async fn call_provider_turn(
+  app: &AppHandle,
+  client: &reqwest::Client,
+  cfg: &app_settings::ModelProvider,
+  messages: &[ChatMessage],
+  system_prompt: &str,
+  temperature: Option<f32>,
+  tools: &[ToolSpec],
+  live_stream: Option<&LiveStreamSession>,
+) -> Result<ModelTurn, String> {
+  let clamped;
+  let cfg = match (cfg.generation.max_tokens, catalog_max_output_tokens(app, cfg)) {
+    (Some(requested), Some(limit)) if requested > limit => {
+      let mut capped = cfg.clone();
+      capped.generation.output_limit = Some(limit);
+      clamped = capped;
+      &clamped
+    }
+    _ => cfg,
+  };
+  match cfg.kind {
+    app_settings::ProviderKind::OpenAI
+    | app_settings::ProviderKind::OpenAICompatible
+    | app_settings::ProviderKind::Scripted => {
+      call_openai_unbounded(app, client, cfg, messages, system_prompt, temperature, tools, live_stream).await
+    }
+    app_settings::ProviderKind::Anthropic => {
+      call_anthropic_unbounded(app, client, cfg, messages, system_prompt, temperature, tools, live_stream).await
+    }
+    app_settings::ProviderKind::Gemini => {
+      call_gemini_unbounded(app, client, cfg, messages, system_prompt, temperature, tools, live_stream).await
+    }
+    app_settings::ProviderKind::Ollama => {
+      call_ollama_unbounded(app, client, cfg, messages, system_prompt, temperature, tools, live_stream).await
+    }
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn call_openai_unbounded(
   app: &AppHandle,
   client: &reqwest::Client,
@@ -2410,20 +4044,47 @@ async fn call_openai_unbounded(
   messages: &[ChatMessage],
   system_prompt: &str,
   temperature_override: Option<f32>,
+  tools: &[ToolSpec],
   live_stream: Option<&LiveStreamSession>,
-) -> Result<String, String> {
+) -> Result<ModelTurn, String> {
   let api_key = match secrets::get_api_key(app, &cfg.id) {
     Ok(Some(v)) => v,
     Ok(None) => cfg.api_key.trim().to_string(),
     Err(e) => return Err(format!("keyring read failed: {e}")),
   };
-  if api_key.trim().is_empty() {
+  if api_key.trim().is_empty() && !provider_is_keyless(cfg) {
     return Err(format!("api key not found for provider={}", cfg.id));
   }
 
   let base = cfg.base_url.trim_end_matches('/');
   let url = format!("{base}/chat/completions");
+  let (url, api_key) = (url.as_str(), api_key.trim());
+  let send = |body: serde_json::Value| async move {
+    send_provider_request(app, cfg, &body, live_stream, || client.post(url).bearer_auth(api_key).json(&body)).await
+  };
+  openai_turn(cfg, messages, system_prompt, temperature_override, tools, live_stream, send).await
+}
+
+/// One OpenAI-style model turn; `send` delivers a request body and returns
+/// the raw response. Replies cut off at `length` are continued, and a
+/// rejected `max_tokens` or unsupported streaming is retried without it.
+#[allow(clippy::too_many_arguments)]
+async fn openai_turn<S, SendFut>(
+  cfg: &app_settings::ModelProvider,
+  messages: &[ChatMessage],
+  system_prompt: &str,
+  temperature_override: Option<f32>,
+  tools: &[ToolSpec],
+  live_stream: Option<&LiveStreamSession>,
+  send: S,
+) -> Result<ModelTurn, String>
+where
+  S: Fn(serde_json::Value) -> SendFut,
+  SendFut: std::future::Future<Output = Result<ProviderResponse, String>>,
+{
   let temperature = temperature_override.unwrap_or(0.7);
+  let mut params = cfg.generation.openai();
+  log_generation_params(cfg, Some(temperature), &params);
   let mut out_messages: Vec<serde_json::Value> = Vec::new();
   if !system_prompt.trim().is_empty() {
     out_messages.push(serde_json::json!({
@@ -2431,11 +4092,8 @@ async fn call_openai_unbounded(
       "content": system_prompt
     }));
   }
-  out_messages.extend(
-    messages
-      .iter()
-      .map(|m| serde_json::json!({"role": m.role, "content": m.content})),
-  );
+  out_messages.extend(messages.iter().map(openai_message_json));
+  let tools_json = tools.iter().map(openai_tool_json).collect::<Vec<_>>();
 
   const MAX_CONTINUATIONS: usize = 64;
   const FALLBACK_CHUNK_MAX_TOKENS: u32 = 32000;
@@ -2443,11 +4101,13 @@ async fn call_openai_unbounded(
     "Continue from exactly where you stopped. Do not repeat prior text.";
 
   let mut full_text = String::new();
+  let mut tool_calls: Vec<ToolCall> = Vec::new();
   let mut gate = LiveEmitGate::new();
   let mut stream_supported = true;
+  let mut usage = TokenUsage::default();
   for round in 0..=MAX_CONTINUATIONS {
     let mut use_fallback_chunk_limit = false;
-    let (chunk, finish_reason, stream_applied): (String, Option<String>, bool) = loop {
+    let (chunk, finish_reason, stream_applied, round_calls): (String, Option<String>, bool, Vec<ToolCall>) = loop {
       if stream_supported {
         let mut body = serde_json::json!({
           "model": cfg.model_name,
@@ -2455,20 +4115,25 @@ async fn call_openai_unbounded(
           "temperature": temperature,
           "stream": true
         });
+        params.apply_to(&mut body);
+        if cfg.kind == app_settings::ProviderKind::OpenAI {
+          body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
         if use_fallback_chunk_limit {
           body["max_tokens"] = serde_json::json!(FALLBACK_CHUNK_MAX_TOKENS);
         }
-        let resp = client
-          .post(url.as_str())
-          .bearer_auth(api_key.trim())
-          .json(&body)
-          .send()
-          .await
-          .map_err(|e| format!("request failed: {e}"))?;
-        let status = resp.status();
+        if !tools_json.is_empty() {
+          body["tools"] = serde_json::json!(tools_json);
+        }
+        let resp = send(body).await?;
+        let status = resp.status;
         if !status.is_success() {
-          let raw = resp.text().await.map_err(|e| format!("decode failed: {e}"))?;
+          let raw = resp.text().await?;
           let lowered = raw.to_lowercase();
+          if status.is_client_error() && lowered.contains("max_tokens") && params.fields.remove("max_tokens").is_some() {
+            eprintln!("provider={} rejected max_tokens; retrying without it", cfg.id);
+            continue;
+          }
           let looks_like_missing_max_tokens = status.is_client_error()
             && !use_fallback_chunk_limit
             && lowered.contains("max_tokens");
@@ -2488,14 +4153,16 @@ async fn call_openai_unbounded(
           return Err(format!("http {status}: {raw}"));
         }
 
-        let mut sse_buf = String::new();
+        let mut sse_buf: Vec<u8> = Vec::new();
         let mut round_unique = String::new();
         let mut finish_reason: Option<String> = None;
-        let mut body_stream = resp.bytes_stream();
+        let mut pending_calls: Vec<PendingToolCall> = Vec::new();
+        let mut round_usage = TokenUsage::default();
+        let mut body_stream = resp.body;
         while let Some(item) = body_stream.next().await {
           let bytes = item.map_err(|e| format!("stream read failed: {e}"))?;
-          sse_buf.push_str(&String::from_utf8_lossy(&bytes));
-          while let Some(line) = sse_take_line(&mut sse_buf) {
+          sse_buf.extend_from_slice(&bytes);
+          while let Some(line) = take_byte_line(&mut sse_buf) {
             if let Some(data) = line.strip_prefix("data:") {
               let data = data.trim();
               if data.is_empty() || data == "[DONE]" {
@@ -2506,6 +4173,10 @@ async fn call_openai_unbounded(
               if let Some(reason) = value["choices"][0]["finish_reason"].as_str() {
                 finish_reason = Some(reason.to_string());
               }
+              if let Some(u) = openai_usage(&value["usage"]) {
+                round_usage = u;
+              }
+              collect_openai_tool_call_deltas(&value["choices"][0]["delta"], &mut pending_calls);
               if let Some(content) = value["choices"][0]["delta"]["content"].as_str() {
                 let (_, unique_piece) = append_chunk_with_overlap(&mut full_text, content);
                 if !unique_piece.is_empty() {
@@ -2516,8 +4187,9 @@ async fn call_openai_unbounded(
             }
           }
         }
-        if !sse_buf.trim().is_empty() {
-          let line = sse_buf.trim();
+        let rest = String::from_utf8_lossy(&sse_buf).into_owned();
+        if !rest.trim().is_empty() {
+          let line = rest.trim();
           if let Some(data) = line.strip_prefix("data:") {
             let data = data.trim();
             if !data.is_empty() && data != "[DONE]" {
@@ -2526,6 +4198,10 @@ async fn call_openai_unbounded(
               if let Some(reason) = value["choices"][0]["finish_reason"].as_str() {
                 finish_reason = Some(reason.to_string());
               }
+              if let Some(u) = openai_usage(&value["usage"]) {
+                round_usage = u;
+              }
+              collect_openai_tool_call_deltas(&value["choices"][0]["delta"], &mut pending_calls);
               if let Some(content) = value["choices"][0]["delta"]["content"].as_str() {
                 let (_, unique_piece) = append_chunk_with_overlap(&mut full_text, content);
                 if !unique_piece.is_empty() {
@@ -2536,11 +4212,12 @@ async fn call_openai_unbounded(
             }
           }
         }
-        if round_unique.is_empty() && finish_reason.is_none() {
+        usage.add(round_usage);
+        if round_unique.is_empty() && finish_reason.is_none() && pending_calls.is_empty() {
           stream_supported = false;
           continue;
         }
-        break (round_unique, finish_reason, true);
+        break (round_unique, finish_reason, true, finish_pending_tool_calls(pending_calls));
       } else {
         let mut body = serde_json::json!({
           "model": cfg.model_name,
@@ -2548,32 +4225,38 @@ async fn call_openai_unbounded(
           "temperature": temperature,
           "stream": false
         });
+        params.apply_to(&mut body);
         if use_fallback_chunk_limit {
           body["max_tokens"] = serde_json::json!(FALLBACK_CHUNK_MAX_TOKENS);
         }
+        if !tools_json.is_empty() {
+          body["tools"] = serde_json::json!(tools_json);
+        }
 
-        let resp = client
-          .post(url.as_str())
-          .bearer_auth(api_key.trim())
-          .json(&body)
-          .send()
-          .await
-          .map_err(|e| format!("request failed: {e}"))?;
+        let resp = send(body).await?;
 
-        let status = resp.status();
-        let value: serde_json::Value = resp.json().await.map_err(|e| format!("decode failed: {e}"))?;
+        let status = resp.status;
+        let value: serde_json::Value = resp.json().await?;
         if status.is_success() {
-          let chunk = value["choices"][0]["message"]["content"]
-            .as_str()
-            .map(|s| s.to_string())
-            .ok_or_else(|| "missing choices[0].message.content".to_string())?;
+          if let Some(u) = openai_usage(&value["usage"]) {
+            usage.add(u);
+          }
+          let round_calls = parse_openai_tool_calls(&value["choices"][0]["message"]);
+          let chunk = match value["choices"][0]["message"]["content"].as_str() {
+            Some(s) => s.to_string(),
+            None if !round_calls.is_empty() => String::new(),
+            None => return Err("missing choices[0].message.content".to_string()),
+          };
           let finish_reason = value["choices"][0]["finish_reason"].as_str().map(|s| s.to_string());
-          break (chunk, finish_reason, false);
+          break (chunk, finish_reason, false, round_calls);
         }
 
-        let looks_like_missing_max_tokens = status.is_client_error()
-          && !use_fallback_chunk_limit
-          && value.to_string().to_lowercase().contains("max_tokens");
+        let mentions_max_tokens = value.to_string().to_lowercase().contains("max_tokens");
+        if status.is_client_error() && mentions_max_tokens && params.fields.remove("max_tokens").is_some() {
+          eprintln!("provider={} rejected max_tokens; retrying without it", cfg.id);
+          continue;
+        }
+        let looks_like_missing_max_tokens = status.is_client_error() && !use_fallback_chunk_limit && mentions_max_tokens;
         if looks_like_missing_max_tokens {
           use_fallback_chunk_limit = true;
           continue;
@@ -2582,10 +4265,11 @@ async fn call_openai_unbounded(
       }
     };
 
-    if stream_applied && chunk.is_empty() && finish_reason.is_none() {
+    if stream_applied && chunk.is_empty() && finish_reason.is_none() && round_calls.is_empty() {
       stream_supported = false;
       continue;
     }
+    tool_calls.extend(round_calls);
 
     let unique_chunk = if stream_applied {
       chunk
@@ -2601,15 +4285,16 @@ async fn call_openai_unbounded(
       unique_chunk
     };
 
-    if finish_reason.as_deref() != Some("length") {
+    let budget_left = cfg.generation.continues_cut_off_replies(reply_output_tokens(usage, &full_text));
+    if finish_reason.as_deref() != Some("length") || !budget_left {
       gate.finalize(live_stream);
-      return Ok(full_text);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
     }
     if round == MAX_CONTINUATIONS {
       full_text.push_str("\n\n[output may be truncated after repeated continuations]");
       gate.push(live_stream, "\n\n[output may be truncated after repeated continuations]");
       gate.finalize(live_stream);
-      return Ok(full_text);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
     }
 
     out_messages.push(serde_json::json!({
@@ -2623,17 +4308,20 @@ async fn call_openai_unbounded(
   }
 
   gate.finalize(live_stream);
-  Ok(full_text)
+  Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() })
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn call_anthropic_unbounded(
   app: &AppHandle,
   client: &reqwest::Client,
   cfg: &app_settings::ModelProvider,
   messages: &[ChatMessage],
   system_prompt: &str,
+  temperature_override: Option<f32>,
+  tools: &[ToolSpec],
   live_stream: Option<&LiveStreamSession>,
-) -> Result<String, String> {
+) -> Result<ModelTurn, String> {
   let api_key = match secrets::get_api_key(app, &cfg.id) {
     Ok(Some(v)) => v,
     Ok(None) => cfg.api_key.trim().to_string(),
@@ -2643,10 +4331,8 @@ async fn call_anthropic_unbounded(
     return Err(format!("api key not found for provider={}", cfg.id));
   }
 
-  let mut out_messages: Vec<serde_json::Value> = messages
-    .iter()
-    .map(|m| serde_json::json!({"role": m.role, "content": m.content}))
-    .collect();
+  let mut out_messages: Vec<serde_json::Value> = anthropic_messages_json(messages);
+  let tools_json = tools.iter().map(anthropic_tool_json).collect::<Vec<_>>();
   let base = cfg.base_url.trim_end_matches('/');
   let endpoint = if base.is_empty() {
     "https://api.anthropic.com/v1/messages".to_string()
@@ -2660,32 +4346,46 @@ async fn call_anthropic_unbounded(
   const CHUNK_MAX_TOKENS: u32 = 32000;
   const CONTINUE_PROMPT: &str =
     "Continue from exactly where you stopped. Do not repeat prior text.";
+  let chunk_max_tokens = cfg.generation.request_max_tokens().unwrap_or(CHUNK_MAX_TOKENS);
+  // Anthropic caps temperature at 1.0.
+  let temperature = temperature_override.map(|t| t.clamp(0.0, 1.0));
+  let mut params = cfg.generation.anthropic();
+  if let Some(t) = temperature {
+    params.fields.insert("temperature".to_string(), serde_json::json!(t));
+  }
+  log_generation_params(cfg, temperature, &params);
 
   let mut full_text = String::new();
+  let mut tool_calls: Vec<ToolCall> = Vec::new();
   let mut gate = LiveEmitGate::new();
   let mut stream_supported = true;
+  let mut usage = TokenUsage::default();
   for round in 0..=MAX_CONTINUATIONS {
-    let (chunk, stop_reason, stream_applied): (String, Option<String>, bool) = if stream_supported {
-      let body = serde_json::json!({
+    let (chunk, stop_reason, stream_applied, round_calls): (String, Option<String>, bool, Vec<ToolCall>) = if stream_supported {
+      let mut body = serde_json::json!({
         "model": cfg.model_name,
-        "max_tokens": CHUNK_MAX_TOKENS,
+        "max_tokens": chunk_max_tokens,
         "system": system_prompt,
         "messages": out_messages,
         "stream": true
       });
+      params.apply_to(&mut body);
+      if !tools_json.is_empty() {
+        body["tools"] = serde_json::json!(tools_json);
+      }
 
-      let resp = client
-        .post(endpoint.as_str())
-        .header("x-api-key", api_key.trim())
-        .header("anthropic-version", "2023-06-01")
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| format!("request failed: {e}"))?;
+      let resp = send_provider_request(app, cfg, &body, live_stream, || {
+        client
+          .post(endpoint.as_str())
+          .header("x-api-key", api_key.trim())
+          .header("anthropic-version", "2023-06-01")
+          .json(&body)
+      })
+      .await?;
 
-      let status = resp.status();
+      let status = resp.status;
       if !status.is_success() {
-        let raw = resp.text().await.map_err(|e| format!("decode failed: {e}"))?;
+        let raw = resp.text().await?;
         let lowered = raw.to_lowercase();
         let stream_unsupported = lowered.contains("stream")
           && (lowered.contains("not support")
@@ -2699,14 +4399,16 @@ async fn call_anthropic_unbounded(
         return Err(format!("http {status}: {raw}"));
       }
 
-      let mut sse_buf = String::new();
+      let mut sse_buf: Vec<u8> = Vec::new();
       let mut round_unique = String::new();
       let mut stop_reason: Option<String> = None;
-      let mut body_stream = resp.bytes_stream();
+      let mut pending_calls: Vec<PendingToolCall> = Vec::new();
+      let mut round_usage = TokenUsage::default();
+      let mut body_stream = resp.body;
       while let Some(item) = body_stream.next().await {
         let bytes = item.map_err(|e| format!("stream read failed: {e}"))?;
-        sse_buf.push_str(&String::from_utf8_lossy(&bytes));
-        while let Some(line) = sse_take_line(&mut sse_buf) {
+        sse_buf.extend_from_slice(&bytes);
+        while let Some(line) = take_byte_line(&mut sse_buf) {
           if let Some(data) = line.strip_prefix("data:") {
             let data = data.trim();
             if data.is_empty() || data == "[DONE]" {
@@ -2715,7 +4417,27 @@ async fn call_anthropic_unbounded(
             let value: serde_json::Value =
               serde_json::from_str(data).map_err(|e| format!("stream parse failed: {e}; data={data}"))?;
             match value["type"].as_str().unwrap_or_default() {
+              "message_start" => {
+                round_usage.input_tokens = anthropic_input_tokens(&value["message"]["usage"]);
+              }
+              "content_block_start" => {
+                let block = &value["content_block"];
+                if block["type"].as_str() == Some("tool_use") {
+                  pending_calls.push(PendingToolCall {
+                    index: value["index"].as_u64().unwrap_or_default(),
+                    id: block["id"].as_str().unwrap_or_default().to_string(),
+                    name: block["name"].as_str().unwrap_or_default().to_string(),
+                    arguments: String::new(),
+                  });
+                }
+              }
               "content_block_delta" => {
+                if let Some(partial) = value["delta"]["partial_json"].as_str() {
+                  let index = value["index"].as_u64().unwrap_or_default();
+                  if let Some(call) = pending_calls.iter_mut().find(|c| c.index == index) {
+                    call.arguments.push_str(partial);
+                  }
+                }
                 if let Some(text) = value["delta"]["text"].as_str() {
                   let (_, unique_piece) = append_chunk_with_overlap(&mut full_text, text);
                   if !unique_piece.is_empty() {
@@ -2728,40 +4450,53 @@ async fn call_anthropic_unbounded(
                 if let Some(reason) = value["delta"]["stop_reason"].as_str() {
                   stop_reason = Some(reason.to_string());
                 }
+                if let Some(output) = value["usage"]["output_tokens"].as_u64() {
+                  round_usage.output_tokens = output;
+                }
               }
               _ => {}
             }
           }
         }
       }
-      if round_unique.is_empty() && stop_reason.is_none() {
+      usage.add(round_usage);
+      if round_unique.is_empty() && stop_reason.is_none() && pending_calls.is_empty() {
         stream_supported = false;
         continue;
       }
-      (round_unique, stop_reason, true)
+      (round_unique, stop_reason, true, finish_pending_tool_calls(pending_calls))
     } else {
-      let body = serde_json::json!({
+      let mut body = serde_json::json!({
         "model": cfg.model_name,
-        "max_tokens": CHUNK_MAX_TOKENS,
+        "max_tokens": chunk_max_tokens,
         "system": system_prompt,
         "messages": out_messages
       });
+      params.apply_to(&mut body);
+      if !tools_json.is_empty() {
+        body["tools"] = serde_json::json!(tools_json);
+      }
+
+      let resp = send_provider_request(app, cfg, &body, live_stream, || {
+        client
+          .post(endpoint.as_str())
+          .header("x-api-key", api_key.trim())
+          .header("anthropic-version", "2023-06-01")
+          .json(&body)
+      })
+      .await?;
 
-      let resp = client
-        .post(endpoint.as_str())
-        .header("x-api-key", api_key.trim())
-        .header("anthropic-version", "2023-06-01")
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| format!("request failed: {e}"))?;
-
-      let status = resp.status();
-      let value: serde_json::Value = resp.json().await.map_err(|e| format!("decode failed: {e}"))?;
+      let status = resp.status;
+      let value = resp.json().await?;
       if !status.is_success() {
         return Err(format!("http {status}: {value}"));
       }
+      usage.add(TokenUsage {
+        input_tokens: anthropic_input_tokens(&value["usage"]),
+        output_tokens: value["usage"]["output_tokens"].as_u64().unwrap_or(0),
+      });
 
+      let round_calls = parse_anthropic_tool_uses(&value["content"]);
       let chunk = value["content"]
         .as_array()
         .map(|arr| {
@@ -2771,16 +4506,17 @@ async fn call_anthropic_unbounded(
             .collect::<Vec<_>>()
             .join("")
         })
-        .filter(|s| !s.is_empty())
+        .filter(|s| !s.is_empty() || !round_calls.is_empty())
         .ok_or_else(|| "missing content[].text".to_string())?;
       let stop_reason = value["stop_reason"].as_str().map(|s| s.to_string());
-      (chunk, stop_reason, false)
+      (chunk, stop_reason, false, round_calls)
     };
 
-    if stream_applied && chunk.is_empty() && stop_reason.is_none() {
+    if stream_applied && chunk.is_empty() && stop_reason.is_none() && round_calls.is_empty() {
       stream_supported = false;
       continue;
     }
+    tool_calls.extend(round_calls);
 
     let unique_chunk = if stream_applied {
       chunk
@@ -2795,15 +4531,16 @@ async fn call_anthropic_unbounded(
       gate.push(live_stream, unique_chunk.as_str());
       unique_chunk
     };
-    if stop_reason.as_deref() != Some("max_tokens") {
+    let budget_left = cfg.generation.continues_cut_off_replies(reply_output_tokens(usage, &full_text));
+    if stop_reason.as_deref() != Some("max_tokens") || !budget_left {
       gate.finalize(live_stream);
-      return Ok(full_text);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
     }
     if round == MAX_CONTINUATIONS {
       full_text.push_str("\n\n[output may be truncated after repeated continuations]");
       gate.push(live_stream, "\n\n[output may be truncated after repeated continuations]");
       gate.finalize(live_stream);
-      return Ok(full_text);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
     }
 
     out_messages.push(serde_json::json!({
@@ -2817,7 +4554,330 @@ async fn call_anthropic_unbounded(
   }
 
   gate.finalize(live_stream);
-  Ok(full_text)
+  Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn call_gemini_unbounded(
+  app: &AppHandle,
+  client: &reqwest::Client,
+  cfg: &app_settings::ModelProvider,
+  messages: &[ChatMessage],
+  system_prompt: &str,
+  temperature_override: Option<f32>,
+  tools: &[ToolSpec],
+  live_stream: Option<&LiveStreamSession>,
+) -> Result<ModelTurn, String> {
+  let api_key = match secrets::get_api_key(app, &cfg.id) {
+    Ok(Some(v)) => v,
+    Ok(None) => cfg.api_key.trim().to_string(),
+    Err(e) => return Err(format!("keyring read failed: {e}")),
+  };
+  if api_key.trim().is_empty() {
+    return Err(format!("api key not found for provider={}", cfg.id));
+  }
+
+  let endpoint = gemini_model_endpoint(cfg);
+  let stream_url = format!("{endpoint}:streamGenerateContent?alt=sse");
+  let url = format!("{endpoint}:generateContent");
+  let mut contents = gemini_contents_json(messages);
+  let tools_json = tools.iter().map(gemini_tool_json).collect::<Vec<_>>();
+  let mut base_body = serde_json::json!({
+    "generationConfig": { "temperature": temperature_override.unwrap_or(0.7) },
+    "safetySettings": gemini_safety_settings()
+  });
+  let params = cfg.generation.gemini();
+  log_generation_params(cfg, Some(temperature_override.unwrap_or(0.7)), &params);
+  params.apply_to(&mut base_body["generationConfig"]);
+  if !system_prompt.trim().is_empty() {
+    base_body["systemInstruction"] = serde_json::json!({ "parts": [{ "text": system_prompt }] });
+  }
+  if !tools_json.is_empty() {
+    base_body["tools"] = serde_json::json!([{ "functionDeclarations": tools_json }]);
+  }
+
+  const MAX_CONTINUATIONS: usize = 64;
+  const CONTINUE_PROMPT: &str =
+    "Continue from exactly where you stopped. Do not repeat prior text.";
+
+  let mut full_text = String::new();
+  let mut tool_calls: Vec<ToolCall> = Vec::new();
+  let mut gate = LiveEmitGate::new();
+  let mut stream_supported = true;
+  let mut usage = TokenUsage::default();
+  for round in 0..=MAX_CONTINUATIONS {
+    let mut body = base_body.clone();
+    body["contents"] = serde_json::json!(contents);
+    let (chunk, finish_reason, stream_applied, round_calls): (String, Option<String>, bool, Vec<ToolCall>) = if stream_supported {
+      let resp = send_provider_request(app, cfg, &body, live_stream, || {
+        client
+          .post(stream_url.as_str())
+          .header("x-goog-api-key", api_key.trim())
+          .json(&body)
+      })
+      .await?;
+      let status = resp.status;
+      if !status.is_success() {
+        let raw = resp.text().await?;
+        return Err(format!("http {status}: {raw}"));
+      }
+
+      let mut sse_buf: Vec<u8> = Vec::new();
+      let mut round_unique = String::new();
+      let mut finish_reason: Option<String> = None;
+      let mut round_calls: Vec<ToolCall> = Vec::new();
+      let mut blocked: Option<String> = None;
+      let mut round_usage = TokenUsage::default();
+      let mut body_stream = resp.body;
+      let mut handle_data = |data: &str| -> Result<(), String> {
+        let value: serde_json::Value =
+          serde_json::from_str(data).map_err(|e| format!("stream parse failed: {e}; data={data}"))?;
+        if let Some(reason) = value["candidates"][0]["finishReason"].as_str() {
+          finish_reason = Some(reason.to_string());
+        }
+        if blocked.is_none() {
+          blocked = gemini_block_reason(&value);
+        }
+        // Every chunk carries the running totals of the request.
+        if let Some(u) = gemini_usage(&value["usageMetadata"]) {
+          round_usage = u;
+        }
+        let mut text = String::new();
+        collect_gemini_parts(&value, &mut text, &mut round_calls);
+        if !text.is_empty() {
+          let (_, unique_piece) = append_chunk_with_overlap(&mut full_text, text.as_str());
+          if !unique_piece.is_empty() {
+            round_unique.push_str(unique_piece.as_str());
+            gate.push(live_stream, unique_piece.as_str());
+          }
+        }
+        Ok(())
+      };
+      while let Some(item) = body_stream.next().await {
+        let bytes = item.map_err(|e| format!("stream read failed: {e}"))?;
+        sse_buf.extend_from_slice(&bytes);
+        while let Some(line) = take_byte_line(&mut sse_buf) {
+          if let Some(data) = line.strip_prefix("data:") {
+            let data = data.trim();
+            if !data.is_empty() {
+              handle_data(data)?;
+            }
+          }
+        }
+      }
+      let rest = String::from_utf8_lossy(&sse_buf).into_owned();
+      if let Some(data) = rest.trim().strip_prefix("data:") {
+        let data = data.trim();
+        if !data.is_empty() {
+          handle_data(data)?;
+        }
+      }
+      usage.add(round_usage);
+      if let Some(reason) = blocked.filter(|_| round_unique.is_empty() && round_calls.is_empty()) {
+        return Err(format!("gemini blocked the response: {reason}"));
+      }
+      if round_unique.is_empty() && finish_reason.is_none() && round_calls.is_empty() {
+        stream_supported = false;
+        continue;
+      }
+      (round_unique, finish_reason, true, round_calls)
+    } else {
+      let resp = send_provider_request(app, cfg, &body, live_stream, || {
+        client
+          .post(url.as_str())
+          .header("x-goog-api-key", api_key.trim())
+          .json(&body)
+      })
+      .await?;
+      let status = resp.status;
+      let value: serde_json::Value = resp.json().await?;
+      if !status.is_success() {
+        return Err(format!("http {status}: {value}"));
+      }
+      if let Some(u) = gemini_usage(&value["usageMetadata"]) {
+        usage.add(u);
+      }
+      let mut chunk = String::new();
+      let mut round_calls: Vec<ToolCall> = Vec::new();
+      collect_gemini_parts(&value, &mut chunk, &mut round_calls);
+      if chunk.is_empty() && round_calls.is_empty() {
+        return Err(match gemini_block_reason(&value) {
+          Some(reason) => format!("gemini blocked the response: {reason}"),
+          None => "missing candidates[0].content.parts".to_string(),
+        });
+      }
+      let finish_reason = value["candidates"][0]["finishReason"].as_str().map(|s| s.to_string());
+      (chunk, finish_reason, false, round_calls)
+    };
+
+    tool_calls.extend(round_calls);
+
+    let unique_chunk = if stream_applied {
+      chunk
+    } else {
+      let (overlap, unique_chunk) = append_chunk_with_overlap(&mut full_text, chunk.as_str());
+      if overlap > 0 {
+        eprintln!(
+          "continuation overlap dedup provider={} round={} overlap_chars={}",
+          cfg.id, round, overlap
+        );
+      }
+      gate.push(live_stream, unique_chunk.as_str());
+      unique_chunk
+    };
+    let budget_left = cfg.generation.continues_cut_off_replies(reply_output_tokens(usage, &full_text));
+    if finish_reason.as_deref() != Some("MAX_TOKENS") || !budget_left {
+      gate.finalize(live_stream);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
+    }
+    if round == MAX_CONTINUATIONS {
+      full_text.push_str("\n\n[output may be truncated after repeated continuations]");
+      gate.push(live_stream, "\n\n[output may be truncated after repeated continuations]");
+      gate.finalize(live_stream);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
+    }
+
+    contents.push(serde_json::json!({
+      "role": "model",
+      "parts": [{ "text": unique_chunk }]
+    }));
+    contents.push(serde_json::json!({
+      "role": "user",
+      "parts": [{ "text": CONTINUE_PROMPT }]
+    }));
+  }
+
+  gate.finalize(live_stream);
+  Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() })
+}
+
+/// Chat through Ollama's native `/api/chat`, which streams one JSON object per
+/// line and reports `done_reason = "length"` when `num_predict` cut it short.
+#[allow(clippy::too_many_arguments)]
+async fn call_ollama_unbounded(
+  app: &AppHandle,
+  client: &reqwest::Client,
+  cfg: &app_settings::ModelProvider,
+  messages: &[ChatMessage],
+  system_prompt: &str,
+  temperature_override: Option<f32>,
+  tools: &[ToolSpec],
+  live_stream: Option<&LiveStreamSession>,
+) -> Result<ModelTurn, String> {
+  let url = format!("{}/api/chat", ollama_base_url(cfg));
+  let mut out_messages: Vec<serde_json::Value> = Vec::new();
+  if !system_prompt.trim().is_empty() {
+    out_messages.push(serde_json::json!({
+      "role": "system",
+      "content": system_prompt
+    }));
+  }
+  out_messages.extend(messages.iter().map(ollama_message_json));
+  let tools_json = tools.iter().map(openai_tool_json).collect::<Vec<_>>();
+  let mut options = serde_json::json!({
+    "temperature": temperature_override.unwrap_or(0.7),
+    "num_ctx": cfg.context_window_tokens(),
+  });
+  if cfg.ollama.num_predict != 0 {
+    options["num_predict"] = serde_json::json!(cfg.ollama.num_predict);
+  }
+  let params = cfg.generation.ollama();
+  log_generation_params(cfg, Some(temperature_override.unwrap_or(0.7)), &params);
+  params.apply_to(&mut options);
+
+  const MAX_CONTINUATIONS: usize = 64;
+  const CONTINUE_PROMPT: &str =
+    "Continue from exactly where you stopped. Do not repeat prior text.";
+
+  let mut full_text = String::new();
+  let mut tool_calls: Vec<ToolCall> = Vec::new();
+  let mut gate = LiveEmitGate::new();
+  let mut usage = TokenUsage::default();
+  for round in 0..=MAX_CONTINUATIONS {
+    let mut body = serde_json::json!({
+      "model": cfg.model_name,
+      "messages": out_messages,
+      "options": options,
+      "stream": true
+    });
+    if !cfg.ollama.keep_alive.trim().is_empty() {
+      body["keep_alive"] = serde_json::json!(cfg.ollama.keep_alive.trim());
+    }
+    if !tools_json.is_empty() {
+      body["tools"] = serde_json::json!(tools_json);
+    }
+    let resp = send_provider_request(app, cfg, &body, live_stream, || client.post(url.as_str()).json(&body)).await?;
+    let status = resp.status;
+    if !status.is_success() {
+      let raw = resp.text().await?;
+      return Err(format!("http {status}: {raw}"));
+    }
+
+    let mut line_buf: Vec<u8> = Vec::new();
+    let mut round_unique = String::new();
+    let mut done_reason: Option<String> = None;
+    let mut body_stream = resp.body;
+    let mut handle_line = |line: &str| -> Result<(), String> {
+      let value: serde_json::Value =
+        serde_json::from_str(line).map_err(|e| format!("stream parse failed: {e}; data={line}"))?;
+      if let Some(error) = value["error"].as_str() {
+        return Err(format!("ollama error: {error}"));
+      }
+      tool_calls.extend(parse_ollama_tool_calls(&value["message"]));
+      if let Some(content) = value["message"]["content"].as_str() {
+        let (_, unique_piece) = append_chunk_with_overlap(&mut full_text, content);
+        if !unique_piece.is_empty() {
+          round_unique.push_str(unique_piece.as_str());
+          gate.push(live_stream, unique_piece.as_str());
+        }
+      }
+      if value["done"].as_bool() == Some(true) {
+        done_reason = Some(value["done_reason"].as_str().unwrap_or("stop").to_string());
+        usage.add(TokenUsage {
+          input_tokens: value["prompt_eval_count"].as_u64().unwrap_or(0),
+          output_tokens: value["eval_count"].as_u64().unwrap_or(0),
+        });
+      }
+      Ok(())
+    };
+    while let Some(item) = body_stream.next().await {
+      let bytes = item.map_err(|e| format!("stream read failed: {e}"))?;
+      line_buf.extend_from_slice(&bytes);
+      while let Some(line) = take_byte_line(&mut line_buf) {
+        if !line.trim().is_empty() {
+          handle_line(line.trim())?;
+        }
+      }
+    }
+    let rest = String::from_utf8_lossy(&line_buf).into_owned();
+    if !rest.trim().is_empty() {
+      handle_line(rest.trim())?;
+    }
+
+    let budget_left = cfg.generation.continues_cut_off_replies(reply_output_tokens(usage, &full_text));
+    if done_reason.as_deref() != Some("length") || !budget_left {
+      gate.finalize(live_stream);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
+    }
+    if round == MAX_CONTINUATIONS {
+      full_text.push_str("\n\n[output may be truncated after repeated continuations]");
+      gate.push(live_stream, "\n\n[output may be truncated after repeated continuations]");
+      gate.finalize(live_stream);
+      return Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() });
+    }
+
+    out_messages.push(serde_json::json!({
+      "role": "assistant",
+      "content": round_unique
+    }));
+    out_messages.push(serde_json::json!({
+      "role": "user",
+      "content": CONTINUE_PROMPT
+    }));
+  }
+
+  gate.finalize(live_stream);
+  Ok(ModelTurn { text: full_text, tool_calls, usage, provider_id: cfg.id.clone() })
 }
 
 #[tauri::command]
@@ -2832,35 +4892,14 @@ pub async fn ai_assistance_generate(
   let current_provider = resolve_current_provider(&app, &settings)?;
   
   // Create a simple message for AI assistance
-  let messages = vec![ChatMessage {
-    role: "user".to_string(),
-    content: prompt,
-  }];
+  let messages = vec![ChatMessage::new("user", prompt)];
   
   // Call the appropriate AI provider
-  match current_provider.kind {
-    app_settings::ProviderKind::OpenAI | app_settings::ProviderKind::OpenAICompatible => {
-      call_openai_unbounded(
-        &app,
-        &client,
-        &current_provider,
-        &messages,
-        "",
-        None,
-        None,
-      ).await
-    },
-    app_settings::ProviderKind::Anthropic => {
-      call_anthropic_unbounded(
-        &app,
-        &client,
-        &current_provider,
-        &messages,
-        "",
-        None,
-      ).await
-    }
+  let turn = call_provider_turn(&app, &client, &current_provider, &messages, "", None, &[], None).await?;
+  if let Some(root) = current_workspace_root(&app) {
+    record_model_usage(&root, "", "ai_assistance", &current_provider, turn.usage);
   }
+  Ok(turn.text)
 }
 
 #[derive(Serialize, Deserialize, Clone, Default)]
@@ -3089,6 +5128,53 @@ fn parse_risk_scan_result(raw: &str, scanned_chars: usize) -> RiskScanResult {
   }
 }
 
+/// Appends one model request to the project's usage log and returns the entry.
+fn record_model_usage(
+  root: &Path,
+  stream_id: &str,
+  agent_id: &str,
+  provider: &app_settings::ModelProvider,
+  usage: TokenUsage,
+) -> usage_log::UsageEntry {
+  let entry = usage_log::UsageEntry {
+    ts: chrono::Local::now().to_rfc3339(),
+    stream_id: stream_id.to_string(),
+    agent_id: agent_id.to_string(),
+    provider_id: provider.id.clone(),
+    model: provider.model_name.clone(),
+    input_tokens: usage.input_tokens,
+    output_tokens: usage.output_tokens,
+    cost: provider.usage_cost(usage.input_tokens, usage.output_tokens),
+  };
+  if let Err(e) = usage_log::append(root, &entry) {
+    eprintln!("usage log failed: {e}");
+  }
+  entry
+}
+
+/// Token usage of the open project, grouped by `day` (default), `provider` or
+/// `agent`; `since`/`until` are inclusive `YYYY-MM-DD` days.
+#[allow(non_snake_case)]
+#[tauri::command]
+pub fn get_usage_report(
+  state: State<'_, AppState>,
+  groupBy: Option<String>,
+  group_by: Option<String>,
+  since: Option<String>,
+  until: Option<String>,
+) -> Result<usage_log::UsageReport, String> {
+  let root = get_workspace_root(&state)?;
+  let group_by = usage_log::UsageGroupBy::parse(groupBy.or(group_by).as_deref().unwrap_or_default())?;
+  let since = since.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+  let until = until.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+  Ok(usage_log::summarize(
+    &usage_log::read(&root),
+    group_by,
+    since.as_deref(),
+    until.as_deref(),
+  ))
+}
+
 fn append_risk_scan_log(root: &Path, entry: serde_json::Value) -> Result<(), String> {
   let log_dir = root.join(".novel").join(".logs");
   fs::create_dir_all(&log_dir).map_err(|e| format!("create log dir failed: {e}"))?;
@@ -3145,36 +5231,21 @@ pub async fn risk_scan_content(
   prompt.push_str(payload_text.as_str());
 
   let system_prompt = "你是严格的中文小说合规审校助手，输出务必是可解析 JSON，不得包含解释文字。";
-  let messages = vec![ChatMessage {
-    role: "user".to_string(),
-    content: prompt,
-  }];
-
-  let raw = match current_provider.kind {
-    app_settings::ProviderKind::OpenAI | app_settings::ProviderKind::OpenAICompatible => {
-      call_openai_unbounded(
-        &app,
-        &client,
-        &current_provider,
-        &messages,
-        system_prompt,
-        Some(0.2),
-        None,
-      )
-      .await?
-    }
-    app_settings::ProviderKind::Anthropic => {
-      call_anthropic_unbounded(
-        &app,
-        &client,
-        &current_provider,
-        &messages,
-        system_prompt,
-        None,
-      )
-      .await?
-    }
-  };
+  let messages = vec![ChatMessage::new("user", prompt)];
+
+  let turn = call_provider_turn(
+    &app,
+    &client,
+    &current_provider,
+    &messages,
+    system_prompt,
+    Some(0.2),
+    &[],
+    None,
+  )
+  .await?;
+  record_model_usage(&root, "", "risk_scan", &current_provider, turn.usage);
+  let raw = turn.text;
 
   let result = parse_risk_scan_result(raw.as_str(), scanned_chars);
   let _ = append_risk_scan_log(
@@ -3857,3 +5928,297 @@ pub async fn book_extract_techniques(content: String) -> Result<Vec<WritingTechn
   Ok(techniques)
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn scripted_provider_cfg(fixture: &Path) -> app_settings::ModelProvider {
+    app_settings::ModelProvider {
+      id: "scripted".to_string(),
+      name: "Scripted".to_string(),
+      kind: app_settings::ProviderKind::Scripted,
+      api_key: String::new(),
+      base_url: fixture.to_string_lossy().to_string(),
+      model_name: "fixture".to_string(),
+      native_tool_calls: true,
+      context_window: 0,
+      ollama: app_settings::OllamaOptions::default(),
+      prices: BTreeMap::new(),
+      generation: Default::default(),
+    }
+  }
+
+  /// Runs `runtime` against `cfg` through the OpenAI client, recording every
+  /// request body it sends.
+  fn run_scripted(
+    runtime: &mut agent_system::AgentRuntime,
+    cfg: &app_settings::ModelProvider,
+    request: &str,
+    on_tool_event: impl FnMut(agent_system::AgentToolEvent) + Send,
+  ) -> (Result<(String, agent_system::AgentPerf), String>, Vec<serde_json::Value>) {
+    let fixture = PathBuf::from(&cfg.base_url);
+    let bodies = Arc::new(Mutex::new(Vec::new()));
+    let result = tauri::async_runtime::block_on(runtime.run_react(
+      vec![ChatMessage::new("user", request)],
+      "You write novels.".to_string(),
+      app_settings::AiEditApplyMode::Review,
+      true,
+      |request| {
+        let (cfg, fixture, bodies) = (cfg.clone(), fixture.clone(), bodies.clone());
+        async move {
+          let system = request.messages.iter().filter(|m| m.role == "system").map(|m| m.content.as_str());
+          let system = system.collect::<Vec<_>>().join("\n");
+          let messages = request.messages.iter().filter(|m| m.role != "system").cloned().collect::<Vec<_>>();
+          let send = |body: serde_json::Value| {
+            bodies.lock().unwrap().push(body.clone());
+            std::future::ready(scripted_response(&fixture, &body))
+          };
+          openai_turn(&cfg, &messages, &system, None, &request.tools, None, send).await
+        }
+      },
+      on_tool_event,
+      |_| std::future::ready(true),
+    ));
+    let bodies = bodies.lock().unwrap().clone();
+    (result, bodies)
+  }
+
+  #[test]
+  fn scripted_run_continues_cut_off_replies_and_reviews_writes() {
+    let root = std::env::temp_dir().join(format!("novel-scripted-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&root).unwrap();
+    let fixture = root.join("fixture.json");
+    let script = json!({
+      "responses": [
+        { "tool_calls": [{ "name": "fs_write_text", "arguments": { "path": "stories/02.md", "text": "林默推开门。" } }] },
+        { "chunks": ["他看见了一个", "熟悉的身影，"], "finish_reason": "length" },
+        // The continuation repeats the tail it was cut off at.
+        { "chunks": ["熟悉的身影，", "正站在雨里。"] }
+      ]
+    });
+    fs::write(&fixture, script.to_string()).unwrap();
+    let cfg = scripted_provider_cfg(&fixture);
+    let mut runtime = agent_system::AgentRuntime::new(root.clone());
+
+    let (result, bodies) = run_scripted(&mut runtime, &cfg, "写第二章开头", |_| {});
+    let (answer, perf) = result.unwrap();
+    assert_eq!(answer, "他看见了一个熟悉的身影，正站在雨里。");
+    assert_eq!(perf.steps, 2);
+    assert_eq!(bodies.len(), 3);
+    let tool_reply = bodies[1]["messages"].as_array().unwrap().last().unwrap().clone();
+    assert_eq!(tool_reply["role"], "tool");
+    let continue_prompt = bodies[2]["messages"].as_array().unwrap().last().unwrap().clone();
+    assert!(continue_prompt["content"].as_str().unwrap().starts_with("Continue from exactly"));
+
+    let changes = runtime.overlay_change_set().expect("reviewed write");
+    assert_eq!(changes.files.len(), 1);
+    assert_eq!(changes.files[0].file_path, "stories/02.md");
+    assert_eq!(changes.files[0].file_op, Some(crate::modification_types::FileOperation::Create));
+    assert_eq!(changes.files[0].modifications[0].modified_text.as_deref(), Some("林默推开门。"));
+    assert!(!root.join("stories/02.md").exists());
+    let _ = fs::remove_dir_all(&root);
+  }
+
+  #[test]
+  fn cancelled_run_checkpoints_and_resumes_at_the_next_step() {
+    let root = std::env::temp_dir().join(format!("novel-resume-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&root).unwrap();
+    let fixture = root.join("fixture.json");
+    let script = json!({
+      "responses": [
+        { "tool_calls": [{ "name": "fs_write_text", "arguments": { "path": "stories/02.md", "text": "林默推开门。" } }] },
+        { "text": "第二章开头写好了。" }
+      ]
+    });
+    fs::write(&fixture, script.to_string()).unwrap();
+    let cfg = scripted_provider_cfg(&fixture);
+
+    // Cancel once the first step's tool call is done; the run stops at the next step boundary.
+    let cancel = CancelToken::new();
+    let mut runtime = agent_system::AgentRuntime::new(root.clone());
+    runtime.set_cancel_token(cancel.clone());
+    let (result, _) = run_scripted(&mut runtime, &cfg, "写第二章开头", |event| {
+      if event.ok.is_some() {
+        cancel.cancel();
+      }
+    });
+    assert_eq!(result.err().as_deref(), Some(agent_system::RUN_CANCELLED));
+    let checkpoint = runtime.checkpoint().expect("checkpoint at the step boundary");
+    run_checkpoint::save(&root, "stream-1", "", "scripted", "cancelled", checkpoint).unwrap();
+
+    let raw = fs::read_to_string(root.join(".novel/state/session-state.json")).unwrap();
+    let saved = &serde_json::from_str::<serde_json::Value>(&raw).unwrap()["agent_checkpoints"]["stream-1"];
+    assert_eq!(saved["step"], 1);
+    assert_eq!(saved["reason"], "cancelled");
+    let messages = saved["messages"].as_array().unwrap();
+    assert_eq!(messages.last().unwrap()["role"], "tool");
+    assert_eq!(saved["overlay"]["entries"]["stories/02.md"]["text"], "林默推开门。");
+
+    let loaded = run_checkpoint::load(&root, None).unwrap();
+    assert_eq!(loaded.stream_id, "stream-1");
+    let mut resumed = agent_system::AgentRuntime::new(root.clone());
+    resumed.resume_from(loaded.run);
+    let (result, bodies) = run_scripted(&mut resumed, &cfg, "", |_| {});
+    let (answer, perf) = result.unwrap();
+    assert_eq!(answer, "第二章开头写好了。");
+    assert_eq!(perf.steps, 2);
+    assert_eq!(bodies.len(), 1);
+    let sent = bodies[0]["messages"].as_array().unwrap();
+    assert_eq!(sent.last().unwrap()["role"], "tool");
+    let changes = resumed.overlay_change_set().expect("resumed draft edits");
+    assert_eq!(changes.files[0].file_path, "stories/02.md");
+
+    run_checkpoint::remove(&root, "stream-1").unwrap();
+    assert!(run_checkpoint::load(&root, Some("stream-1")).is_err());
+    assert!(!root.join("stories/02.md").exists());
+    let _ = fs::remove_dir_all(&root);
+  }
+
+  #[test]
+  fn falls_back_on_a_rate_limited_provider() {
+    let root = std::env::temp_dir().join(format!("novel-fallback-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&root).unwrap();
+    let limited = json!({ "responses": [{ "status": 429, "error": "rate limit exceeded" }] });
+    let backup = json!({ "responses": [{ "text": "林默推开门。" }, { "text": "雨还在下。" }] });
+    let mut chain = Vec::new();
+    for (id, script, window) in [("primary", limited, 200_000), ("backup", backup, 8_000)] {
+      let fixture = root.join(format!("{id}.json"));
+      fs::write(&fixture, script.to_string()).unwrap();
+      let mut cfg = scripted_provider_cfg(&fixture);
+      cfg.id = id.to_string();
+      cfg.context_window = window;
+      chain.push(cfg);
+    }
+    // The prompt has to fit the backup's window as well.
+    assert_eq!(run_context_budget(&chain), 6_000);
+
+    let active = AtomicUsize::new(0);
+    let messages = vec![ChatMessage::new("user", "写开头")];
+    // The second step goes straight to the backup; the primary's fixture is used up.
+    for expected in ["林默推开门。", "雨还在下。"] {
+      let (turn, provider) = tauri::async_runtime::block_on(call_with_fallback(&chain, &active, |cfg| {
+        let fixture = PathBuf::from(&cfg.base_url);
+        let send = move |body: serde_json::Value| std::future::ready(scripted_response(&fixture, &body));
+        openai_turn(cfg, &messages, "", None, &[], None, send)
+      }))
+      .unwrap();
+      assert_eq!((turn.text.as_str(), provider.id.as_str()), (expected, "backup"));
+      assert_eq!(active.load(Ordering::Relaxed), 1);
+    }
+    let _ = fs::remove_dir_all(&root);
+  }
+
+  /// An assistant step with text and two calls of the same tool, then both results.
+  fn two_call_step() -> Vec<ChatMessage> {
+    let call = |id: &str, path: &str| ToolCall {
+      id: id.to_string(),
+      name: "fs_read_text".to_string(),
+      arguments: json!({ "path": path }),
+    };
+    let result = |id: &str, text: &str| ChatMessage {
+      role: "tool".to_string(),
+      content: json!({ "text": text }).to_string(),
+      tool_call_id: Some(id.to_string()),
+      name: Some("fs_read_text".to_string()),
+      ..Default::default()
+    };
+    vec![
+      ChatMessage::new("user", "对比两章"),
+      ChatMessage {
+        role: "assistant".to_string(),
+        content: "先读两章。".to_string(),
+        tool_calls: vec![call("call-1", "stories/01.md"), call("call-2", "stories/02.md")],
+        ..Default::default()
+      },
+      result("call-1", "第一章"),
+      result("call-2", "第二章"),
+    ]
+  }
+
+  #[test]
+  fn anthropic_messages_keep_text_before_calls_and_merge_results() {
+    let out = anthropic_messages_json(&two_call_step());
+    assert_eq!(out.len(), 3);
+    let blocks = out[1]["content"].as_array().unwrap();
+    assert_eq!(out[1]["role"], "assistant");
+    assert_eq!(blocks[0], json!({ "type": "text", "text": "先读两章。" }));
+    let uses = blocks[1..].iter().map(|b| (&b["type"], &b["id"], &b["name"])).collect::<Vec<_>>();
+    assert_eq!(
+      uses,
+      vec![
+        (&json!("tool_use"), &json!("call-1"), &json!("fs_read_text")),
+        (&json!("tool_use"), &json!("call-2"), &json!("fs_read_text")),
+      ]
+    );
+    assert_eq!(blocks[2]["input"], json!({ "path": "stories/02.md" }));
+    assert_eq!(out[2]["role"], "user");
+    let results = out[2]["content"].as_array().unwrap();
+    let ids = results.iter().map(|r| (&r["type"], &r["tool_use_id"])).collect::<Vec<_>>();
+    assert_eq!(
+      ids,
+      vec![(&json!("tool_result"), &json!("call-1")), (&json!("tool_result"), &json!("call-2"))]
+    );
+  }
+
+  #[test]
+  fn gemini_contents_keep_text_before_calls_and_merge_results() {
+    let out = gemini_contents_json(&two_call_step());
+    assert_eq!(out.len(), 3);
+    assert_eq!(out[1]["role"], "model");
+    let parts = out[1]["parts"].as_array().unwrap();
+    assert_eq!(parts[0], json!({ "text": "先读两章。" }));
+    assert_eq!(
+      parts[1]["functionCall"],
+      json!({ "id": "call-1", "name": "fs_read_text", "args": { "path": "stories/01.md" } })
+    );
+    assert_eq!(parts[2]["functionCall"]["id"], "call-2");
+    assert_eq!(out[2]["role"], "user");
+    let results = out[2]["parts"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(
+      results[0]["functionResponse"],
+      json!({ "id": "call-1", "name": "fs_read_text", "response": { "text": "第一章" } })
+    );
+    assert_eq!(results[1]["functionResponse"]["id"], "call-2");
+    assert_eq!(results[1]["functionResponse"]["response"]["text"], "第二章");
+  }
+
+  #[test]
+  fn explicit_max_tokens_bounds_continuations() {
+    let root = std::env::temp_dir().join(format!("novel-budget-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&root).unwrap();
+    let fixture = root.join("fixture.json");
+    let script = json!({
+      "responses": [
+        { "chunks": ["林默推开门，雨下得正大。"], "finish_reason": "length" },
+        { "chunks": ["他没有撑伞。"] }
+      ]
+    });
+    fs::write(&fixture, script.to_string()).unwrap();
+    // The scripted provider reports no usage, so the 12 CJK characters are
+    // estimated at 12 tokens.
+    for (budget, expected, requests) in [
+      (12, "林默推开门，雨下得正大。", 1),
+      (40, "林默推开门，雨下得正大。他没有撑伞。", 2),
+    ] {
+      scripted_provider::reset(&fixture);
+      let mut cfg = scripted_provider_cfg(&fixture);
+      cfg.generation.max_tokens = Some(budget);
+      cfg.generation.output_limit = Some(8);
+      let bodies = Mutex::new(Vec::new());
+      let send = |body: serde_json::Value| {
+        bodies.lock().unwrap().push(body.clone());
+        std::future::ready(scripted_response(&fixture, &body))
+      };
+      let messages = vec![ChatMessage::new("user", "写开头")];
+      let turn = tauri::async_runtime::block_on(openai_turn(&cfg, &messages, "", None, &[], None, send)).unwrap();
+      assert_eq!(turn.text, expected);
+      let bodies = bodies.into_inner().unwrap();
+      assert_eq!(bodies.len(), requests);
+      assert_eq!(bodies[0]["max_tokens"], 8);
+    }
+    let _ = fs::remove_dir_all(&root);
+  }
+}
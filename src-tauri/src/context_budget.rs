@@ -0,0 +1,286 @@
+use crate::ai_types::ChatMessage;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Observations longer than this are cut down first when over budget.
+const TRUNCATED_OBSERVATION_CHARS: usize = 600;
+/// Tool-call string arguments (e.g. whole chapters) are cut to this length.
+const TRUNCATED_ARGUMENT_CHARS: usize = 200;
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// Rough token count: one token per CJK character, four characters per token
+/// for everything else. Errs on the high side for Chinese prose.
+pub fn estimate_tokens(text: &str) -> usize {
+  let mut cjk = 0usize;
+  let mut other = 0usize;
+  for ch in text.chars() {
+    if is_cjk(ch) {
+      cjk += 1;
+    } else {
+      other += 1;
+    }
+  }
+  cjk + other.div_ceil(4)
+}
+
+pub(crate) fn is_cjk(ch: char) -> bool {
+  matches!(
+    ch as u32,
+    0x3000..=0x303F // CJK punctuation
+      | 0x3040..=0x30FF // kana
+      | 0x3400..=0x4DBF
+      | 0x4E00..=0x9FFF
+      | 0xAC00..=0xD7AF // hangul
+      | 0xF900..=0xFAFF
+      | 0xFF00..=0xFFEF // full-width forms
+      | 0x20000..=0x2FA1F
+  )
+}
+
+pub fn estimate_message_tokens(message: &ChatMessage) -> usize {
+  let calls = message
+    .tool_calls
+    .iter()
+    .map(|c| estimate_tokens(&c.name) + estimate_tokens(&c.arguments.to_string()))
+    .sum::<usize>();
+  MESSAGE_OVERHEAD_TOKENS + estimate_tokens(&message.content) + calls
+}
+
+pub fn estimate_messages_tokens(messages: &[ChatMessage]) -> usize {
+  messages.iter().map(estimate_message_tokens).sum()
+}
+
+/// Result of one `compact_messages` pass, accumulated into `AgentPerf`.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct CompactionStats {
+  pub compacted_messages: u32,
+  pub tokens_before: usize,
+  pub tokens_after: usize,
+}
+
+impl CompactionStats {
+  pub fn tokens_saved(&self) -> usize {
+    self.tokens_before.saturating_sub(self.tokens_after)
+  }
+}
+
+fn is_observation(message: &ChatMessage) -> bool {
+  message.role == "tool" || (message.role == "user" && message.content.starts_with("OBSERVATION"))
+}
+
+/// Shrinks tool traffic older than the latest assistant turn until the
+/// estimate fits `budget`: long observations are truncated, then replaced with
+/// a one-line stub; large string arguments of old tool calls, native or
+/// written as `INPUT:` lines, are truncated.
+/// System and conversation messages are never touched.
+pub fn compact_messages(messages: &mut [ChatMessage], budget: usize) -> CompactionStats {
+  let mut stats = CompactionStats {
+    tokens_before: estimate_messages_tokens(messages),
+    ..Default::default()
+  };
+  let mut total = stats.tokens_before;
+  if total <= budget {
+    stats.tokens_after = total;
+    return stats;
+  }
+  let protected_from = messages
+    .iter()
+    .rposition(|m| m.role == "assistant")
+    .unwrap_or(messages.len());
+  let mut touched = vec![false; messages.len()];
+
+  for pass in 0..2 {
+    for idx in 0..protected_from {
+      if total <= budget {
+        break;
+      }
+      let message = &mut messages[idx];
+      let before = estimate_message_tokens(message);
+      let changed = if is_observation(message) {
+        if pass == 0 {
+          truncate_observation(message)
+        } else {
+          stub_observation(message)
+        }
+      } else if pass == 0 && message.role == "assistant" && !message.tool_calls.is_empty() {
+        let mut changed = false;
+        for call in message.tool_calls.iter_mut() {
+          changed |= truncate_long_strings(&mut call.arguments);
+        }
+        changed
+      } else if pass == 0 && message.role == "assistant" {
+        truncate_text_tool_inputs(message)
+      } else {
+        false
+      };
+      if changed {
+        touched[idx] = true;
+        total = total + estimate_message_tokens(message) - before;
+      }
+    }
+  }
+  stats.compacted_messages = touched.iter().filter(|t| **t).count() as u32;
+  stats.tokens_after = total;
+  stats
+}
+
+fn truncate_observation(message: &mut ChatMessage) -> bool {
+  let chars = message.content.chars().count();
+  if chars <= TRUNCATED_OBSERVATION_CHARS {
+    return false;
+  }
+  let head = message.content.chars().take(TRUNCATED_OBSERVATION_CHARS).collect::<String>();
+  message.content = format!(
+    "{head}\n...[compacted: {} more characters omitted; call the tool again if needed]",
+    chars - TRUNCATED_OBSERVATION_CHARS
+  );
+  true
+}
+
+fn stub_observation(message: &mut ChatMessage) -> bool {
+  if message.content.starts_with("[compacted observation") {
+    return false;
+  }
+  let tool = message.name.clone().unwrap_or_else(|| "tool".to_string());
+  let first_line = message
+    .content
+    .lines()
+    .find(|l| !l.trim().is_empty() && !l.starts_with("OBSERVATION"))
+    .unwrap_or("")
+    .chars()
+    .take(80)
+    .collect::<String>();
+  let replaced = format!("[compacted observation from {tool}] {first_line}");
+  if replaced.len() >= message.content.len() {
+    return false;
+  }
+  message.content = if message.role == "user" {
+    format!("OBSERVATION:\n{replaced}")
+  } else {
+    replaced
+  };
+  true
+}
+
+fn is_line_with_prefix(line: &str, prefix: &str) -> bool {
+  line
+    .trim_start()
+    .get(..prefix.len())
+    .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+}
+
+/// Text-protocol counterpart of truncating native tool-call arguments: the
+/// body of each `INPUT:` line (up to the next `ACTION:`) has its long strings
+/// cut, or is cut as plain text when it is not JSON.
+fn truncate_text_tool_inputs(message: &mut ChatMessage) -> bool {
+  let mut out: Vec<String> = Vec::new();
+  let mut changed = false;
+  let mut lines = message.content.lines().peekable();
+  while let Some(line) = lines.next() {
+    if !is_line_with_prefix(line, "INPUT:") {
+      out.push(line.to_string());
+      continue;
+    }
+    let mut segment = vec![line];
+    while let Some(next) = lines.next_if(|next| !is_line_with_prefix(next, "ACTION:")) {
+      segment.push(next);
+    }
+    let body = segment.join("\n");
+    let body = body.trim_start()["INPUT:".len()..].trim();
+    let compacted = match serde_json::from_str::<Value>(body) {
+      Ok(mut value) => truncate_long_strings(&mut value).then(|| value.to_string()),
+      Err(_) => {
+        let mut raw = Value::String(body.to_string());
+        truncate_long_strings(&mut raw).then(|| raw.as_str().unwrap_or_default().to_string())
+      }
+    };
+    match compacted {
+      Some(body) => {
+        changed = true;
+        out.push(format!("INPUT: {body}"));
+      }
+      None => out.extend(segment.iter().map(|l| l.to_string())),
+    }
+  }
+  if changed {
+    message.content = out.join("\n");
+  }
+  changed
+}
+
+fn truncate_long_strings(value: &mut Value) -> bool {
+  match value {
+    Value::String(s) if s.chars().count() > TRUNCATED_ARGUMENT_CHARS => {
+      let omitted = s.chars().count() - TRUNCATED_ARGUMENT_CHARS;
+      let head = s.chars().take(TRUNCATED_ARGUMENT_CHARS).collect::<String>();
+      *s = format!("{head}...[compacted: {omitted} characters omitted]");
+      true
+    }
+    Value::Array(items) => {
+      let mut changed = false;
+      for item in items.iter_mut() {
+        changed |= truncate_long_strings(item);
+      }
+      changed
+    }
+    Value::Object(map) => {
+      let mut changed = false;
+      for item in map.values_mut() {
+        changed |= truncate_long_strings(item);
+      }
+      changed
+    }
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_cjk_per_character() {
+    assert_eq!(estimate_tokens("你好，世界"), 5);
+    assert_eq!(estimate_tokens("abcdefgh"), 2);
+    assert_eq!(estimate_tokens("林默abcd"), 3);
+  }
+
+  #[test]
+  fn compacts_old_observations_but_keeps_latest_turn() {
+    let long = "章".repeat(3000);
+    let mut messages = vec![
+      ChatMessage::new("system", "rules"),
+      ChatMessage::new("user", "write"),
+      ChatMessage::new("assistant", "ACTION: fs_read_text"),
+      ChatMessage::new("user", format!("OBSERVATION:\n{long}")),
+      ChatMessage::new("assistant", "ACTION: fs_read_text"),
+      ChatMessage::new("user", format!("OBSERVATION:\n{long}")),
+    ];
+    let stats = compact_messages(&mut messages, 4000);
+    assert_eq!(stats.compacted_messages, 1);
+    assert!(stats.tokens_after < stats.tokens_before);
+    assert!(messages[3].content.contains("[compacted"));
+    assert_eq!(messages[5].content.chars().count(), 3000 + "OBSERVATION:\n".len());
+  }
+
+  #[test]
+  fn compacts_text_protocol_tool_inputs() {
+    let chapter = "章".repeat(3000);
+    let write = format!(
+      "写好了。\nACTION: fs_write_text\nINPUT: {}",
+      serde_json::json!({ "path": "stories/01.md", "content": chapter })
+    );
+    let mut messages = vec![
+      ChatMessage::new("user", "write"),
+      ChatMessage::new("assistant", write.clone()),
+      ChatMessage::new("user", "OBSERVATION:\n{\"ok\":true}"),
+      ChatMessage::new("assistant", write),
+    ];
+    let stats = compact_messages(&mut messages, 4000);
+    assert_eq!(stats.compacted_messages, 1);
+    let old = &messages[1].content;
+    assert!(old.starts_with("写好了。\nACTION: fs_write_text\nINPUT: {"));
+    assert!(old.contains("stories/01.md") && old.contains("[compacted: 2800 characters omitted]"));
+    assert!(messages[3].content.contains(&chapter));
+  }
+}
@@ -0,0 +1,255 @@
+//! Sampling parameters set on a provider and overridden per agent, and their
+//! translation into each provider API's request fields.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Unset fields are left to the provider's defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct GenerationParams {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub top_p: Option<f32>,
+  /// Output tokens of a whole reply, continuations included.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub max_tokens: Option<u32>,
+  /// Output limit of the model from the provider catalog; one request asks
+  /// for at most this many of the `max_tokens`.
+  #[serde(skip)]
+  pub output_limit: Option<u32>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub stop: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub presence_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub frequency_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub seed: Option<i64>,
+  /// `low`, `medium` or `high`, for models that reason before answering.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reasoning_effort: Option<String>,
+}
+
+/// Request fields for one provider API plus the parameters it cannot take.
+#[derive(Debug, Default)]
+pub struct TranslatedParams {
+  pub fields: Map<String, Value>,
+  pub dropped: Vec<&'static str>,
+}
+
+impl TranslatedParams {
+  pub fn apply_to(&self, target: &mut Value) {
+    if let Some(obj) = target.as_object_mut() {
+      obj.extend(self.fields.clone());
+    }
+  }
+}
+
+impl GenerationParams {
+  /// `self` with every field that `over` sets replaced by its value.
+  pub fn merged(&self, over: &GenerationParams) -> GenerationParams {
+    GenerationParams {
+      top_p: over.top_p.or(self.top_p),
+      max_tokens: over.max_tokens.or(self.max_tokens),
+      output_limit: over.output_limit.or(self.output_limit),
+      stop: if over.stop.is_empty() { self.stop.clone() } else { over.stop.clone() },
+      presence_penalty: over.presence_penalty.or(self.presence_penalty),
+      frequency_penalty: over.frequency_penalty.or(self.frequency_penalty),
+      seed: over.seed.or(self.seed),
+      reasoning_effort: over.reasoning_effort.clone().or_else(|| self.reasoning_effort.clone()),
+    }
+  }
+
+  /// `max_tokens` for a single request.
+  pub fn request_max_tokens(&self) -> Option<u32> {
+    match (self.max_tokens, self.output_limit) {
+      (Some(requested), Some(limit)) => Some(requested.min(limit)),
+      (requested, _) => requested,
+    }
+  }
+
+  /// Whether a reply cut off at the output limit after `spent` output tokens
+  /// is continued with another request: always without `max_tokens`, and
+  /// until the reply has used it up otherwise.
+  pub fn continues_cut_off_replies(&self, spent: u64) -> bool {
+    self.max_tokens.is_none_or(|budget| spent < u64::from(budget))
+  }
+
+  fn stop_sequences(&self, limit: usize) -> Vec<&str> {
+    self.stop.iter().map(|s| s.as_str()).filter(|s| !s.is_empty()).take(limit).collect()
+  }
+
+  fn effort(&self) -> Option<&str> {
+    self.reasoning_effort.as_deref().map(str::trim).filter(|e| !e.is_empty())
+  }
+
+  /// Top-level fields of an OpenAI-style chat completion.
+  pub fn openai(&self) -> TranslatedParams {
+    let mut out = TranslatedParams::default();
+    let f = &mut out.fields;
+    if let Some(v) = self.top_p {
+      f.insert("top_p".into(), json!(v));
+    }
+    if let Some(v) = self.request_max_tokens() {
+      f.insert("max_tokens".into(), json!(v));
+    }
+    let stop = self.stop_sequences(4);
+    if !stop.is_empty() {
+      f.insert("stop".into(), json!(stop));
+    }
+    if let Some(v) = self.presence_penalty {
+      f.insert("presence_penalty".into(), json!(v));
+    }
+    if let Some(v) = self.frequency_penalty {
+      f.insert("frequency_penalty".into(), json!(v));
+    }
+    if let Some(v) = self.seed {
+      f.insert("seed".into(), json!(v));
+    }
+    if let Some(v) = self.effort() {
+      f.insert("reasoning_effort".into(), json!(v));
+    }
+    out
+  }
+
+  /// Top-level fields of an Anthropic message request; `max_tokens` is left
+  /// to the caller since the API requires it. Extended thinking would have to
+  /// be replayed with every tool result, so reasoning effort is dropped.
+  pub fn anthropic(&self) -> TranslatedParams {
+    let mut out = TranslatedParams::default();
+    if let Some(v) = self.top_p {
+      out.fields.insert("top_p".into(), json!(v));
+    }
+    let stop = self.stop_sequences(usize::MAX);
+    if !stop.is_empty() {
+      out.fields.insert("stop_sequences".into(), json!(stop));
+    }
+    out.dropped = unsupported(&[
+      ("presence_penalty", self.presence_penalty.is_some()),
+      ("frequency_penalty", self.frequency_penalty.is_some()),
+      ("seed", self.seed.is_some()),
+      ("reasoning_effort", self.effort().is_some()),
+    ]);
+    out
+  }
+
+  /// Fields of Gemini's `generationConfig`; reasoning effort becomes a
+  /// thinking budget.
+  pub fn gemini(&self) -> TranslatedParams {
+    let mut out = TranslatedParams::default();
+    let f = &mut out.fields;
+    if let Some(v) = self.top_p {
+      f.insert("topP".into(), json!(v));
+    }
+    if let Some(v) = self.request_max_tokens() {
+      f.insert("maxOutputTokens".into(), json!(v));
+    }
+    let stop = self.stop_sequences(5);
+    if !stop.is_empty() {
+      f.insert("stopSequences".into(), json!(stop));
+    }
+    if let Some(v) = self.presence_penalty {
+      f.insert("presencePenalty".into(), json!(v));
+    }
+    if let Some(v) = self.frequency_penalty {
+      f.insert("frequencyPenalty".into(), json!(v));
+    }
+    if let Some(v) = self.seed {
+      f.insert("seed".into(), json!(v));
+    }
+    match self.effort() {
+      Some("low") => {
+        f.insert("thinkingConfig".into(), json!({ "thinkingBudget": 1024 }));
+      }
+      Some("medium") => {
+        f.insert("thinkingConfig".into(), json!({ "thinkingBudget": 8192 }));
+      }
+      Some("high") => {
+        f.insert("thinkingConfig".into(), json!({ "thinkingBudget": 24576 }));
+      }
+      Some(_) => out.dropped.push("reasoning_effort"),
+      None => {}
+    }
+    out
+  }
+
+  /// Fields of Ollama's `options`; `max_tokens` becomes `num_predict`.
+  pub fn ollama(&self) -> TranslatedParams {
+    let mut out = TranslatedParams::default();
+    let f = &mut out.fields;
+    if let Some(v) = self.top_p {
+      f.insert("top_p".into(), json!(v));
+    }
+    if let Some(v) = self.request_max_tokens() {
+      f.insert("num_predict".into(), json!(v));
+    }
+    let stop = self.stop_sequences(usize::MAX);
+    if !stop.is_empty() {
+      f.insert("stop".into(), json!(stop));
+    }
+    if let Some(v) = self.presence_penalty {
+      f.insert("presence_penalty".into(), json!(v));
+    }
+    if let Some(v) = self.frequency_penalty {
+      f.insert("frequency_penalty".into(), json!(v));
+    }
+    if let Some(v) = self.seed {
+      f.insert("seed".into(), json!(v));
+    }
+    out.dropped = unsupported(&[("reasoning_effort", self.effort().is_some())]);
+    out
+  }
+}
+
+/// Names of the parameters that are set but have no equivalent.
+fn unsupported(fields: &[(&'static str, bool)]) -> Vec<&'static str> {
+  fields.iter().filter(|(_, set)| *set).map(|(name, _)| *name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn merges_overrides_and_translates_per_api() {
+    let provider = GenerationParams {
+      top_p: Some(0.9),
+      max_tokens: Some(4096),
+      stop: vec!["END".to_string()],
+      seed: Some(7),
+      ..Default::default()
+    };
+    let agent = GenerationParams {
+      max_tokens: Some(1024),
+      presence_penalty: Some(0.5),
+      reasoning_effort: Some("high".to_string()),
+      ..Default::default()
+    };
+    let effective = provider.merged(&agent);
+    assert_eq!(effective.max_tokens, Some(1024));
+    assert!(effective.continues_cut_off_replies(1000));
+    assert!(!effective.continues_cut_off_replies(1024));
+    assert!(GenerationParams::default().continues_cut_off_replies(u64::MAX));
+    let capped = GenerationParams {
+      output_limit: Some(512),
+      ..effective.clone()
+    };
+    assert_eq!(capped.request_max_tokens(), Some(512));
+    assert_eq!(capped.openai().fields["max_tokens"], 512);
+    assert!(capped.continues_cut_off_replies(512));
+    assert_eq!(effective.stop, vec!["END"]);
+
+    let openai = effective.openai();
+    assert_eq!(openai.fields["reasoning_effort"], "high");
+    assert!(openai.dropped.is_empty());
+
+    let anthropic = effective.anthropic();
+    assert_eq!(anthropic.fields["stop_sequences"], json!(["END"]));
+    assert_eq!(anthropic.dropped, vec!["presence_penalty", "seed", "reasoning_effort"]);
+
+    assert_eq!(effective.gemini().fields["thinkingConfig"]["thinkingBudget"], 24576);
+    let ollama = effective.ollama();
+    assert_eq!(ollama.fields["num_predict"], 1024);
+    assert_eq!(ollama.dropped, vec!["reasoning_effort"]);
+  }
+}
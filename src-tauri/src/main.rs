@@ -2,16 +2,32 @@
 
 mod commands;
 mod ai_types;
+mod agent_memory;
 mod agent_system;
 mod app_data;
 mod app_settings;
 mod agents;
+mod cancel_token;
 mod chat_history;
 mod secrets;
 mod state;
 mod modification_types;
 mod ai_response_parser;
 mod prompt_config;
+mod prompt_tools;
+mod generation_params;
+mod provider_catalog;
+mod provider_retry;
+mod tool_schema;
+mod usage_log;
+mod text_search;
+mod context_budget;
+mod run_checkpoint;
+mod run_deadline;
+mod run_trace;
+mod scripted_provider;
+mod story_index;
+mod workspace_overlay;
 mod skills;
 mod mcp;
 mod book_split;
@@ -42,6 +58,7 @@ fn main() {
       commands::validate_novel_task_quality,
       commands::read_text,
       commands::write_text,
+      commands::search_text,
       commands::create_file,
       commands::create_dir,
       commands::delete_entry,
@@ -51,6 +68,8 @@ fn main() {
       commands::get_api_key_status,
       commands::set_api_key,
       commands::test_provider_connectivity,
+      commands::list_provider_models,
+      commands::get_usage_report,
       commands::get_agents,
       commands::get_writing_assistants,
       commands::set_agents,
@@ -68,6 +87,15 @@ fn main() {
       commands::restore_history_snapshot,
       commands::chat_generate_stream,
       commands::chat_cancel_stream,
+      commands::chat_resume_stream,
+      commands::agent_tool_approve,
+      commands::list_agent_runs,
+      commands::load_agent_run,
+      commands::story_search,
+      commands::list_memory_items,
+      commands::save_memory_item,
+      commands::delete_memory_item,
+      commands::replay_agent_run,
       commands::ai_assistance_generate,
       commands::risk_scan_content,
       commands::get_skills,
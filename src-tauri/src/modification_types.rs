@@ -42,6 +42,16 @@ pub struct FileModification {
     pub original_content: String,
     pub modifications: Vec<Modification>,
     pub status: FileModificationStatus,
+    /// Set when accepting the change creates or deletes the whole file.
+    #[serde(rename = "fileOp", default, skip_serializing_if = "Option::is_none")]
+    pub file_op: Option<FileOperation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileOperation {
+    Create,
+    Delete,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -5,6 +5,8 @@ use std::sync::OnceLock;
 pub struct AgentPromptConfig {
   pub builtin_workflow_appendix: String,
   pub runtime_prompt_template: String,
+  pub tool_protocol_text: String,
+  pub tool_protocol_native: String,
   pub mode_auto_apply: String,
   pub mode_review: String,
 }
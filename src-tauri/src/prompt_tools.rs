@@ -0,0 +1,143 @@
+//! Workspace-defined prompt tools. Every `.novel/tools/*.toml` declares one
+//! tool; calling it fills the prompt template with the arguments and answers
+//! with a single model completion.
+//!
+//! ```toml
+//! name = "dialogue_punch_up"
+//! description = "Rewrite a dialogue passage so it is sharper."
+//! system = "You are a dialogue editor."   # optional
+//! temperature = 0.8                        # optional
+//! prompt = """
+//! Punch up this dialogue, keep the speakers: {{dialogue}}
+//! Tone: {{tone}}
+//! """
+//!
+//! [input_schema]
+//! type = "object"
+//! required = ["dialogue"]
+//! properties.dialogue = { type = "string", minLength = 1 }
+//! properties.tone = { type = "string" }
+//! ```
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PromptTool {
+  pub name: String,
+  pub description: String,
+  /// JSON Schema of the arguments; must describe an object.
+  #[serde(default = "default_input_schema")]
+  pub input_schema: Value,
+  /// Template with `{{argument}}` placeholders.
+  pub prompt: String,
+  #[serde(default)]
+  pub system: Option<String>,
+  #[serde(default)]
+  pub temperature: Option<f32>,
+}
+
+fn default_input_schema() -> Value {
+  serde_json::json!({ "type": "object", "properties": {}, "additionalProperties": false })
+}
+
+impl PromptTool {
+  fn check(&self) -> Result<(), String> {
+    let valid_name = self.name.starts_with(|c: char| c.is_ascii_lowercase())
+      && self.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid_name {
+      return Err(format!("tool name must be snake_case: {}", self.name));
+    }
+    if self.description.trim().is_empty() {
+      return Err("description is required".to_string());
+    }
+    if self.prompt.trim().is_empty() {
+      return Err("prompt is required".to_string());
+    }
+    if self.input_schema.get("type").and_then(|v| v.as_str()) != Some("object") {
+      return Err("input_schema must have type = \"object\"".to_string());
+    }
+    Ok(())
+  }
+
+  /// The prompt with every `{{name}}` replaced by that argument; strings are
+  /// inserted verbatim, other values as JSON, missing ones as empty text.
+  pub fn render(&self, args: &Value) -> String {
+    let mut out = String::new();
+    let mut rest = self.prompt.as_str();
+    while let Some(start) = rest.find("{{") {
+      let Some(len) = rest[start + 2..].find("}}") else {
+        break;
+      };
+      out.push_str(&rest[..start]);
+      let key = rest[start + 2..start + 2 + len].trim();
+      match args.get(key) {
+        Some(Value::String(s)) => out.push_str(s),
+        Some(Value::Null) | None => {}
+        Some(other) => out.push_str(&other.to_string()),
+      }
+      rest = &rest[start + 2 + len + 2..];
+    }
+    out.push_str(rest);
+    out.trim().to_string()
+  }
+}
+
+/// Loads `.novel/tools/*.toml` in file name order. Files that fail to parse or
+/// validate are skipped and reported as `path: error`.
+pub fn load(workspace_root: &Path) -> (Vec<PromptTool>, Vec<String>) {
+  let dir = workspace_root.join(".novel").join("tools");
+  let Ok(entries) = fs::read_dir(&dir) else {
+    return (Vec::new(), Vec::new());
+  };
+  let mut paths = entries
+    .filter_map(|e| e.ok().map(|e| e.path()))
+    .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("toml"))
+    .collect::<Vec<_>>();
+  paths.sort();
+  let mut tools: Vec<PromptTool> = Vec::new();
+  let mut errors = Vec::new();
+  for path in paths {
+    let parsed = fs::read_to_string(&path)
+      .map_err(|e| format!("read failed: {e}"))
+      .and_then(|raw| toml::from_str::<PromptTool>(&raw).map_err(|e| format!("parse failed: {e}")))
+      .and_then(|tool| tool.check().map(|_| tool));
+    match parsed {
+      Ok(tool) if tools.iter().any(|t| t.name == tool.name) => {
+        errors.push(format!("{}: duplicate tool name {}", path.display(), tool.name))
+      }
+      Ok(tool) => tools.push(tool),
+      Err(e) => errors.push(format!("{}: {e}", path.display())),
+    }
+  }
+  (tools, errors)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_and_renders_template() {
+    let tool: PromptTool = toml::from_str(
+      r#"
+name = "name_generator"
+description = "Suggest character names."
+prompt = "Give {{count}} names for a {{ role }} in {{setting}}.{{missing}}"
+
+[input_schema]
+type = "object"
+required = ["role"]
+properties.role = { type = "string" }
+properties.count = { type = "integer" }
+"#,
+    )
+    .unwrap();
+    assert!(tool.check().is_ok());
+    assert_eq!(tool.input_schema["properties"]["count"]["type"], "integer");
+    let text = tool.render(&serde_json::json!({ "role": "侠客", "count": 3, "setting": "江南" }));
+    assert_eq!(text, "Give 3 names for a 侠客 in 江南.");
+  }
+}
@@ -0,0 +1,229 @@
+//! Model catalogs reported by provider endpoints (`/models` and friends),
+//! normalized to one shape and cached per provider in `AppState`.
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Catalogs older than this are fetched again.
+pub const CATALOG_TTL_MS: i64 = 60 * 60 * 1000;
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct CatalogModel {
+  pub id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub display_name: Option<String>,
+  /// Input context in tokens, when the endpoint reports it.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub context_length: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub max_output_tokens: Option<u32>,
+  /// Features the endpoint advertises, e.g. `tools`, `vision`, `streaming`,
+  /// `embeddings`; empty when it says nothing.
+  pub capabilities: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProviderCatalog {
+  pub provider_id: String,
+  /// The catalog is dropped when the provider's `base_url` changes.
+  pub base_url: String,
+  pub fetched_at: i64,
+  pub models: Vec<CatalogModel>,
+}
+
+impl ProviderCatalog {
+  pub fn is_fresh(&self, base_url: &str, now_ms: i64) -> bool {
+    self.base_url == base_url && now_ms - self.fetched_at < CATALOG_TTL_MS
+  }
+
+  pub fn contains(&self, model: &str) -> bool {
+    has_model(&self.models, model)
+  }
+}
+
+/// Whether `model` is listed. Gemini's `models/` prefix and Ollama's implicit
+/// `:latest` tag are ignored.
+pub fn has_model(models: &[CatalogModel], model: &str) -> bool {
+  let model = model.trim();
+  let model = model.strip_prefix("models/").unwrap_or(model);
+  models
+    .iter()
+    .any(|m| m.id == model || (!model.contains(':') && m.id == format!("{model}:latest")))
+}
+
+fn as_u32(v: &Value) -> Option<u32> {
+  v.as_u64().map(|n| n.min(u32::MAX as u64) as u32).filter(|n| *n > 0)
+}
+
+fn push_unique(out: &mut Vec<String>, cap: &str) {
+  if !out.iter().any(|c| c == cap) {
+    out.push(cap.to_string());
+  }
+}
+
+/// OpenAI-style `{ data: [{ id, ... }] }`. Plain OpenAI only gives ids;
+/// aggregators such as OpenRouter or Groq add context sizes and features.
+pub fn parse_openai_models(value: &Value) -> Vec<CatalogModel> {
+  let Some(items) = value["data"].as_array() else {
+    return Vec::new();
+  };
+  items
+    .iter()
+    .filter_map(|m| {
+      let id = m["id"].as_str()?.to_string();
+      let mut capabilities = Vec::new();
+      if let Some(params) = m["supported_parameters"].as_array() {
+        if params.iter().any(|p| p.as_str() == Some("tools")) {
+          push_unique(&mut capabilities, "tools");
+        }
+      }
+      if let Some(modalities) = m["architecture"]["input_modalities"].as_array() {
+        if modalities.iter().any(|p| p.as_str() == Some("image")) {
+          push_unique(&mut capabilities, "vision");
+        }
+      }
+      Some(CatalogModel {
+        display_name: m["name"].as_str().map(|s| s.to_string()),
+        context_length: as_u32(&m["context_length"]).or_else(|| as_u32(&m["context_window"])),
+        max_output_tokens: as_u32(&m["top_provider"]["max_completion_tokens"])
+          .or_else(|| as_u32(&m["max_completion_tokens"])),
+        capabilities,
+        id,
+      })
+    })
+    .collect()
+}
+
+/// Anthropic `{ data: [{ id, display_name, max_input_tokens?, max_tokens?,
+/// capabilities? }] }`.
+pub fn parse_anthropic_models(value: &Value) -> Vec<CatalogModel> {
+  let Some(items) = value["data"].as_array() else {
+    return Vec::new();
+  };
+  items
+    .iter()
+    .filter_map(|m| {
+      let id = m["id"].as_str()?.to_string();
+      let mut capabilities = Vec::new();
+      if let Some(caps) = m["capabilities"].as_object() {
+        for (name, cap) in caps {
+          if cap["supported"].as_bool() == Some(true) {
+            push_unique(&mut capabilities, name);
+          }
+        }
+      }
+      Some(CatalogModel {
+        display_name: m["display_name"].as_str().map(|s| s.to_string()),
+        context_length: as_u32(&m["max_input_tokens"]),
+        max_output_tokens: as_u32(&m["max_tokens"]),
+        capabilities,
+        id,
+      })
+    })
+    .collect()
+}
+
+/// Gemini `{ models: [{ name: "models/…", inputTokenLimit,
+/// outputTokenLimit, supportedGenerationMethods }] }`.
+pub fn parse_gemini_models(value: &Value) -> Vec<CatalogModel> {
+  let Some(items) = value["models"].as_array() else {
+    return Vec::new();
+  };
+  items
+    .iter()
+    .filter_map(|m| {
+      let name = m["name"].as_str()?;
+      let mut capabilities = Vec::new();
+      for method in m["supportedGenerationMethods"].as_array().into_iter().flatten() {
+        match method.as_str() {
+          Some("generateContent") => push_unique(&mut capabilities, "chat"),
+          Some("streamGenerateContent") => push_unique(&mut capabilities, "streaming"),
+          Some("embedContent") => push_unique(&mut capabilities, "embeddings"),
+          _ => {}
+        }
+      }
+      Some(CatalogModel {
+        id: name.strip_prefix("models/").unwrap_or(name).to_string(),
+        display_name: m["displayName"].as_str().map(|s| s.to_string()),
+        context_length: as_u32(&m["inputTokenLimit"]),
+        max_output_tokens: as_u32(&m["outputTokenLimit"]),
+        capabilities,
+      })
+    })
+    .collect()
+}
+
+/// Ollama `GET /api/tags`; newer servers also list `capabilities`.
+pub fn parse_ollama_models(value: &Value) -> Vec<CatalogModel> {
+  let Some(items) = value["models"].as_array() else {
+    return Vec::new();
+  };
+  items
+    .iter()
+    .filter_map(|m| {
+      let id = m["name"].as_str().or_else(|| m["model"].as_str())?.to_string();
+      let capabilities = m["capabilities"]
+        .as_array()
+        .map(|caps| caps.iter().filter_map(|c| c.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default();
+      let details = [
+        m["details"]["parameter_size"].as_str().unwrap_or_default(),
+        m["details"]["quantization_level"].as_str().unwrap_or_default(),
+      ]
+      .iter()
+      .filter(|s| !s.is_empty())
+      .copied()
+      .collect::<Vec<_>>()
+      .join(" ");
+      Some(CatalogModel {
+        display_name: Some(details).filter(|s| !s.is_empty()),
+        capabilities,
+        id,
+        ..Default::default()
+      })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_catalogs_and_matches_aliases() {
+    let openrouter = parse_openai_models(&serde_json::json!({
+      "data": [{
+        "id": "anthropic/claude-3.5-sonnet",
+        "context_length": 200000,
+        "supported_parameters": ["tools", "temperature"],
+        "architecture": { "input_modalities": ["text", "image"] }
+      }, { "id": "gpt-4o-mini" }]
+    }));
+    assert_eq!(openrouter[0].context_length, Some(200000));
+    assert_eq!(openrouter[0].capabilities, vec!["tools", "vision"]);
+    assert!(openrouter[1].capabilities.is_empty());
+
+    let gemini = parse_gemini_models(&serde_json::json!({
+      "models": [{
+        "name": "models/gemini-2.0-flash",
+        "inputTokenLimit": 1048576,
+        "outputTokenLimit": 8192,
+        "supportedGenerationMethods": ["generateContent", "countTokens"]
+      }]
+    }));
+    let ollama = parse_ollama_models(&serde_json::json!({
+      "models": [{ "name": "qwen2.5:latest", "details": { "parameter_size": "7.6B" } }]
+    }));
+    let catalog = ProviderCatalog {
+      provider_id: "p".to_string(),
+      base_url: String::new(),
+      fetched_at: 0,
+      models: gemini.into_iter().chain(ollama).collect(),
+    };
+    assert_eq!(catalog.models[0].capabilities, vec!["chat"]);
+    assert_eq!(catalog.models[1].display_name.as_deref(), Some("7.6B"));
+    assert!(catalog.contains("models/gemini-2.0-flash"));
+    assert!(catalog.contains("qwen2.5"));
+    assert!(!catalog.contains("qwen2.5:14b"));
+  }
+}
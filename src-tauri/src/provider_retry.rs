@@ -0,0 +1,101 @@
+//! Retry policy for provider requests: which failures are worth another
+//! attempt and how long to wait before it.
+
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// Attempts per request when the settings do not say otherwise.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+/// Longest wait between attempts, even if the server asks for more.
+pub const MAX_DELAY: Duration = Duration::from_secs(60);
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Rate limits, timeouts and transient server errors; 529 is Anthropic's
+/// "overloaded".
+pub fn is_retryable_status(status: u16) -> bool {
+  matches!(status, 408 | 425 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+/// Parses a `Retry-After` value given in seconds or as an HTTP date; waits
+/// beyond `MAX_DELAY` are clamped to it.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+  let value = value.trim();
+  if let Ok(secs) = value.parse::<f64>() {
+    let delay = Duration::try_from_secs_f64(secs).ok().or_else(|| (secs > 0.0).then_some(MAX_DELAY))?;
+    return Some(delay.min(MAX_DELAY));
+  }
+  let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+  Some((at - now).to_std().unwrap_or(Duration::ZERO).min(MAX_DELAY))
+}
+
+/// Exponential backoff before attempt `attempt + 1`, scaled into its upper
+/// half by `jitter` (0..1) so parallel runs do not retry in lockstep.
+pub fn backoff_delay(attempt: u32, jitter: f64) -> Duration {
+  let exp = BASE_DELAY.saturating_mul(1u32 << attempt.saturating_sub(1).min(16));
+  exp.min(MAX_BACKOFF).mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Whether a failed model call should move on to the next provider of the
+/// fallback chain: transport failures, retryable statuses and quota or
+/// billing errors. Failures after output started streaming never qualify.
+pub fn is_fallback_error(message: &str) -> bool {
+  if message.starts_with("request failed:") {
+    return true;
+  }
+  let Some(rest) = message.strip_prefix("http ") else {
+    return false;
+  };
+  let status = rest
+    .chars()
+    .take_while(|c| c.is_ascii_digit())
+    .collect::<String>()
+    .parse::<u16>()
+    .unwrap_or_default();
+  let lowered = rest.to_lowercase();
+  is_retryable_status(status)
+    || status == 402
+    || ["quota", "billing", "credit balance", "rate limit"].iter().any(|k| lowered.contains(k))
+}
+
+/// Uniform in 0..1, from the random bits of a v4 UUID; clock-based values
+/// are too coarse on some platforms to tell parallel runs apart.
+pub fn jitter() -> f64 {
+  let bytes = uuid::Uuid::new_v4().into_bytes();
+  // The version and variant bits live in bytes 6 and 8; byte 8 lands in the
+  // low bits that the shift drops.
+  let bits = u64::from_le_bytes(bytes[8..].try_into().unwrap_or_default());
+  (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff_and_retry_after() {
+    assert_eq!(backoff_delay(1, 1.0), Duration::from_secs(1));
+    assert_eq!(backoff_delay(3, 0.0), Duration::from_secs(2));
+    assert_eq!(backoff_delay(10, 1.0), MAX_BACKOFF);
+
+    let now = DateTime::parse_from_rfc3339("2015-10-21T07:27:30Z").unwrap().with_timezone(&Utc);
+    assert_eq!(parse_retry_after("7", now), Some(Duration::from_secs(7)));
+    assert_eq!(
+      parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+      Some(Duration::from_secs(30))
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+    assert_eq!(parse_retry_after("1e30", now), Some(MAX_DELAY));
+    assert_eq!(parse_retry_after("inf", now), Some(MAX_DELAY));
+    assert_eq!(parse_retry_after("-3", now), None);
+    assert_eq!(parse_retry_after("NaN", now), None);
+    assert!(is_retryable_status(429) && !is_retryable_status(401));
+    assert!(is_fallback_error("http 429 Too Many Requests: slow down"));
+    assert!(is_fallback_error(r#"http 403: {"error":{"code":"insufficient_quota"}}"#));
+    assert!(!is_fallback_error("http 400: invalid model"));
+    assert!(!is_fallback_error("stream read failed: reset"));
+    let samples = (0..64).map(|_| jitter()).collect::<Vec<_>>();
+    assert!(samples.iter().all(|j| (0.0..1.0).contains(j)));
+    assert!(samples.iter().any(|j| *j != samples[0]));
+  }
+}
@@ -0,0 +1,139 @@
+//! Checkpoints of interrupted agent runs, kept under `agent_checkpoints` in
+//! `.novel/state/session-state.json` (the planner owns the `sessions` key of
+//! the same file).
+
+use crate::ai_types::ChatMessage;
+use crate::workspace_overlay::WorkspaceOverlay;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CHECKPOINTS_KEY: &str = "agent_checkpoints";
+/// Older checkpoints are dropped when a new one is saved.
+const MAX_CHECKPOINTS: usize = 5;
+
+/// Where a run stood at its last step boundary.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RunCheckpoint {
+  /// Steps completed; a resumed run continues with `step + 1`.
+  pub step: u32,
+  /// Full prompt, including the runtime system message and tool observations.
+  pub messages: Vec<ChatMessage>,
+  /// Review-mode draft edits made so far.
+  #[serde(default)]
+  pub overlay: Option<WorkspaceOverlay>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionCheckpoint {
+  pub stream_id: String,
+  pub agent_id: String,
+  pub provider_id: String,
+  /// `cancelled` or `timeout`.
+  pub reason: String,
+  pub saved_at: i64,
+  #[serde(flatten)]
+  pub run: RunCheckpoint,
+}
+
+fn state_path(root: &Path) -> PathBuf {
+  root.join(".novel").join("state").join("session-state.json")
+}
+
+fn read_state(root: &Path) -> serde_json::Map<String, Value> {
+  fs::read_to_string(state_path(root))
+    .ok()
+    .and_then(|raw| serde_json::from_str::<Value>(&raw).ok())
+    .and_then(|v| match v {
+      Value::Object(map) => Some(map),
+      _ => None,
+    })
+    .unwrap_or_default()
+}
+
+fn write_state(root: &Path, state: serde_json::Map<String, Value>) -> Result<(), String> {
+  let path = state_path(root);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).map_err(|e| format!("create state dir failed: {e}"))?;
+  }
+  let raw = serde_json::to_string_pretty(&Value::Object(state))
+    .map_err(|e| format!("serialize session state failed: {e}"))?;
+  fs::write(path, raw).map_err(|e| format!("write session state failed: {e}"))
+}
+
+fn checkpoints(state: &serde_json::Map<String, Value>) -> Vec<SessionCheckpoint> {
+  state
+    .get(CHECKPOINTS_KEY)
+    .and_then(|v| v.as_object())
+    .map(|map| {
+      map
+        .values()
+        .filter_map(|v| serde_json::from_value::<SessionCheckpoint>(v.clone()).ok())
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn store_checkpoints(state: &mut serde_json::Map<String, Value>, mut items: Vec<SessionCheckpoint>) {
+  items.sort_by_key(|cp| std::cmp::Reverse(cp.saved_at));
+  items.truncate(MAX_CHECKPOINTS);
+  let map = items
+    .into_iter()
+    .filter_map(|cp| Some((cp.stream_id.clone(), serde_json::to_value(&cp).ok()?)))
+    .collect::<serde_json::Map<_, _>>();
+  state.insert(CHECKPOINTS_KEY.to_string(), Value::Object(map));
+}
+
+pub fn save(
+  root: &Path,
+  stream_id: &str,
+  agent_id: &str,
+  provider_id: &str,
+  reason: &str,
+  run: RunCheckpoint,
+) -> Result<SessionCheckpoint, String> {
+  let checkpoint = SessionCheckpoint {
+    stream_id: stream_id.to_string(),
+    agent_id: agent_id.to_string(),
+    provider_id: provider_id.to_string(),
+    reason: reason.to_string(),
+    saved_at: Utc::now().timestamp_millis(),
+    run,
+  };
+  let mut state = read_state(root);
+  let mut items = checkpoints(&state);
+  items.retain(|cp| cp.stream_id != stream_id);
+  items.push(checkpoint.clone());
+  store_checkpoints(&mut state, items);
+  write_state(root, state)?;
+  Ok(checkpoint)
+}
+
+/// The checkpoint of `stream_id`, or the most recent one when `None`.
+pub fn load(root: &Path, stream_id: Option<&str>) -> Result<SessionCheckpoint, String> {
+  let items = checkpoints(&read_state(root));
+  match stream_id.map(str::trim).filter(|s| !s.is_empty()) {
+    Some(id) => items
+      .into_iter()
+      .find(|cp| cp.stream_id == id)
+      .ok_or_else(|| format!("no checkpoint for stream {id}")),
+    None => items
+      .into_iter()
+      .max_by_key(|cp| cp.saved_at)
+      .ok_or_else(|| "no interrupted run to resume".to_string()),
+  }
+}
+
+pub fn remove(root: &Path, stream_id: &str) -> Result<(), String> {
+  let mut state = read_state(root);
+  let mut items = checkpoints(&state);
+  let before = items.len();
+  items.retain(|cp| cp.stream_id != stream_id);
+  if items.len() == before {
+    return Ok(());
+  }
+  store_checkpoints(&mut state, items);
+  write_state(root, state)
+}
@@ -0,0 +1,146 @@
+//! Time limit of an agent run that stands still while the run waits on the
+//! user, so a slow answer to a tool approval prompt does not count against
+//! the model and tool time the limit is meant for.
+
+use futures_util::future::{self, Either};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[derive(Clone)]
+pub struct RunDeadline {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  limit: Duration,
+  clock: Mutex<Clock>,
+  resumed: Notify,
+}
+
+struct Clock {
+  /// Time counted before the current running stretch.
+  used: Duration,
+  /// Start of the current running stretch; `None` while paused.
+  running_since: Option<Instant>,
+  pauses: u32,
+}
+
+/// Keeps the clock stopped until dropped.
+pub struct PauseGuard {
+  inner: Arc<Inner>,
+}
+
+impl RunDeadline {
+  /// Starts the clock.
+  pub fn new(limit: Duration) -> Self {
+    Self {
+      inner: Arc::new(Inner {
+        limit,
+        clock: Mutex::new(Clock {
+          used: Duration::ZERO,
+          running_since: Some(Instant::now()),
+          pauses: 0,
+        }),
+        resumed: Notify::new(),
+      }),
+    }
+  }
+
+  pub fn limit(&self) -> Duration {
+    self.inner.limit
+  }
+
+  /// Stops the clock until the returned guard is dropped. Pauses nest: the
+  /// clock runs again once every guard is gone.
+  pub fn pause(&self) -> PauseGuard {
+    if let Ok(mut clock) = self.inner.clock.lock() {
+      if let Some(since) = clock.running_since.take() {
+        clock.used += since.elapsed();
+      }
+      clock.pauses += 1;
+    }
+    PauseGuard {
+      inner: self.inner.clone(),
+    }
+  }
+
+  /// Time left before the limit, or `None` while paused.
+  fn remaining(&self) -> Option<Duration> {
+    let clock = self.inner.clock.lock().ok()?;
+    let since = clock.running_since?;
+    Some(self.inner.limit.saturating_sub(clock.used + since.elapsed()))
+  }
+
+  /// Resolves once the running time reaches the limit.
+  async fn expired(&self) {
+    loop {
+      let resumed = self.inner.resumed.notified();
+      match self.remaining() {
+        Some(left) if left.is_zero() => return,
+        Some(left) => tokio::time::sleep(left).await,
+        None => resumed.await,
+      }
+    }
+  }
+
+  /// Awaits `fut` unless the limit runs out first, in which case `fut` is
+  /// dropped and `None` is returned.
+  pub async fn run<F: Future>(&self, fut: F) -> Option<F::Output> {
+    let fut = std::pin::pin!(fut);
+    let expired = std::pin::pin!(self.expired());
+    match future::select(fut, expired).await {
+      Either::Left((output, _)) => Some(output),
+      Either::Right(_) => None,
+    }
+  }
+}
+
+impl Drop for PauseGuard {
+  fn drop(&mut self) {
+    if let Ok(mut clock) = self.inner.clock.lock() {
+      clock.pauses = clock.pauses.saturating_sub(1);
+      if clock.pauses == 0 {
+        clock.running_since = Some(Instant::now());
+      }
+    }
+    self.inner.resumed.notify_waiters();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::time::sleep;
+
+  #[test]
+  fn paused_time_does_not_count_against_the_limit() {
+    tauri::async_runtime::block_on(async {
+      let deadline = RunDeadline::new(Duration::from_millis(200));
+      let run = deadline.run(async {
+        sleep(Duration::from_millis(120)).await;
+        {
+          // A tool approval the user takes a while to answer.
+          let _approval = deadline.pause();
+          sleep(Duration::from_millis(300)).await;
+        }
+        sleep(Duration::from_millis(40)).await;
+        "done"
+      });
+      assert_eq!(run.await, Some("done"));
+
+      let deadline = RunDeadline::new(Duration::from_millis(100));
+      let run = deadline.run(async {
+        let first = deadline.pause();
+        let second = deadline.pause();
+        drop(second);
+        // Still paused by the first guard.
+        sleep(Duration::from_millis(150)).await;
+        drop(first);
+        sleep(Duration::from_millis(300)).await;
+      });
+      assert_eq!(run.await, None);
+    });
+  }
+}
@@ -0,0 +1,243 @@
+use crate::ai_types::ToolCall;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One line of `.novel/.logs/agent-runs/<stream_id>.jsonl`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TraceRecord {
+  Start {
+    stream_id: String,
+    agent_id: String,
+    provider_id: String,
+    model: String,
+    native_tools: bool,
+  },
+  SystemPrompt {
+    text: String,
+  },
+  ModelOutput {
+    step: u32,
+    text: String,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+    duration_ms: u64,
+  },
+  ToolCall {
+    step: u32,
+    action_id: String,
+    tool: String,
+    args: Value,
+    ok: bool,
+    observation: Value,
+    duration_ms: u64,
+  },
+  Finish {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    perf: Value,
+  },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TraceLine {
+  pub ts: i64,
+  /// Set on lines of a delegated run: the `delegate` call it answers.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub parent_action_id: Option<String>,
+  #[serde(flatten)]
+  pub record: TraceRecord,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RunSummary {
+  pub stream_id: String,
+  pub started_at: i64,
+  pub agent_id: String,
+  pub provider_id: String,
+  pub steps: u32,
+  pub tool_calls: u32,
+  /// `None` while the run has no finish record (still running or aborted).
+  pub ok: Option<bool>,
+  pub size_bytes: u64,
+}
+
+pub fn runs_dir(root: &Path) -> PathBuf {
+  root.join(".novel").join(".logs").join("agent-runs")
+}
+
+fn trace_file_name(stream_id: &str) -> String {
+  let safe = stream_id
+    .trim()
+    .chars()
+    .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+    .collect::<String>();
+  format!("{safe}.jsonl")
+}
+
+/// Appends trace records for one stream. Cloning shares the same file.
+#[derive(Clone, Debug)]
+pub struct RunTraceWriter {
+  path: PathBuf,
+  parent_action_id: Option<String>,
+}
+
+impl RunTraceWriter {
+  pub fn create(root: &Path, stream_id: &str) -> Result<Self, String> {
+    let dir = runs_dir(root);
+    fs::create_dir_all(&dir).map_err(|e| format!("create agent run dir failed: {e}"))?;
+    Ok(Self {
+      path: dir.join(trace_file_name(stream_id)),
+      parent_action_id: None,
+    })
+  }
+
+  /// Writer for the child run started by the `delegate` call `parent_action_id`.
+  pub fn for_delegate(&self, parent_action_id: &str) -> Self {
+    Self {
+      path: self.path.clone(),
+      parent_action_id: Some(parent_action_id.to_string()),
+    }
+  }
+
+  /// Tracing never fails a run; write errors are only logged.
+  pub fn record(&self, record: TraceRecord) {
+    let line = TraceLine {
+      ts: Utc::now().timestamp_millis(),
+      parent_action_id: self.parent_action_id.clone(),
+      record,
+    };
+    let Ok(mut raw) = serde_json::to_string(&line) else {
+      return;
+    };
+    raw.push('\n');
+    let result = fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .and_then(|mut f| std::io::Write::write_all(&mut f, raw.as_bytes()));
+    if let Err(e) = result {
+      eprintln!("append agent run trace failed: {e}");
+    }
+  }
+}
+
+pub fn load_trace(root: &Path, stream_id: &str) -> Result<Vec<TraceLine>, String> {
+  let path = runs_dir(root).join(trace_file_name(stream_id));
+  let raw = fs::read_to_string(&path).map_err(|e| format!("read agent run failed: {e}"))?;
+  Ok(
+    raw
+      .lines()
+      .filter(|l| !l.trim().is_empty())
+      .filter_map(|l| serde_json::from_str::<TraceLine>(l).ok())
+      .collect(),
+  )
+}
+
+pub fn list_runs(root: &Path, max: usize) -> Result<Vec<RunSummary>, String> {
+  let dir = runs_dir(root);
+  if !dir.exists() {
+    return Ok(Vec::new());
+  }
+  let mut out = Vec::new();
+  for entry in fs::read_dir(&dir).map_err(|e| format!("read agent run dir failed: {e}"))? {
+    let path = entry.map_err(|e| format!("read agent run entry failed: {e}"))?.path();
+    if path.extension().and_then(|v| v.to_str()) != Some("jsonl") {
+      continue;
+    }
+    let stream_id = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let mut summary = RunSummary {
+      stream_id: stream_id.clone(),
+      size_bytes: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+      ..Default::default()
+    };
+    for line in load_trace(root, &stream_id)? {
+      if summary.started_at == 0 {
+        summary.started_at = line.ts;
+      }
+      match line.record {
+        TraceRecord::Start { agent_id, provider_id, .. } => {
+          summary.agent_id = agent_id;
+          summary.provider_id = provider_id;
+        }
+        TraceRecord::ModelOutput { step, .. } if line.parent_action_id.is_none() => {
+          summary.steps = summary.steps.max(step)
+        }
+        TraceRecord::ModelOutput { .. } => {}
+        TraceRecord::ToolCall { .. } => summary.tool_calls += 1,
+        TraceRecord::Finish { ok, .. } => summary.ok = Some(ok),
+        TraceRecord::SystemPrompt { .. } => {}
+      }
+    }
+    out.push(summary);
+  }
+  out.sort_by_key(|r| std::cmp::Reverse(r.started_at));
+  out.truncate(max);
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn trace_round_trips_and_skips_a_truncated_last_line() {
+    let root = std::env::temp_dir().join(format!("novel-trace-{}", uuid::Uuid::new_v4()));
+    let writer = RunTraceWriter::create(&root, "stream/1").unwrap();
+    writer.record(TraceRecord::Start {
+      stream_id: "stream/1".to_string(),
+      agent_id: "fantasy".to_string(),
+      provider_id: "openai".to_string(),
+      model: "m".to_string(),
+      native_tools: true,
+    });
+    writer.record(TraceRecord::ModelOutput {
+      step: 1,
+      text: String::new(),
+      tool_calls: Vec::new(),
+      duration_ms: 5,
+    });
+    // Steps of a delegated run are numbered on their own and not counted.
+    let child = writer.for_delegate("step-1");
+    child.record(TraceRecord::ModelOutput {
+      step: 3,
+      text: "审阅完毕".to_string(),
+      tool_calls: Vec::new(),
+      duration_ms: 5,
+    });
+    child.record(TraceRecord::ToolCall {
+      step: 1,
+      action_id: "step-1/step-1".to_string(),
+      tool: "fs_read_text".to_string(),
+      args: serde_json::json!({ "path": "stories/01.md" }),
+      ok: true,
+      observation: serde_json::json!({ "content": "林默推门而入" }),
+      duration_ms: 3,
+    });
+    // A run killed mid-write leaves half a line behind.
+    fs::OpenOptions::new()
+      .append(true)
+      .open(runs_dir(&root).join("stream_1.jsonl"))
+      .and_then(|mut f| std::io::Write::write_all(&mut f, br#"{"ts":1,"kind":"finish","ok":tr"#))
+      .unwrap();
+
+    let lines = load_trace(&root, "stream/1").unwrap();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[3].parent_action_id.as_deref(), Some("step-1"));
+    match &lines[3].record {
+      TraceRecord::ToolCall { observation, .. } => assert_eq!(observation["content"], "林默推门而入"),
+      other => panic!("unexpected record {other:?}"),
+    }
+    let runs = list_runs(&root, 10).unwrap();
+    assert_eq!(runs[0].provider_id, "openai");
+    assert_eq!(runs[0].steps, 1);
+    assert_eq!(runs[0].tool_calls, 1);
+    assert_eq!(runs[0].ok, None);
+    let _ = fs::remove_dir_all(&root);
+  }
+}
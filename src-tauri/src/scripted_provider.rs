@@ -0,0 +1,296 @@
+//! Offline provider that replays canned OpenAI-style chat responses from a
+//! JSON fixture, so agent workflows can run without a network or API key.
+//!
+//! Fixture layout (`base_url` holds its path, relative paths resolve against
+//! the open workspace):
+//!
+//! ```json
+//! {
+//!   "sse_chunk_bytes": 37,
+//!   "responses": [
+//!     { "text": "第一段", "finish_reason": "length" },
+//!     { "chunks": ["段落", "继续"], "finish_reason": "stop" },
+//!     { "tool_calls": [{ "name": "fs_read_text", "arguments": { "path": "a.md" } }] },
+//!     { "status": 429, "error": "rate limited" }
+//!   ]
+//! }
+//! ```
+//!
+//! Every provider request consumes the next response. Streaming requests get
+//! the response as SSE split into small byte chunks, so the client's line
+//! buffering, overlap dedup and `length` continuation paths are exercised.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Deserialize)]
+struct ScriptFixture {
+  responses: Vec<ScriptedResponse>,
+  /// Start over after the last response instead of failing.
+  #[serde(default)]
+  repeat: bool,
+  /// Size of the byte chunks an SSE body is cut into.
+  #[serde(default = "default_sse_chunk_bytes")]
+  sse_chunk_bytes: usize,
+  /// Characters per content delta when a response gives `text` only.
+  #[serde(default = "default_delta_chars")]
+  delta_chars: usize,
+}
+
+fn default_sse_chunk_bytes() -> usize {
+  37
+}
+
+fn default_delta_chars() -> usize {
+  12
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ScriptedResponse {
+  text: String,
+  /// Explicit content deltas; overrides splitting `text`.
+  chunks: Vec<String>,
+  tool_calls: Vec<ScriptedToolCall>,
+  finish_reason: Option<String>,
+  /// Non-2xx status to answer with; 0 means 200.
+  status: u16,
+  error: String,
+}
+
+#[derive(Deserialize)]
+struct ScriptedToolCall {
+  #[serde(default)]
+  id: String,
+  name: String,
+  #[serde(default)]
+  arguments: Value,
+}
+
+pub struct ScriptedReply {
+  pub status: u16,
+  pub chunks: Vec<Vec<u8>>,
+}
+
+fn cursors() -> &'static Mutex<HashMap<PathBuf, usize>> {
+  static CURSORS: OnceLock<Mutex<HashMap<PathBuf, usize>>> = OnceLock::new();
+  CURSORS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn resolve_fixture_path(base_url: &str, workspace_root: Option<&Path>) -> PathBuf {
+  let raw = base_url.trim().trim_start_matches("file://");
+  let path = PathBuf::from(raw);
+  match workspace_root {
+    Some(root) if path.is_relative() => root.join(path),
+    _ => path,
+  }
+}
+
+fn load_fixture(path: &Path) -> Result<ScriptFixture, String> {
+  let raw = fs::read_to_string(path).map_err(|e| format!("read scripted fixture failed: {e}"))?;
+  let fixture: ScriptFixture =
+    serde_json::from_str(&raw).map_err(|e| format!("parse scripted fixture failed: {e}"))?;
+  if fixture.responses.is_empty() {
+    return Err("scripted fixture has no responses".to_string());
+  }
+  Ok(fixture)
+}
+
+/// Validates the fixture and returns how many responses it holds.
+pub fn probe(path: &Path) -> Result<usize, String> {
+  load_fixture(path).map(|f| f.responses.len())
+}
+
+/// Rewinds the fixture so the next request gets its first response.
+pub fn reset(path: &Path) {
+  if let Ok(mut map) = cursors().lock() {
+    map.remove(path);
+  }
+}
+
+/// Answers one chat request (`body` is the OpenAI request JSON).
+pub fn respond(path: &Path, body: &Value) -> Result<ScriptedReply, String> {
+  let fixture = load_fixture(path)?;
+  let index = {
+    let mut map = cursors().lock().map_err(|_| "scripted cursor lock poisoned".to_string())?;
+    let cursor = map.entry(path.to_path_buf()).or_insert(0);
+    if *cursor >= fixture.responses.len() {
+      if !fixture.repeat {
+        return Err(format!(
+          "scripted fixture exhausted after {} responses",
+          fixture.responses.len()
+        ));
+      }
+      *cursor = 0;
+    }
+    *cursor += 1;
+    *cursor - 1
+  };
+  let response = &fixture.responses[index];
+  if response.status != 0 && !(200..300).contains(&response.status) {
+    let body = serde_json::json!({ "error": { "message": response.error } }).to_string();
+    return Ok(ScriptedReply {
+      status: response.status,
+      chunks: vec![body.into_bytes()],
+    });
+  }
+  let streaming = body["stream"].as_bool().unwrap_or(false);
+  let chunks = if streaming {
+    let sse = render_sse(response, fixture.delta_chars.max(1));
+    split_bytes(&sse, fixture.sse_chunk_bytes.max(1))
+  } else {
+    vec![render_message(response).to_string().into_bytes()]
+  };
+  Ok(ScriptedReply { status: 200, chunks })
+}
+
+fn finish_reason(response: &ScriptedResponse) -> String {
+  response.finish_reason.clone().unwrap_or_else(|| {
+    if response.tool_calls.is_empty() {
+      "stop".to_string()
+    } else {
+      "tool_calls".to_string()
+    }
+  })
+}
+
+fn call_id(call: &ScriptedToolCall, idx: usize) -> String {
+  if call.id.is_empty() {
+    format!("scripted-call-{idx}")
+  } else {
+    call.id.clone()
+  }
+}
+
+fn arguments_text(call: &ScriptedToolCall) -> String {
+  match &call.arguments {
+    Value::String(raw) => raw.clone(),
+    Value::Null => "{}".to_string(),
+    other => other.to_string(),
+  }
+}
+
+fn content_deltas(response: &ScriptedResponse, delta_chars: usize) -> Vec<String> {
+  if !response.chunks.is_empty() {
+    return response.chunks.clone();
+  }
+  let chars = response.text.chars().collect::<Vec<_>>();
+  chars.chunks(delta_chars).map(|c| c.iter().collect()).collect()
+}
+
+fn render_sse(response: &ScriptedResponse, delta_chars: usize) -> String {
+  let mut events: Vec<Value> = content_deltas(response, delta_chars)
+    .into_iter()
+    .map(|piece| serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": piece } }] }))
+    .collect();
+  for (idx, call) in response.tool_calls.iter().enumerate() {
+    events.push(serde_json::json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [{
+      "index": idx,
+      "id": call_id(call, idx),
+      "type": "function",
+      "function": { "name": call.name, "arguments": "" }
+    }] } }] }));
+    // Arguments arrive in two fragments, as real providers split them.
+    let args = arguments_text(call).chars().collect::<Vec<_>>();
+    let mid = args.len() / 2;
+    for part in [&args[..mid], &args[mid..]] {
+      if part.is_empty() {
+        continue;
+      }
+      events.push(serde_json::json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [{
+        "index": idx,
+        "function": { "arguments": part.iter().collect::<String>() }
+      }] } }] }));
+    }
+  }
+  events.push(serde_json::json!({
+    "choices": [{ "index": 0, "delta": {}, "finish_reason": finish_reason(response) }]
+  }));
+  let mut out = String::new();
+  for event in events {
+    out.push_str("data: ");
+    out.push_str(&event.to_string());
+    out.push_str("\n\n");
+  }
+  out.push_str("data: [DONE]\n\n");
+  out
+}
+
+fn render_message(response: &ScriptedResponse) -> Value {
+  let text = if response.chunks.is_empty() {
+    response.text.clone()
+  } else {
+    response.chunks.concat()
+  };
+  let mut message = serde_json::json!({ "role": "assistant", "content": text });
+  if !response.tool_calls.is_empty() {
+    message["tool_calls"] = Value::Array(
+      response
+        .tool_calls
+        .iter()
+        .enumerate()
+        .map(|(idx, call)| {
+          serde_json::json!({
+            "id": call_id(call, idx),
+            "type": "function",
+            "function": { "name": call.name, "arguments": arguments_text(call) }
+          })
+        })
+        .collect(),
+    );
+  }
+  serde_json::json!({
+    "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason(response) }]
+  })
+}
+
+/// Cuts `text` into byte chunks of `size`, like a network read would: a
+/// UTF-8 character can straddle two chunks.
+fn split_bytes(text: &str, size: usize) -> Vec<Vec<u8>> {
+  text.as_bytes().chunks(size).map(|chunk| chunk.to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sse_round_trips_content_and_tool_calls() {
+    let response = ScriptedResponse {
+      text: "林默推开门，看见了她。".to_string(),
+      tool_calls: vec![ScriptedToolCall {
+        id: String::new(),
+        name: "fs_read_text".to_string(),
+        arguments: serde_json::json!({ "path": "stories/a.md" }),
+      }],
+      ..Default::default()
+    };
+    let sse = render_sse(&response, 4);
+    let chunks = split_bytes(&sse, 7);
+    assert!(chunks.iter().any(|c| std::str::from_utf8(c).is_err()));
+    assert_eq!(chunks.concat(), sse.as_bytes());
+
+    let mut text = String::new();
+    let mut args = String::new();
+    let mut reason = String::new();
+    for line in sse.lines().filter_map(|l| l.strip_prefix("data: ")) {
+      if line == "[DONE]" {
+        continue;
+      }
+      let v: Value = serde_json::from_str(line).unwrap();
+      let choice = &v["choices"][0];
+      text.push_str(choice["delta"]["content"].as_str().unwrap_or(""));
+      args.push_str(choice["delta"]["tool_calls"][0]["function"]["arguments"].as_str().unwrap_or(""));
+      if let Some(r) = choice["finish_reason"].as_str() {
+        reason = r.to_string();
+      }
+    }
+    assert_eq!(text, response.text);
+    assert_eq!(serde_json::from_str::<Value>(&args).unwrap()["path"], "stories/a.md");
+    assert_eq!(reason, "tool_calls");
+  }
+}
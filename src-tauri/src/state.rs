@@ -1,12 +1,26 @@
+use crate::cancel_token::CancelToken;
+use crate::provider_catalog::ProviderCatalog;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Mutex;
 use tauri::async_runtime::JoinHandle;
+use tokio::sync::oneshot;
+
+/// An agent tool call paused until the user answers `agent_tool_approve`.
+pub struct PendingToolApproval {
+  pub stream_id: String,
+  pub responder: oneshot::Sender<bool>,
+}
 
 pub struct AppState {
   pub workspace_root: Mutex<Option<PathBuf>>,
   pub fs_watcher: Mutex<Option<notify::RecommendedWatcher>>,
   pub ai_stream_tasks: Mutex<HashMap<String, JoinHandle<()>>>,
+  /// Cooperative cancel token of each running stream, raised by `chat_cancel_stream`.
+  pub ai_stream_cancels: Mutex<HashMap<String, CancelToken>>,
+  pub tool_approvals: Mutex<HashMap<String, PendingToolApproval>>,
+  /// Model catalogs fetched by `list_provider_models`, keyed by provider id.
+  pub provider_catalogs: Mutex<HashMap<String, ProviderCatalog>>,
 }
 
 impl Default for AppState {
@@ -15,6 +29,9 @@ impl Default for AppState {
       workspace_root: Mutex::new(None),
       fs_watcher: Mutex::new(None),
       ai_stream_tasks: Mutex::new(HashMap::new()),
+      ai_stream_cancels: Mutex::new(HashMap::new()),
+      tool_approvals: Mutex::new(HashMap::new()),
+      provider_catalogs: Mutex::new(HashMap::new()),
     }
   }
 }
@@ -0,0 +1,574 @@
+//! BM25 retrieval over long-term memory and the chapter/concept files.
+//!
+//! Files are split into passages of a few paragraphs and indexed with CJK
+//! unigrams + bigrams and lowercased words. The index is cached per workspace
+//! in `.novel/.cache/story_index.json`; `write_text_internal` keeps it current
+//! and every search re-checks file sizes and mtimes to catch outside edits,
+//! renames and deletions. Writes only update the in-memory index; the next
+//! search saves it, and passages lost to an unsaved index are re-indexed by
+//! that same mtime check.
+
+use crate::agent_memory::{MemoryItem, MemoryScope};
+use crate::context_budget::is_cjk;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+
+const INDEX_VERSION: u32 = 1;
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+/// A passage closes at the first blank line after this many characters, or
+/// at any line once it reaches twice that.
+const PASSAGE_CHARS: usize = 400;
+const MAX_FILE_BYTES: u64 = 4 * 1024 * 1024;
+const SNIPPET_CHARS: usize = 160;
+const INDEXED_DIRS: [&str; 2] = ["stories", "concept"];
+
+/// Words of two or more characters (lowercased) plus every CJK character and
+/// every pair of adjacent CJK characters. Repeats are kept for term counts.
+pub fn tokenize(text: &str) -> Vec<String> {
+  let mut out = Vec::new();
+  let mut word = String::new();
+  let mut prev_cjk: Option<char> = None;
+  let flush_word = |word: &mut String, out: &mut Vec<String>| {
+    if word.chars().count() >= 2 {
+      out.push(word.to_lowercase());
+    }
+    word.clear();
+  };
+  for ch in text.chars() {
+    if is_cjk(ch) && ch.is_alphanumeric() {
+      flush_word(&mut word, &mut out);
+      out.push(ch.to_string());
+      if let Some(prev) = prev_cjk {
+        out.push(format!("{prev}{ch}"));
+      }
+      prev_cjk = Some(ch);
+      continue;
+    }
+    prev_cjk = None;
+    if ch.is_alphanumeric() {
+      word.push(ch);
+    } else {
+      flush_word(&mut word, &mut out);
+    }
+  }
+  flush_word(&mut word, &mut out);
+  out
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct DocTerms {
+  len: usize,
+  tf: BTreeMap<String, u32>,
+}
+
+/// Okapi BM25 over documents keyed by string ids.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Bm25 {
+  docs: BTreeMap<String, DocTerms>,
+  total_len: usize,
+  #[serde(skip)]
+  postings: HashMap<String, HashMap<String, u32>>,
+}
+
+impl Bm25 {
+  pub fn insert(&mut self, id: &str, text: &str) {
+    self.remove(id);
+    let tokens = tokenize(text);
+    let mut tf = BTreeMap::new();
+    for token in tokens.iter() {
+      *tf.entry(token.clone()).or_insert(0u32) += 1;
+    }
+    for (term, count) in tf.iter() {
+      self.postings.entry(term.clone()).or_default().insert(id.to_string(), *count);
+    }
+    self.total_len += tokens.len();
+    self.docs.insert(id.to_string(), DocTerms { len: tokens.len(), tf });
+  }
+
+  pub fn remove(&mut self, id: &str) {
+    let Some(doc) = self.docs.remove(id) else {
+      return;
+    };
+    self.total_len -= doc.len;
+    for term in doc.tf.keys() {
+      if let Some(list) = self.postings.get_mut(term) {
+        list.remove(id);
+        if list.is_empty() {
+          self.postings.remove(term);
+        }
+      }
+    }
+  }
+
+  fn rebuild_postings(&mut self) {
+    self.postings.clear();
+    for (id, doc) in self.docs.iter() {
+      for (term, count) in doc.tf.iter() {
+        self.postings.entry(term.clone()).or_default().insert(id.clone(), *count);
+      }
+    }
+  }
+
+  /// Ids scored against `query`, best first; `keep` filters ids before ranking.
+  pub fn search(&self, query: &str, limit: usize, keep: impl Fn(&str) -> bool) -> Vec<(String, f64)> {
+    let mut terms = tokenize(query);
+    terms.sort();
+    terms.dedup();
+    if self.docs.is_empty() || terms.is_empty() {
+      return Vec::new();
+    }
+    let n = self.docs.len() as f64;
+    let avg_len = (self.total_len as f64 / n).max(1.0);
+    let mut scores: HashMap<&str, f64> = HashMap::new();
+    for term in terms.iter() {
+      let Some(list) = self.postings.get(term) else {
+        continue;
+      };
+      let df = list.len() as f64;
+      let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+      for (id, tf) in list.iter() {
+        if !keep(id) {
+          continue;
+        }
+        let len = self.docs.get(id).map(|d| d.len).unwrap_or(0) as f64;
+        let tf = *tf as f64;
+        let score = idf * tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * (1.0 - BM25_B + BM25_B * len / avg_len));
+        *scores.entry(id.as_str()).or_insert(0.0) += score;
+      }
+    }
+    let mut ranked = scores.into_iter().map(|(id, s)| (id.to_string(), s)).collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked.truncate(limit);
+    ranked
+  }
+}
+
+/// Text a memory item is indexed under; the key counts twice.
+pub fn memory_document(item: &MemoryItem) -> String {
+  format!("{k} {k} {} {}", item.subject, item.value, k = item.key)
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct IndexedFile {
+  size: u64,
+  mtime_ms: u128,
+  /// 1-based inclusive line ranges, one per passage.
+  passages: Vec<(usize, usize)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct StoryIndexData {
+  version: u32,
+  files: BTreeMap<String, IndexedFile>,
+  /// Memory item id -> `updated_at` at indexing time.
+  memory: BTreeMap<String, i64>,
+  bm25: Bm25,
+  /// Changed since it was last saved.
+  #[serde(skip)]
+  dirty: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct StorySources {
+  pub memory: bool,
+  pub chapters: bool,
+  pub concepts: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StoryHit {
+  /// `memory`, `chapter` or `concept`.
+  pub kind: String,
+  pub score: f64,
+  pub path: Option<String>,
+  pub line_start: Option<usize>,
+  pub line_end: Option<usize>,
+  pub memory_id: Option<String>,
+  pub memory_key: Option<String>,
+  pub memory_scope: Option<MemoryScope>,
+  pub memory_subject: Option<String>,
+  pub snippet: String,
+}
+
+fn index_cache() -> &'static Mutex<HashMap<PathBuf, StoryIndexData>> {
+  static CACHE: OnceLock<Mutex<HashMap<PathBuf, StoryIndexData>>> = OnceLock::new();
+  CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn index_path(root: &Path) -> PathBuf {
+  root.join(".novel").join(".cache").join("story_index.json")
+}
+
+fn load_index(root: &Path) -> StoryIndexData {
+  let mut data = fs::read_to_string(index_path(root))
+    .ok()
+    .and_then(|raw| serde_json::from_str::<StoryIndexData>(&raw).ok())
+    .filter(|d| d.version == INDEX_VERSION)
+    .unwrap_or_else(|| StoryIndexData {
+      version: INDEX_VERSION,
+      ..Default::default()
+    });
+  data.bm25.rebuild_postings();
+  data
+}
+
+fn save_index(root: &Path, data: &StoryIndexData) -> Result<(), String> {
+  let path = index_path(root);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).map_err(|e| format!("create story index dir failed: {e}"))?;
+  }
+  let raw = serde_json::to_string(data).map_err(|e| format!("serialize story index failed: {e}"))?;
+  fs::write(path, raw).map_err(|e| format!("write story index failed: {e}"))
+}
+
+/// Whether `rel_path` (normalized, `/` separators) belongs in the index.
+pub fn is_indexed_path(rel_path: &str) -> bool {
+  let lower = rel_path.to_lowercase();
+  INDEXED_DIRS.iter().any(|dir| lower.starts_with(&format!("{dir}/")))
+    && (lower.ends_with(".md") || lower.ends_with(".txt"))
+}
+
+fn kind_for_path(rel_path: &str) -> &'static str {
+  if rel_path.starts_with("concept/") {
+    "concept"
+  } else {
+    "chapter"
+  }
+}
+
+fn file_stamp(path: &Path) -> Option<(u64, u128)> {
+  let md = fs::metadata(path).ok()?;
+  let mtime = md
+    .modified()
+    .ok()
+    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    .map(|d| d.as_millis())
+    .unwrap_or(0);
+  Some((md.len(), mtime))
+}
+
+/// Splits text into passages at paragraph breaks; returns 1-based line ranges.
+fn split_passages(text: &str) -> Vec<(usize, usize, String)> {
+  let mut out = Vec::new();
+  let mut start = 0usize;
+  let mut buf = String::new();
+  let mut chars = 0usize;
+  for (idx, line) in text.lines().enumerate() {
+    if buf.is_empty() {
+      start = idx + 1;
+    }
+    buf.push_str(line);
+    buf.push('\n');
+    chars += line.chars().count();
+    let blank = line.trim().is_empty();
+    if (blank && chars >= PASSAGE_CHARS) || chars >= PASSAGE_CHARS * 2 {
+      out.push((start, idx + 1, std::mem::take(&mut buf)));
+      chars = 0;
+    }
+  }
+  if !buf.trim().is_empty() {
+    out.push((start, text.lines().count(), buf));
+  }
+  out
+}
+
+fn index_file_content(data: &mut StoryIndexData, rel_path: &str, content: &str, stamp: (u64, u128)) {
+  drop_file(data, rel_path);
+  let mut passages = Vec::new();
+  for (n, (start, end, text)) in split_passages(content).into_iter().enumerate() {
+    data.bm25.insert(&format!("file:{rel_path}#{n}"), &text);
+    passages.push((start, end));
+  }
+  data.files.insert(
+    rel_path.to_string(),
+    IndexedFile {
+      size: stamp.0,
+      mtime_ms: stamp.1,
+      passages,
+    },
+  );
+}
+
+fn drop_file(data: &mut StoryIndexData, rel_path: &str) {
+  if let Some(file) = data.files.remove(rel_path) {
+    for n in 0..file.passages.len() {
+      data.bm25.remove(&format!("file:{rel_path}#{n}"));
+    }
+  }
+}
+
+/// Re-indexes changed files under the indexed directories and drops missing ones.
+fn refresh_files(root: &Path, data: &mut StoryIndexData) -> bool {
+  let mut seen = Vec::new();
+  let mut changed = false;
+  let mut stack = INDEXED_DIRS.iter().map(|d| root.join(d)).collect::<Vec<_>>();
+  while let Some(dir) = stack.pop() {
+    let Ok(entries) = fs::read_dir(&dir) else {
+      continue;
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+      if path.is_dir() {
+        stack.push(path);
+        continue;
+      }
+      let rel = path
+        .strip_prefix(root)
+        .unwrap_or(&path)
+        .to_string_lossy()
+        .replace('\\', "/");
+      let Some(stamp) = file_stamp(&path) else {
+        continue;
+      };
+      if !is_indexed_path(&rel) || stamp.0 > MAX_FILE_BYTES {
+        continue;
+      }
+      seen.push(rel.clone());
+      let current = data.files.get(&rel).map(|f| (f.size, f.mtime_ms));
+      if current == Some(stamp) {
+        continue;
+      }
+      if let Ok(content) = fs::read_to_string(&path) {
+        index_file_content(data, &rel, &content, stamp);
+        changed = true;
+      }
+    }
+  }
+  let missing = data
+    .files
+    .keys()
+    .filter(|k| !seen.contains(k))
+    .cloned()
+    .collect::<Vec<_>>();
+  for rel in missing {
+    drop_file(data, &rel);
+    changed = true;
+  }
+  changed
+}
+
+fn sync_memory(data: &mut StoryIndexData, items: &[MemoryItem]) -> bool {
+  let mut changed = false;
+  let live = items.iter().map(|it| it.id.as_str()).collect::<Vec<_>>();
+  let stale = data
+    .memory
+    .keys()
+    .filter(|id| !live.contains(&id.as_str()))
+    .cloned()
+    .collect::<Vec<_>>();
+  for id in stale {
+    data.memory.remove(&id);
+    data.bm25.remove(&format!("memory:{id}"));
+    changed = true;
+  }
+  for item in items {
+    if data.memory.get(&item.id) == Some(&item.updated_at) {
+      continue;
+    }
+    data.bm25.insert(&format!("memory:{}", item.id), &memory_document(item));
+    data.memory.insert(item.id.clone(), item.updated_at);
+    changed = true;
+  }
+  changed
+}
+
+/// Updates the passages of one file after it was written.
+pub fn update_file(root: &Path, rel_path: &str, content: &str) -> Result<(), String> {
+  if !is_indexed_path(rel_path) {
+    return Ok(());
+  }
+  let stamp = file_stamp(&root.join(rel_path)).unwrap_or((content.len() as u64, 0));
+  let mut cache = index_cache().lock().map_err(|_| "story index lock poisoned".to_string())?;
+  let data = cache.entry(root.to_path_buf()).or_insert_with(|| load_index(root));
+  index_file_content(data, rel_path, content, stamp);
+  data.dirty = true;
+  Ok(())
+}
+
+/// `overrides` are files a dry run changed, with their current text or `None`
+/// once deleted; they are searched in place of the disk copies without
+/// reaching the saved index.
+pub fn search(
+  root: &Path,
+  memory_items: &[MemoryItem],
+  overrides: &[(String, Option<String>)],
+  query: &str,
+  sources: StorySources,
+  limit: usize,
+) -> Result<Vec<StoryHit>, String> {
+  if query.trim().is_empty() {
+    return Err("query is required".to_string());
+  }
+  let mut cache = index_cache().lock().map_err(|_| "story index lock poisoned".to_string())?;
+  let data = cache.entry(root.to_path_buf()).or_insert_with(|| load_index(root));
+  let files_changed = refresh_files(root, data);
+  let memory_changed = sync_memory(data, memory_items);
+  if files_changed || memory_changed || data.dirty {
+    save_index(root, data)?;
+    data.dirty = false;
+  }
+  let overridden = overrides.iter().filter(|(rel, _)| is_indexed_path(rel)).collect::<Vec<_>>();
+  let view;
+  let data = if overridden.is_empty() {
+    &*data
+  } else {
+    view = {
+      let mut view = data.clone();
+      for (rel, content) in overridden.iter() {
+        match content {
+          Some(content) => index_file_content(&mut view, rel, content, (content.len() as u64, 0)),
+          None => drop_file(&mut view, rel),
+        }
+      }
+      view
+    };
+    &view
+  };
+  let keep = |id: &str| {
+    if id.starts_with("memory:") {
+      sources.memory
+    } else if id.starts_with("file:concept/") {
+      sources.concepts
+    } else {
+      sources.chapters
+    }
+  };
+  let terms = tokenize(query);
+  let mut hits = Vec::new();
+  for (id, score) in data.bm25.search(query, limit, keep) {
+    if let Some(memory_id) = id.strip_prefix("memory:") {
+      let Some(item) = memory_items.iter().find(|it| it.id == memory_id) else {
+        continue;
+      };
+      hits.push(StoryHit {
+        kind: "memory".to_string(),
+        score,
+        path: item.source.path.clone(),
+        line_start: None,
+        line_end: None,
+        memory_id: Some(item.id.clone()),
+        memory_key: Some(item.key.clone()),
+        memory_scope: Some(item.scope),
+        memory_subject: Some(item.subject.clone()).filter(|s| !s.is_empty()),
+        snippet: snippet_for(&item.value, &terms),
+      });
+      continue;
+    }
+    let Some((rel, n)) = id.strip_prefix("file:").and_then(|rest| rest.rsplit_once('#')) else {
+      continue;
+    };
+    let Some(&(start, end)) = data
+      .files
+      .get(rel)
+      .and_then(|f| n.parse::<usize>().ok().and_then(|n| f.passages.get(n)))
+    else {
+      continue;
+    };
+    let text = match overrides.iter().find(|(path, _)| path == rel) {
+      Some((_, content)) => Ok(content.clone().unwrap_or_default()),
+      None => fs::read_to_string(root.join(rel)),
+    }
+    .map(|raw| raw.lines().skip(start - 1).take(end + 1 - start).collect::<Vec<_>>().join("\n"))
+      .unwrap_or_default();
+    hits.push(StoryHit {
+      kind: kind_for_path(rel).to_string(),
+      score,
+      path: Some(rel.to_string()),
+      line_start: Some(start),
+      line_end: Some(end),
+      memory_id: None,
+      memory_key: None,
+      memory_scope: None,
+      memory_subject: None,
+      snippet: snippet_for(&text, &terms),
+    });
+  }
+  Ok(hits)
+}
+
+/// A window of `text` around the longest query term it contains.
+fn snippet_for(text: &str, terms: &[String]) -> String {
+  let lower = text.to_lowercase();
+  let mut best = terms.iter().collect::<Vec<_>>();
+  best.sort_by_key(|t| std::cmp::Reverse(t.chars().count()));
+  let at = best
+    .iter()
+    .find_map(|t| lower.find(t.as_str()))
+    .map(|byte| lower[..byte].chars().count())
+    .unwrap_or(0);
+  let chars = text.chars().collect::<Vec<_>>();
+  let from = at.saturating_sub(SNIPPET_CHARS / 3);
+  let to = (from + SNIPPET_CHARS).min(chars.len());
+  let mut out = String::new();
+  if from > 0 {
+    out.push_str("...");
+  }
+  out.push_str(chars[from..to].iter().collect::<String>().replace('\n', " ").trim());
+  if to < chars.len() {
+    out.push_str("...");
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tokenizes_cjk_and_words() {
+    assert_eq!(tokenize("林默 met Su"), vec!["林", "默", "林默", "met", "su"]);
+    assert_eq!(tokenize("第3章"), vec!["第", "章"]);
+  }
+
+  #[test]
+  fn ranks_bigram_matches_first() {
+    let mut index = Bm25::default();
+    index.insert("a", "林默推开门，雨下得很大。");
+    index.insert("b", "默默无言，林中起风。");
+    index.insert("c", "The harbor was quiet.");
+    let ranked = index.search("林默", 10, |_| true);
+    assert_eq!(ranked.first().map(|r| r.0.as_str()), Some("a"));
+    assert!(ranked.iter().all(|r| r.0 != "c"));
+    index.remove("a");
+    assert_eq!(index.search("林默", 10, |_| true).first().map(|r| r.0.as_str()), Some("b"));
+  }
+
+  #[test]
+  fn written_files_are_saved_on_the_next_search() {
+    let root = std::env::temp_dir().join(format!("novel-index-{}", uuid::Uuid::new_v4()));
+    let chapter = "林默推开门，雨下得很大。";
+    fs::create_dir_all(root.join("stories")).unwrap();
+    fs::write(root.join("stories/01.md"), chapter).unwrap();
+    update_file(&root, "stories/01.md", chapter).unwrap();
+    update_file(&root, "stories/01.md", chapter).unwrap();
+    assert!(!index_path(&root).exists());
+
+    let sources = StorySources {
+      chapters: true,
+      ..Default::default()
+    };
+    let hits = search(&root, &[], &[], "林默", sources, 5).unwrap();
+    assert_eq!(hits[0].path.as_deref(), Some("stories/01.md"));
+    let saved = load_index(&root);
+    assert!(saved.files.contains_key("stories/01.md"));
+
+    // A dry run rewrote the chapter and added another; the saved index keeps the disk text.
+    let overrides = vec![
+      ("stories/01.md".to_string(), Some("苏晴站在桥上。".to_string())),
+      ("stories/02.md".to_string(), Some("林默回到港口。".to_string())),
+    ];
+    let hits = search(&root, &[], &overrides, "林默", sources, 5).unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].path.as_deref(), Some("stories/02.md"));
+    assert_eq!(hits[0].snippet, "林默回到港口。");
+    let hits = search(&root, &[], &[("stories/01.md".to_string(), None)], "林默", sources, 5).unwrap();
+    assert!(hits.is_empty());
+    assert!(load_index(&root).files.contains_key("stories/01.md"));
+    assert!(!load_index(&root).files.contains_key("stories/02.md"));
+    let _ = fs::remove_dir_all(&root);
+  }
+}
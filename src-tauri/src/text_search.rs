@@ -0,0 +1,240 @@
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+const MAX_FILE_BYTES: u64 = 4 * 1024 * 1024;
+const SNIPPET_RADIUS_CHARS: usize = 60;
+
+pub struct SearchOptions {
+  pub query: String,
+  pub regex: bool,
+  pub case_sensitive: bool,
+  pub path_glob: Option<String>,
+  pub max_results: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SearchHit {
+  pub path: String,
+  /// 1-based line number.
+  pub line: usize,
+  /// 1-based character column of the match.
+  pub column: usize,
+  pub snippet: String,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SearchResult {
+  pub hits: Vec<SearchHit>,
+  pub files_scanned: usize,
+  pub truncated: bool,
+}
+
+/// Searches UTF-8 files under `root`. `skip_path` receives workspace-relative
+/// paths using `/` separators (directories end with `/`) and prunes anything it
+/// rejects; files that are too large or not valid UTF-8 are ignored.
+pub fn search_workspace(
+  root: &Path,
+  options: &SearchOptions,
+  skip_path: impl Fn(&str) -> bool,
+) -> Result<SearchResult, String> {
+  search_tree(
+    options,
+    skip_path,
+    |dir| match fs::read_dir(root.join(dir)) {
+      Ok(v) => v
+        .filter_map(|e| e.ok())
+        .map(|e| (e.file_name().to_string_lossy().to_string(), e.path().is_dir()))
+        .collect(),
+      Err(_) => Vec::new(),
+    },
+    |rel| {
+      let path = root.join(rel);
+      if fs::metadata(&path).map(|m| m.len() > MAX_FILE_BYTES).unwrap_or(true) {
+        return None;
+      }
+      fs::read_to_string(&path).ok()
+    },
+  )
+}
+
+/// `search_workspace` over any view of the workspace: `list_dir` returns the
+/// `(name, is_dir)` children of a relative directory (`""` is the root) and
+/// `read_file` the text of a file, or `None` to leave it out.
+pub fn search_tree(
+  options: &SearchOptions,
+  skip_path: impl Fn(&str) -> bool,
+  list_dir: impl Fn(&str) -> Vec<(String, bool)>,
+  read_file: impl Fn(&str) -> Option<String>,
+) -> Result<SearchResult, String> {
+  let pattern = build_pattern(&options.query, options.regex, options.case_sensitive)?;
+  let glob = match options.path_glob.as_deref().map(str::trim).filter(|g| !g.is_empty()) {
+    Some(g) => Some(PathGlob::new(g)?),
+    None => None,
+  };
+  let mut result = SearchResult::default();
+  let max_results = options.max_results.max(1);
+  let mut stack = vec![String::new()];
+  while let Some(dir) = stack.pop() {
+    let mut entries = list_dir(&dir);
+    entries.sort();
+    // Directories go back on the stack reversed so files are visited in path order.
+    let mut subdirs = Vec::new();
+    for (name, is_dir) in entries {
+      let rel = if dir.is_empty() { name } else { format!("{dir}/{name}") };
+      if is_dir {
+        if !skip_path(&format!("{rel}/")) {
+          subdirs.push(rel);
+        }
+        continue;
+      }
+      if skip_path(&rel) || glob.as_ref().map(|g| !g.matches(&rel)).unwrap_or(false) {
+        continue;
+      }
+      let Some(text) = read_file(&rel).filter(|t| t.len() as u64 <= MAX_FILE_BYTES) else {
+        continue;
+      };
+      result.files_scanned += 1;
+      for (idx, line) in text.lines().enumerate() {
+        let Some(m) = pattern.find(line) else {
+          continue;
+        };
+        if result.hits.len() >= max_results {
+          result.truncated = true;
+          return Ok(result);
+        }
+        result.hits.push(SearchHit {
+          path: rel.clone(),
+          line: idx + 1,
+          column: line[..m.start()].chars().count() + 1,
+          snippet: snippet_around(line, m.start(), m.end()),
+        });
+      }
+    }
+    stack.extend(subdirs.into_iter().rev());
+  }
+  Ok(result)
+}
+
+fn build_pattern(query: &str, regex: bool, case_sensitive: bool) -> Result<Regex, String> {
+  if query.trim().is_empty() {
+    return Err("query is required".to_string());
+  }
+  let source = if regex { query.to_string() } else { regex::escape(query) };
+  RegexBuilder::new(&source)
+    .case_insensitive(!case_sensitive)
+    .build()
+    .map_err(|e| format!("invalid regex: {e}"))
+}
+
+/// Cuts a window of the line around the match so long paragraphs stay readable.
+fn snippet_around(line: &str, start: usize, end: usize) -> String {
+  let before = &line[..start];
+  let after = &line[end..];
+  let before_chars = before.chars().count();
+  let lead = before
+    .chars()
+    .skip(before_chars.saturating_sub(SNIPPET_RADIUS_CHARS))
+    .collect::<String>();
+  let tail = after.chars().take(SNIPPET_RADIUS_CHARS).collect::<String>();
+  let mut out = String::new();
+  if before_chars > SNIPPET_RADIUS_CHARS {
+    out.push_str("...");
+  }
+  out.push_str(lead.trim_start());
+  out.push_str(&line[start..end]);
+  out.push_str(tail.trim_end());
+  if after.chars().count() > SNIPPET_RADIUS_CHARS {
+    out.push_str("...");
+  }
+  out
+}
+
+/// Path filter supporting `*`, `?` and `**`. A glob without `/` matches the
+/// file name only, so `*.md` finds Markdown files at any depth.
+struct PathGlob {
+  pattern: Regex,
+  name_only: bool,
+}
+
+impl PathGlob {
+  fn new(glob: &str) -> Result<Self, String> {
+    let glob = glob.replace('\\', "/");
+    let glob = glob.trim_start_matches("./");
+    let mut re = String::from("^");
+    let chars = glob.chars().collect::<Vec<_>>();
+    let mut i = 0;
+    while i < chars.len() {
+      match chars[i] {
+        '*' if chars.get(i + 1) == Some(&'*') => {
+          if chars.get(i + 2) == Some(&'/') {
+            re.push_str("(?:.*/)?");
+            i += 3;
+          } else {
+            re.push_str(".*");
+            i += 2;
+          }
+          continue;
+        }
+        '*' => re.push_str("[^/]*"),
+        '?' => re.push_str("[^/]"),
+        c => re.push_str(&regex::escape(&c.to_string())),
+      }
+      i += 1;
+    }
+    re.push('$');
+    let pattern = RegexBuilder::new(&re)
+      .case_insensitive(true)
+      .build()
+      .map_err(|e| format!("invalid path glob: {e}"))?;
+    Ok(Self {
+      pattern,
+      name_only: !glob.contains('/'),
+    })
+  }
+
+  fn matches(&self, rel_path: &str) -> bool {
+    if self.name_only {
+      let name = rel_path.rsplit('/').next().unwrap_or(rel_path);
+      self.pattern.is_match(name)
+    } else {
+      self.pattern.is_match(rel_path)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn path_glob_matches_names_and_nested_paths() {
+    let md = PathGlob::new("*.md").unwrap();
+    assert!(md.matches("stories/卷一/01.MD"));
+    assert!(!md.matches("stories/01.txt"));
+
+    let nested = PathGlob::new("stories/**/0?.md").unwrap();
+    assert!(nested.matches("stories/01.md"));
+    assert!(nested.matches("stories/卷一/02.md"));
+    assert!(!nested.matches("stories/卷一/010.md"));
+    assert!(!nested.matches("outline/01.md"));
+
+    let flat = PathGlob::new("./outline/*").unwrap();
+    assert!(flat.matches("outline/plan.md"));
+    assert!(!flat.matches("outline/old/plan.md"));
+  }
+
+  #[test]
+  fn snippet_cuts_cjk_lines_on_character_boundaries() {
+    let line = format!("{}林默{}", "甲".repeat(100), "乙".repeat(100));
+    let start = line.find("林默").unwrap();
+    let snippet = snippet_around(&line, start, start + "林默".len());
+    assert_eq!(snippet, format!("...{}林默{}...", "甲".repeat(60), "乙".repeat(60)));
+
+    let short = "林默推门而入";
+    assert_eq!(snippet_around(short, 0, "林默".len()), short);
+    let end = short.len();
+    assert_eq!(snippet_around(short, end - "而入".len(), end), short);
+  }
+}
@@ -0,0 +1,214 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// A single argument problem found while checking tool input against its schema.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SchemaIssue {
+  pub path: String,
+  pub message: String,
+}
+
+/// Checks `value` against the JSON Schema subset used by agent tools:
+/// `type`, `properties`, `required`, `additionalProperties: false`, `items`,
+/// `enum`, `minLength`, `minimum` and `maximum`.
+pub fn validate(schema: &Value, value: &Value) -> Vec<SchemaIssue> {
+  let mut issues = Vec::new();
+  validate_at(schema, value, "", &mut issues);
+  issues
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str, issues: &mut Vec<SchemaIssue>) {
+  let display_path = if path.is_empty() { "$" } else { path };
+  if let Some(expected) = schema.get("type").and_then(|v| v.as_str()) {
+    if !type_matches(expected, value) {
+      issues.push(SchemaIssue {
+        path: display_path.to_string(),
+        message: format!("expected {expected}, got {}", type_name(value)),
+      });
+      return;
+    }
+  }
+  if let Some(options) = schema.get("enum").and_then(|v| v.as_array()) {
+    if !options.contains(value) {
+      let allowed = options.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ");
+      issues.push(SchemaIssue {
+        path: display_path.to_string(),
+        message: format!("must be one of {allowed}"),
+      });
+    }
+  }
+  if let (Some(min), Some(s)) = (schema.get("minLength").and_then(|v| v.as_u64()), value.as_str()) {
+    if (s.trim().chars().count() as u64) < min {
+      issues.push(SchemaIssue {
+        path: display_path.to_string(),
+        message: if min == 1 {
+          "must not be empty".to_string()
+        } else {
+          format!("must be at least {min} characters")
+        },
+      });
+    }
+  }
+  if let Some(n) = value.as_f64() {
+    if let Some(min) = schema.get("minimum").and_then(|v| v.as_f64()) {
+      if n < min {
+        issues.push(SchemaIssue {
+          path: display_path.to_string(),
+          message: format!("must be >= {min}"),
+        });
+      }
+    }
+    if let Some(max) = schema.get("maximum").and_then(|v| v.as_f64()) {
+      if n > max {
+        issues.push(SchemaIssue {
+          path: display_path.to_string(),
+          message: format!("must be <= {max}"),
+        });
+      }
+    }
+  }
+  if let Some(obj) = value.as_object() {
+    let props = schema.get("properties").and_then(|v| v.as_object());
+    if let Some(required) = schema.get("required").and_then(|v| v.as_array()) {
+      for key in required.iter().filter_map(|v| v.as_str()) {
+        if obj.get(key).map(|v| v.is_null()).unwrap_or(true) {
+          issues.push(SchemaIssue {
+            path: join_path(path, key),
+            message: "is required".to_string(),
+          });
+        }
+      }
+    }
+    let closed = schema.get("additionalProperties").and_then(|v| v.as_bool()) == Some(false);
+    for (key, child) in obj {
+      match props.and_then(|p| p.get(key)) {
+        Some(_) if child.is_null() => {}
+        Some(child_schema) => validate_at(child_schema, child, &join_path(path, key), issues),
+        None if closed => issues.push(SchemaIssue {
+          path: join_path(path, key),
+          message: "is not an accepted argument".to_string(),
+        }),
+        None => {}
+      }
+    }
+  }
+  if let (Some(items), Some(arr)) = (schema.get("items"), value.as_array()) {
+    for (idx, child) in arr.iter().enumerate() {
+      validate_at(items, child, &format!("{display_path}[{idx}]"), issues);
+    }
+  }
+}
+
+fn join_path(parent: &str, key: &str) -> String {
+  if parent.is_empty() {
+    key.to_string()
+  } else {
+    format!("{parent}.{key}")
+  }
+}
+
+fn type_matches(expected: &str, value: &Value) -> bool {
+  match expected {
+    "object" => value.is_object(),
+    "array" => value.is_array(),
+    "string" => value.is_string(),
+    "boolean" => value.is_boolean(),
+    "number" => value.is_number(),
+    // `2.0` is a float to the tools reading the argument with `as_u64`.
+    "integer" => value.is_i64() || value.is_u64(),
+    "null" => value.is_null(),
+    _ => true,
+  }
+}
+
+fn type_name(value: &Value) -> &'static str {
+  match value {
+    Value::Null => "null",
+    Value::Bool(_) => "boolean",
+    Value::Number(_) => "number",
+    Value::String(_) => "string",
+    Value::Array(_) => "array",
+    Value::Object(_) => "object",
+  }
+}
+
+/// Renders one tool for the text prompt, e.g.
+/// `- fs_read_text(path: string*): Read a UTF-8 text file.` (`*` marks required).
+pub fn render_tool_line(name: &str, description: &str, schema: &Value) -> String {
+  let required = schema
+    .get("required")
+    .and_then(|v| v.as_array())
+    .map(|arr| arr.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>())
+    .unwrap_or_default();
+  let args = schema
+    .get("properties")
+    .and_then(|v| v.as_object())
+    .map(|props| {
+      props
+        .iter()
+        .map(|(key, prop)| {
+          let ty = prop.get("type").and_then(|v| v.as_str()).unwrap_or("any");
+          let mark = if required.contains(&key.as_str()) { "*" } else { "" };
+          format!("{key}: {ty}{mark}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+    })
+    .unwrap_or_default();
+  format!("- {name}({args}): {}", description.trim())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn path_schema() -> Value {
+    json!({
+      "type": "object",
+      "properties": {
+        "path": { "type": "string", "minLength": 1 },
+        "limit": { "type": "integer", "minimum": 1, "maximum": 50 }
+      },
+      "required": ["path"],
+      "additionalProperties": false
+    })
+  }
+
+  #[test]
+  fn accepts_valid_args() {
+    assert!(validate(&path_schema(), &json!({ "path": "stories/a.md", "limit": 3 })).is_empty());
+  }
+
+  #[test]
+  fn reports_missing_and_empty_required() {
+    let issues = validate(&path_schema(), &json!({}));
+    assert_eq!(issues, vec![SchemaIssue { path: "path".to_string(), message: "is required".to_string() }]);
+    let issues = validate(&path_schema(), &json!({ "path": "  " }));
+    assert_eq!(issues[0].message, "must not be empty");
+  }
+
+  #[test]
+  fn reports_type_range_and_unknown_keys() {
+    let issues = validate(&path_schema(), &json!({ "path": 3, "limit": 99, "raw": "x" }));
+    let mut paths = issues.iter().map(|i| i.path.as_str()).collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, vec!["limit", "path", "raw"]);
+  }
+
+  #[test]
+  fn rejects_float_forms_for_integers() {
+    let issues = validate(&path_schema(), &json!({ "path": "a.md", "limit": 2.0 }));
+    assert_eq!(issues, vec![SchemaIssue { path: "limit".to_string(), message: "expected integer, got number".to_string() }]);
+    let issues = validate(&path_schema(), &json!({ "path": "a.md", "limit": 2.5 }));
+    assert_eq!(issues[0].path, "limit");
+  }
+
+  #[test]
+  fn renders_required_marker() {
+    let line = render_tool_line("fs_read_text", "Read a file.", &path_schema());
+    assert!(line.starts_with("- fs_read_text("));
+    assert!(line.contains("path: string*"));
+    assert!(line.contains("limit: integer,") || line.ends_with("limit: integer): Read a file."));
+  }
+}
@@ -0,0 +1,182 @@
+//! Token usage of every model request, appended to `.novel/.logs/usage.jsonl`
+//! and summed up for `get_usage_report`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsageEntry {
+  /// Local time, RFC 3339; its first ten characters are the report day.
+  pub ts: String,
+  pub stream_id: String,
+  pub agent_id: String,
+  pub provider_id: String,
+  pub model: String,
+  pub input_tokens: u64,
+  pub output_tokens: u64,
+  /// Set when the provider has a price for the model.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub cost: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct UsageTotals {
+  pub requests: u32,
+  pub input_tokens: u64,
+  pub output_tokens: u64,
+  /// Sum over the priced requests; `None` when none was priced.
+  pub cost: Option<f64>,
+}
+
+impl UsageTotals {
+  pub fn add(&mut self, entry: &UsageEntry) {
+    self.requests += 1;
+    self.input_tokens += entry.input_tokens;
+    self.output_tokens += entry.output_tokens;
+    if let Some(cost) = entry.cost {
+      self.cost = Some(self.cost.unwrap_or(0.0) + cost);
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsageGroupBy {
+  Day,
+  Provider,
+  Agent,
+}
+
+impl UsageGroupBy {
+  pub fn parse(raw: &str) -> Result<Self, String> {
+    match raw.trim() {
+      "" | "day" => Ok(Self::Day),
+      "provider" => Ok(Self::Provider),
+      "agent" => Ok(Self::Agent),
+      other => Err(format!("unknown usage grouping: {other} (expected day, provider or agent)")),
+    }
+  }
+
+  fn key(self, entry: &UsageEntry) -> String {
+    match self {
+      Self::Day => entry.ts.chars().take(10).collect(),
+      Self::Provider => entry.provider_id.clone(),
+      Self::Agent => entry.agent_id.clone(),
+    }
+  }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UsageGroup {
+  pub key: String,
+  #[serde(flatten)]
+  pub totals: UsageTotals,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UsageReport {
+  pub groups: Vec<UsageGroup>,
+  pub total: UsageTotals,
+}
+
+fn log_path(root: &Path) -> PathBuf {
+  root.join(".novel").join(".logs").join("usage.jsonl")
+}
+
+pub fn append(root: &Path, entry: &UsageEntry) -> Result<(), String> {
+  let path = log_path(root);
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir).map_err(|e| format!("create log dir failed: {e}"))?;
+  }
+  let mut line = serde_json::to_string(entry).map_err(|e| format!("serialize usage failed: {e}"))?;
+  line.push('\n');
+  fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .and_then(|mut f| std::io::Write::write_all(&mut f, line.as_bytes()))
+    .map_err(|e| format!("append usage log failed: {e}"))
+}
+
+/// Every entry of the project log; unreadable lines are skipped.
+pub fn read(root: &Path) -> Vec<UsageEntry> {
+  fs::read_to_string(log_path(root))
+    .map(|raw| {
+      raw
+        .lines()
+        .filter_map(|line| serde_json::from_str::<UsageEntry>(line).ok())
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Totals per group for entries whose day lies in `since..=until`
+/// (`YYYY-MM-DD`, both optional). Days are listed in order, providers and
+/// agents by total tokens, largest first.
+pub fn summarize(
+  entries: &[UsageEntry],
+  group_by: UsageGroupBy,
+  since: Option<&str>,
+  until: Option<&str>,
+) -> UsageReport {
+  let mut groups: BTreeMap<String, UsageTotals> = BTreeMap::new();
+  let mut total = UsageTotals::default();
+  for entry in entries {
+    let day = UsageGroupBy::Day.key(entry);
+    if since.is_some_and(|s| day.as_str() < s) || until.is_some_and(|u| day.as_str() > u) {
+      continue;
+    }
+    groups.entry(group_by.key(entry)).or_default().add(entry);
+    total.add(entry);
+  }
+  let mut groups = groups
+    .into_iter()
+    .map(|(key, totals)| UsageGroup { key, totals })
+    .collect::<Vec<_>>();
+  if group_by != UsageGroupBy::Day {
+    groups.sort_by_key(|g| std::cmp::Reverse(g.totals.input_tokens + g.totals.output_tokens));
+  }
+  UsageReport { groups, total }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(ts: &str, provider: &str, input: u64, output: u64, cost: Option<f64>) -> UsageEntry {
+    UsageEntry {
+      ts: ts.to_string(),
+      stream_id: "s".to_string(),
+      agent_id: "fantasy".to_string(),
+      provider_id: provider.to_string(),
+      model: "m".to_string(),
+      input_tokens: input,
+      output_tokens: output,
+      cost,
+    }
+  }
+
+  #[test]
+  fn groups_by_day_and_provider_within_range() {
+    let entries = vec![
+      entry("2026-03-01T10:00:00+08:00", "openai", 100, 10, Some(0.5)),
+      entry("2026-03-02T09:00:00+08:00", "claude", 1000, 200, None),
+      entry("2026-03-02T11:00:00+08:00", "openai", 50, 5, Some(0.25)),
+      entry("2026-03-05T11:00:00+08:00", "openai", 7, 7, None),
+    ];
+    let by_day = summarize(&entries, UsageGroupBy::Day, None, Some("2026-03-02"));
+    assert_eq!(
+      by_day.groups.iter().map(|g| g.key.as_str()).collect::<Vec<_>>(),
+      vec!["2026-03-01", "2026-03-02"]
+    );
+    assert_eq!(by_day.groups[1].totals.requests, 2);
+    assert_eq!(by_day.total.cost, Some(0.75));
+
+    let by_provider = summarize(&entries, UsageGroupBy::Provider, Some("2026-03-02"), None);
+    assert_eq!(by_provider.groups[0].key, "claude");
+    assert_eq!(by_provider.groups[0].totals.cost, None);
+    assert_eq!(by_provider.groups[1].totals.input_tokens, 57);
+    assert!(UsageGroupBy::parse("week").is_err());
+  }
+}
@@ -0,0 +1,507 @@
+//! In-memory overlay over the workspace for dry runs. Writes, creates,
+//! renames and deletes are recorded instead of applied, reads see the
+//! recorded state, and `change_set` turns the difference against the disk
+//! into a reviewable `ChangeSet`. Paths are validated workspace-relative
+//! paths with `/` separators and no trailing slash.
+
+use crate::modification_types::{
+  ChangeSet, FileModification, FileModificationStatus, FileOperation, Modification, ModificationStatus,
+  ModificationType,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Larger middle sections (lines x lines) are replaced as one hunk instead of diffed.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", content = "text", rename_all = "snake_case")]
+enum Entry {
+  File(String),
+  Dir,
+  Deleted,
+}
+
+enum Lookup {
+  File(String),
+  Dir,
+  Missing,
+  Disk,
+}
+
+/// Serializable so an interrupted dry run can be checkpointed and resumed;
+/// the root is not stored and is supplied again through `with_root`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WorkspaceOverlay {
+  #[serde(skip)]
+  root: PathBuf,
+  entries: BTreeMap<String, Entry>,
+  /// Disk content of every file the run touched; `None` if it did not exist.
+  originals: BTreeMap<String, Option<String>>,
+}
+
+fn join(dir: &str, name: &str) -> String {
+  if dir.is_empty() {
+    name.to_string()
+  } else {
+    format!("{dir}/{name}")
+  }
+}
+
+impl WorkspaceOverlay {
+  pub fn new(root: PathBuf) -> Self {
+    Self {
+      root,
+      entries: BTreeMap::new(),
+      originals: BTreeMap::new(),
+    }
+  }
+
+  pub fn with_root(mut self, root: PathBuf) -> Self {
+    self.root = root;
+    self
+  }
+
+  fn lookup(&self, rel: &str) -> Lookup {
+    match self.entries.get(rel) {
+      Some(Entry::File(content)) => return Lookup::File(content.clone()),
+      Some(Entry::Dir) => return Lookup::Dir,
+      Some(Entry::Deleted) => return Lookup::Missing,
+      None => {}
+    }
+    let mut cur = rel;
+    while let Some((parent, _)) = cur.rsplit_once('/') {
+      if matches!(self.entries.get(parent), Some(Entry::Deleted) | Some(Entry::File(_))) {
+        return Lookup::Missing;
+      }
+      cur = parent;
+    }
+    Lookup::Disk
+  }
+
+  fn has_live_children(&self, rel: &str) -> bool {
+    let prefix = format!("{rel}/");
+    self
+      .entries
+      .range(prefix.clone()..)
+      .take_while(|(k, _)| k.starts_with(&prefix))
+      .any(|(_, e)| !matches!(e, Entry::Deleted))
+  }
+
+  /// `Some("file")`, `Some("dir")` or `None` as seen through the overlay.
+  pub fn kind(&self, rel: &str) -> Option<&'static str> {
+    if rel.is_empty() {
+      return Some("dir");
+    }
+    let implicit_dir = || self.has_live_children(rel).then_some("dir");
+    match self.lookup(rel) {
+      Lookup::File(_) => Some("file"),
+      Lookup::Dir => Some("dir"),
+      Lookup::Missing => implicit_dir(),
+      Lookup::Disk => match fs::metadata(self.root.join(rel)) {
+        Ok(md) if md.is_dir() => Some("dir"),
+        Ok(_) => Some("file"),
+        Err(_) => implicit_dir(),
+      },
+    }
+  }
+
+  pub fn read_text(&self, rel: &str) -> Result<String, String> {
+    match self.lookup(rel) {
+      Lookup::File(content) => Ok(content),
+      Lookup::Dir => Err("read failed: path is a directory".to_string()),
+      Lookup::Missing => Err("read failed: file not found".to_string()),
+      Lookup::Disk => fs::read_to_string(self.root.join(rel)).map_err(|e| format!("read failed: {e}")),
+    }
+  }
+
+  fn touch(&mut self, rel: &str) {
+    if !self.originals.contains_key(rel) {
+      let disk = self.root.join(rel);
+      let original = if disk.is_file() { fs::read_to_string(disk).ok() } else { None };
+      self.originals.insert(rel.to_string(), original);
+    }
+  }
+
+  pub fn write_text(&mut self, rel: &str, content: &str, create_parent_dirs: bool) -> Result<(), String> {
+    if self.kind(rel) == Some("dir") {
+      return Err("write failed: path is a directory".to_string());
+    }
+    if let Some((parent, _)) = rel.rsplit_once('/') {
+      if !create_parent_dirs && self.kind(parent) != Some("dir") {
+        return Err("parent directory does not exist; create it first".to_string());
+      }
+    }
+    self.touch(rel);
+    self.entries.insert(rel.to_string(), Entry::File(content.to_string()));
+    Ok(())
+  }
+
+  pub fn create_dir(&mut self, rel: &str) -> Result<(), String> {
+    match self.kind(rel) {
+      Some("file") => Err("create dir failed: a file exists at this path".to_string()),
+      Some(_) => Ok(()),
+      None => {
+        self.entries.insert(rel.to_string(), Entry::Dir);
+        Ok(())
+      }
+    }
+  }
+
+  pub fn list_dir(&self, rel: &str) -> Result<Vec<(String, &'static str)>, String> {
+    if self.kind(rel) != Some("dir") {
+      return Err("read dir failed: not a directory".to_string());
+    }
+    let mut items: BTreeMap<String, &'static str> = BTreeMap::new();
+    if let Ok(entries) = fs::read_dir(self.root.join(rel)) {
+      for e in entries.filter_map(|e| e.ok()) {
+        let name = e.file_name().to_string_lossy().to_string();
+        if let Some(kind) = self.kind(&join(rel, &name)) {
+          items.insert(name, kind);
+        }
+      }
+    }
+    let prefix = if rel.is_empty() { String::new() } else { format!("{rel}/") };
+    for key in self.entries.keys().filter(|k| k.starts_with(&prefix)) {
+      let name = key[prefix.len()..].split('/').next().unwrap_or("");
+      if name.is_empty() || items.contains_key(name) {
+        continue;
+      }
+      if let Some(kind) = self.kind(&join(rel, name)) {
+        items.insert(name.to_string(), kind);
+      }
+    }
+    Ok(items.into_iter().collect())
+  }
+
+  /// Every file below `rel` as seen through the overlay.
+  fn files_under(&self, rel: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut stack = vec![rel.to_string()];
+    while let Some(dir) = stack.pop() {
+      for (name, kind) in self.list_dir(&dir).unwrap_or_default() {
+        let child = join(&dir, &name);
+        if kind == "dir" {
+          stack.push(child);
+        } else {
+          out.push(child);
+        }
+      }
+    }
+    out
+  }
+
+  pub fn delete_entry(&mut self, rel: &str) -> Result<(), String> {
+    match self.kind(rel) {
+      None => Ok(()),
+      Some("file") => {
+        self.touch(rel);
+        self.entries.insert(rel.to_string(), Entry::Deleted);
+        Ok(())
+      }
+      Some(_) => {
+        for file in self.files_under(rel) {
+          self.touch(&file);
+          self.entries.insert(file, Entry::Deleted);
+        }
+        let prefix = format!("{rel}/");
+        for (key, entry) in self.entries.iter_mut() {
+          if key.starts_with(&prefix) && matches!(entry, Entry::Dir) {
+            *entry = Entry::Deleted;
+          }
+        }
+        self.entries.insert(rel.to_string(), Entry::Deleted);
+        Ok(())
+      }
+    }
+  }
+
+  pub fn rename_entry(&mut self, from: &str, to: &str) -> Result<(), String> {
+    match self.kind(from) {
+      None => Err("rename failed: source does not exist".to_string()),
+      Some("file") => {
+        let content = self.read_text(from)?;
+        self.write_text(to, &content, true)?;
+        self.delete_entry(from)
+      }
+      Some(_) => {
+        for file in self.files_under(from) {
+          let content = self.read_text(&file)?;
+          self.write_text(&join(to, &file[from.len() + 1..]), &content, true)?;
+        }
+        self.delete_entry(from)?;
+        self.create_dir(to)
+      }
+    }
+  }
+
+  /// Every file the run touched with its current text, `None` once deleted.
+  pub fn touched_files(&self) -> Vec<(String, Option<String>)> {
+    self
+      .originals
+      .keys()
+      .map(|rel| {
+        let current = match self.entries.get(rel) {
+          Some(Entry::File(content)) => Some(content.clone()),
+          _ => None,
+        };
+        (rel.clone(), current)
+      })
+      .collect()
+  }
+
+  /// The recorded changes as one pending change set, or `None` if the
+  /// overlay ends up identical to the disk.
+  pub fn change_set(&self) -> Option<ChangeSet> {
+    let mut files = Vec::new();
+    let mut next_id = 0;
+    for (rel, original) in self.originals.iter() {
+      let current = match self.entries.get(rel) {
+        Some(Entry::File(content)) => Some(content.as_str()),
+        _ => None,
+      };
+      let (original_content, modifications, file_op) = match (original.as_deref(), current) {
+        (None, None) => continue,
+        (Some(before), Some(after)) if before == after => continue,
+        (None, Some(after)) => (
+          String::new(),
+          vec![modification(ModificationType::Modify, 1, 1, Some(after.to_string()), &mut next_id)],
+          Some(FileOperation::Create),
+        ),
+        (Some(before), None) => (
+          before.to_string(),
+          vec![modification(
+            ModificationType::Delete,
+            1,
+            before.split('\n').count() as u32,
+            None,
+            &mut next_id,
+          )],
+          Some(FileOperation::Delete),
+        ),
+        (Some(before), Some(after)) => (before.to_string(), diff_modifications(before, after, &mut next_id), None),
+      };
+      files.push(FileModification {
+        file_path: rel.clone(),
+        original_content,
+        modifications,
+        status: FileModificationStatus::Pending,
+        file_op,
+      });
+    }
+    if files.is_empty() {
+      None
+    } else {
+      Some(ChangeSet::new(files))
+    }
+  }
+}
+
+fn modification(
+  mod_type: ModificationType,
+  line_start: u32,
+  line_end: u32,
+  modified_text: Option<String>,
+  next_id: &mut usize,
+) -> Modification {
+  *next_id += 1;
+  Modification {
+    id: format!("mod-{}-{}", chrono::Utc::now().timestamp_millis(), *next_id),
+    mod_type,
+    line_start,
+    line_end,
+    original_text: None,
+    modified_text,
+    status: ModificationStatus::Pending,
+  }
+}
+
+/// Line hunks turning `before` into `after`, in the frontend's convention:
+/// lines split on `\n`, `add` inserts before `lineStart`.
+fn diff_modifications(before: &str, after: &str, next_id: &mut usize) -> Vec<Modification> {
+  let a = before.split('\n').collect::<Vec<_>>();
+  let b = after.split('\n').collect::<Vec<_>>();
+  diff_hunks(&a, &b)
+    .into_iter()
+    .map(|(a0, a1, b0, b1)| {
+      let text = || Some(b[b0..b1].join("\n"));
+      match (a1 > a0, b1 > b0) {
+        (true, true) => modification(ModificationType::Modify, a0 as u32 + 1, a1 as u32, text(), next_id),
+        (true, false) => modification(ModificationType::Delete, a0 as u32 + 1, a1 as u32, None, next_id),
+        _ => modification(ModificationType::Add, a0 as u32 + 1, a0 as u32 + 1, text(), next_id),
+      }
+    })
+    .collect()
+}
+
+/// Changed ranges as `(a_start, a_end, b_start, b_end)`, end-exclusive.
+fn diff_hunks(a: &[&str], b: &[&str]) -> Vec<(usize, usize, usize, usize)> {
+  let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+  let suffix = a[prefix..]
+    .iter()
+    .rev()
+    .zip(b[prefix..].iter().rev())
+    .take_while(|(x, y)| x == y)
+    .count();
+  let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+  if a_mid.is_empty() && b_mid.is_empty() {
+    return Vec::new();
+  }
+  let (n, m) = (a_mid.len(), b_mid.len());
+  if n == 0 || m == 0 || n * m > MAX_DIFF_CELLS {
+    return vec![(prefix, prefix + n, prefix, prefix + m)];
+  }
+  // lcs[i][j] = longest common subsequence of a_mid[i..] and b_mid[j..].
+  let width = m + 1;
+  let mut lcs = vec![0u32; (n + 1) * width];
+  for i in (0..n).rev() {
+    for j in (0..m).rev() {
+      lcs[i * width + j] = if a_mid[i] == b_mid[j] {
+        lcs[(i + 1) * width + j + 1] + 1
+      } else {
+        lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+      };
+    }
+  }
+  let mut hunks = Vec::new();
+  let mut open: Option<(usize, usize)> = None;
+  let (mut i, mut j) = (0, 0);
+  while i < n || j < m {
+    if i < n && j < m && a_mid[i] == b_mid[j] {
+      if let Some((a0, b0)) = open.take() {
+        hunks.push((prefix + a0, prefix + i, prefix + b0, prefix + j));
+      }
+      i += 1;
+      j += 1;
+      continue;
+    }
+    open.get_or_insert((i, j));
+    if j >= m || (i < n && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1]) {
+      i += 1;
+    } else {
+      j += 1;
+    }
+  }
+  if let Some((a0, b0)) = open {
+    hunks.push((prefix + a0, prefix + n, prefix + b0, prefix + m));
+  }
+  hunks
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hunks_cover_replace_insert_and_delete() {
+    let before = "a\nb\nc\nd\ne";
+    let after = "a\nB\nc\nnew\nd";
+    let mods = diff_modifications(before, after, &mut 0);
+    let summary = mods
+      .iter()
+      .map(|m| (format!("{:?}", m.mod_type), m.line_start, m.line_end, m.modified_text.clone()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      summary,
+      vec![
+        ("Modify".to_string(), 2, 2, Some("B".to_string())),
+        ("Add".to_string(), 4, 4, Some("new".to_string())),
+        ("Delete".to_string(), 5, 5, None),
+      ]
+    );
+  }
+
+  fn workspace(files: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("novel-overlay-{}", uuid::Uuid::new_v4()));
+    for (rel, content) in files {
+      let path = root.join(rel);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, content).unwrap();
+    }
+    root
+  }
+
+  fn file_ops(overlay: &WorkspaceOverlay) -> Vec<(String, Option<FileOperation>)> {
+    let set = overlay.change_set().expect("changes");
+    set.files.iter().map(|f| (f.file_path.clone(), f.file_op)).collect()
+  }
+
+  #[test]
+  fn renames_directories_as_creates_and_deletes() {
+    let root = workspace(&[("drafts/01.md", "一"), ("drafts/old/02.md", "二")]);
+    let mut overlay = WorkspaceOverlay::new(root.clone());
+    overlay.rename_entry("drafts", "stories").unwrap();
+
+    assert_eq!(overlay.kind("drafts"), None);
+    assert_eq!(overlay.kind("stories/old"), Some("dir"));
+    assert_eq!(overlay.read_text("stories/old/02.md").unwrap(), "二");
+    assert_eq!(
+      overlay.list_dir("stories").unwrap(),
+      vec![("01.md".to_string(), "file"), ("old".to_string(), "dir")]
+    );
+    assert_eq!(
+      file_ops(&overlay),
+      vec![
+        ("drafts/01.md".to_string(), Some(FileOperation::Delete)),
+        ("drafts/old/02.md".to_string(), Some(FileOperation::Delete)),
+        ("stories/01.md".to_string(), Some(FileOperation::Create)),
+        ("stories/old/02.md".to_string(), Some(FileOperation::Create)),
+      ]
+    );
+    assert!(root.join("drafts/01.md").is_file() && !root.join("stories").exists());
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn deleted_parents_hide_disk_files_until_recreated() {
+    let root = workspace(&[("notes/a.md", "a"), ("notes/sub/b.md", "b")]);
+    let mut overlay = WorkspaceOverlay::new(root.clone());
+    overlay.delete_entry("notes").unwrap();
+    // Files that appear on disk after the delete stay hidden behind it.
+    fs::write(root.join("notes/sub/late.md"), "late").unwrap();
+
+    assert_eq!(overlay.kind("notes"), None);
+    assert_eq!(
+      overlay.read_text("notes/sub/late.md").unwrap_err(),
+      "read failed: file not found"
+    );
+    assert!(overlay.write_text("notes/c.md", "c", false).is_err());
+
+    overlay.write_text("notes/a.md", "a2", true).unwrap();
+    overlay.write_text("notes/c.md", "c", false).unwrap();
+    assert_eq!(overlay.kind("notes"), Some("dir"));
+    assert_eq!(overlay.kind("notes/sub"), None);
+    assert_eq!(
+      overlay.list_dir("notes").unwrap(),
+      vec![("a.md".to_string(), "file"), ("c.md".to_string(), "file")]
+    );
+
+    let set = overlay.change_set().expect("changes");
+    let recreated = set.files.iter().find(|f| f.file_path == "notes/a.md").unwrap();
+    assert_eq!(recreated.file_op, None);
+    assert_eq!(recreated.original_content, "a");
+    assert_eq!(
+      file_ops(&overlay),
+      vec![
+        ("notes/a.md".to_string(), None),
+        ("notes/c.md".to_string(), Some(FileOperation::Create)),
+        ("notes/sub/b.md".to_string(), Some(FileOperation::Delete)),
+      ]
+    );
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn change_set_is_empty_when_edits_cancel_out() {
+    let root = workspace(&[("a.md", "same")]);
+    let mut overlay = WorkspaceOverlay::new(root.clone());
+    overlay.write_text("new.md", "tmp", false).unwrap();
+    overlay.delete_entry("new.md").unwrap();
+    overlay.rename_entry("a.md", "b.md").unwrap();
+    overlay.rename_entry("b.md", "a.md").unwrap();
+    assert!(overlay.change_set().is_none());
+    fs::remove_dir_all(root).unwrap();
+  }
+}